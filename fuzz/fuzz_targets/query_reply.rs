@@ -0,0 +1,16 @@
+#![no_main]
+
+// Manager replies are parsed straight off the network: any datagram must be rejected or parsed, never panic.
+// Run with: cargo fuzz run query_reply -- -max_total_time=60
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/query/protocol.rs"]
+#[allow(dead_code)]
+mod protocol;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(fields) = protocol::parse_fields(data) {
+        let _ = protocol::parse_reply(&fields);
+    }
+});
@@ -0,0 +1,207 @@
+use std::net::{IpAddr, SocketAddr};
+
+// Servers the manager may assign (--allowed-servers). Entries are IP addresses, CIDR networks (IPv4 or IPv6) or
+// host names. Both sides are resolved and compared as canonical IP addresses, so the same host written
+// differently (name vs address, IPv4-mapped IPv6) matches the same way. The server is resolved only once and the
+// connection goes to the very addresses that were checked, so its name cannot be rebound to another address between
+// the check and the connection
+#[derive(Debug, Clone)]
+pub struct ServerAllowlist(Vec<AllowedEntry>);
+
+#[derive(Debug, Clone)]
+enum AllowedEntry {
+    Network(IpAddr, u8),    // Address and prefix length
+    Host(String),
+}
+
+// IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) are compared as IPv4
+fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+        address => address,
+    }
+}
+
+fn in_network(address: IpAddr, network: IpAddr, prefix_length: u8) -> bool {
+    match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_length as u32).unwrap_or(0);
+            u32::from(address) & mask == u32::from(network) & mask
+        },
+        (IpAddr::V6(address), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_length as u32).unwrap_or(0);
+            u128::from(address) & mask == u128::from(network) & mask
+        },
+        _ => false,
+    }
+}
+
+// Host part of a server address: host:port, [IPv6]:port or a bare address
+fn server_host(server_address: &str) -> &str {
+    if server_address.parse::<IpAddr>().is_ok() {
+        return server_address;
+    }
+
+    let host = server_address.rsplit_once(':').map(|(host, _)| host).unwrap_or(server_address);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+async fn resolve(host: &str) -> Vec<IpAddr> {
+    match host.parse::<IpAddr>() {
+        Ok(address) => vec![canonical(address)],
+        Err(_) => match tokio::net::lookup_host((host, 0)).await {
+            Ok(addresses) => addresses.map(|address| canonical(address.ip())).collect(),
+            Err(e) => {
+                println!("Cannot resolve {}: {}", host, e);
+                Vec::new()
+            }
+        },
+    }
+}
+
+impl ServerAllowlist {
+    pub fn parse(value: &str) -> Result<ServerAllowlist, String> {
+        let mut entries = Vec::new();
+
+        for entry in value.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+            let entry = match entry.split_once('/') {
+                Some((address, prefix_length)) => {
+                    let address = address.parse::<IpAddr>().map_err(|_| format!("Invalid network address in allowed server '{}'", entry))?;
+                    let address = canonical(address);
+                    let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+
+                    match prefix_length.parse::<u8>() {
+                        Ok(prefix_length) if prefix_length <= max_prefix_length => AllowedEntry::Network(address, prefix_length),
+                        _ => return Err(format!("Invalid prefix length in allowed server '{}' (must be 0-{})", entry, max_prefix_length)),
+                    }
+                },
+                None => match entry.parse::<IpAddr>() {
+                    Ok(address) => {
+                        let address = canonical(address);
+                        AllowedEntry::Network(address, if address.is_ipv4() { 32 } else { 128 })
+                    },
+                    Err(_) => AllowedEntry::Host(entry.trim_end_matches('.').to_lowercase()),
+                },
+            };
+
+            entries.push(entry);
+        }
+
+        if entries.is_empty() {
+            return Err(String::from("No allowed servers given"));
+        }
+
+        Ok(ServerAllowlist(entries))
+    }
+
+    // The addresses to connect to, None if the server is not allowed. A server is allowed only if every address its
+    // host resolves to is allowed, a name resolving to several addresses must not be a way around the list
+    pub async fn allowed_addresses(&self, server_address: &str) -> Option<Vec<SocketAddr>> {
+        let addresses: Vec<SocketAddr> = match tokio::net::lookup_host(server_address).await {
+            Ok(addresses) => addresses.collect(),
+            Err(e) => {
+                println!("Cannot resolve {}: {}", server_address, e);
+                return None;
+            }
+        };
+
+        if addresses.is_empty() {
+            return None;
+        }
+
+        let host = server_host(server_address).trim_end_matches('.').to_lowercase();
+        let mut host_addresses = Vec::new();
+
+        for entry in self.0.iter() {
+            match entry {
+                AllowedEntry::Host(name) if *name == host => return Some(addresses),
+                AllowedEntry::Host(name) => host_addresses.extend(resolve(name).await),
+                AllowedEntry::Network(_, _) => {},
+            }
+        }
+
+        let allowed = addresses.iter().map(|address| canonical(address.ip())).all(|address| self.in_networks(address) || host_addresses.contains(&address));
+
+        allowed.then_some(addresses)
+    }
+
+    fn in_networks(&self, address: IpAddr) -> bool {
+        self.0.iter().any(|entry| matches!(entry, AllowedEntry::Network(network, prefix_length) if in_network(address, *network, *prefix_length)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(allowlist: &str, address: &str) -> bool {
+        ServerAllowlist::parse(allowlist).unwrap().in_networks(canonical(address.parse().unwrap()))
+    }
+
+    #[test]
+    fn single_addresses() {
+        assert!(allows("10.0.0.5", "10.0.0.5"));
+        assert!(!allows("10.0.0.5", "10.0.0.6"));
+        assert!(allows("fd00::1", "fd00::1"));
+        assert!(!allows("fd00::1", "fd00::2"));
+    }
+
+    #[test]
+    fn ipv4_networks() {
+        assert!(allows("192.168.1.0/24", "192.168.1.0"));
+        assert!(allows("192.168.1.0/24", "192.168.1.255"));
+        assert!(!allows("192.168.1.0/24", "192.168.2.1"));
+        assert!(allows("192.168.1.77/24", "192.168.1.1"));
+        assert!(allows("172.16.0.0/12", "172.31.255.255"));
+        assert!(!allows("172.16.0.0/12", "172.32.0.0"));
+    }
+
+    #[test]
+    fn prefix_length_limits() {
+        assert!(allows("0.0.0.0/0", "203.0.113.9"));
+        assert!(allows("::/0", "2001:db8::1"));
+        assert!(allows("10.1.2.3/32", "10.1.2.3"));
+        assert!(!allows("10.1.2.3/32", "10.1.2.4"));
+        assert!(ServerAllowlist::parse("10.0.0.0/33").is_err());
+        assert!(ServerAllowlist::parse("fd00::/129").is_err());
+        assert!(ServerAllowlist::parse("10.0.0.0/x").is_err());
+    }
+
+    #[test]
+    fn ipv6_networks() {
+        assert!(allows("fd00:1234::/32", "fd00:1234:ffff::1"));
+        assert!(!allows("fd00:1234::/32", "fd00:1235::1"));
+        assert!(allows("2001:db8::/127", "2001:db8::1"));
+        assert!(!allows("2001:db8::/127", "2001:db8::2"));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_entries() {
+        assert!(allows("192.168.1.0/24", "::ffff:192.168.1.20"));
+        assert!(!allows("192.168.1.0/24", "::ffff:192.168.2.20"));
+    }
+
+    #[test]
+    fn families_do_not_mix() {
+        assert!(!allows("0.0.0.0/0", "2001:db8::1"));
+        assert!(!allows("::/0", "10.0.0.1"));
+    }
+
+    #[test]
+    fn entry_lists() {
+        assert!(allows("10.0.0.0/8, 192.168.1.5", "192.168.1.5"));
+        assert!(allows("10.0.0.0/8, 192.168.1.5", "10.200.0.1"));
+        assert!(!allows("10.0.0.0/8, 192.168.1.5", "192.168.1.6"));
+        assert!(!allows("panel-server.local", "10.0.0.1"));
+        assert!(ServerAllowlist::parse(" , ").is_err());
+        assert!(ServerAllowlist::parse("10.0.0.256/8").is_err());
+    }
+
+    #[test]
+    fn host_part_of_server_addresses() {
+        assert_eq!(server_host("10.0.0.1:5900"), "10.0.0.1");
+        assert_eq!(server_host("[fd00::1]:5900"), "fd00::1");
+        assert_eq!(server_host("fd00::1"), "fd00::1");
+        assert_eq!(server_host("server.local:5901"), "server.local");
+    }
+}
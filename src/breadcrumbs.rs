@@ -0,0 +1,206 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Record of the last run, kept on disk for post-mortem debugging after the journal has rotated away. Written at
+// state transitions (throttled, to spare the SD card) and on clean exit. A state reached inside the throttle window is
+// written when the window ends, so the last state before a crash makes it to disk even if nothing follows it
+pub const BREADCRUMBS_FILE: &str = "/var/lib/hometoucher/last-run.json";
+
+// With --split each panel keeps its own record (last-run-1.json and last-run-2.json)
+pub fn panel_file(panel: usize) -> PathBuf {
+    Path::new(BREADCRUMBS_FILE).with_file_name(format!("last-run-{}.json", panel))
+}
+
+const MIN_WRITE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Longest LastCrash value sent to the manager
+const MAX_CRASH_SUMMARY: usize = 200;
+
+#[derive(Debug)]
+pub struct Breadcrumbs {
+    state: String,
+    server: Option<String>,
+    last_error: Option<String>,
+    failed_cycles: u32,
+    last_session: Option<Duration>,
+    device_checks: Option<String>,
+    writer: Arc<Mutex<Writer>>,
+}
+
+// Shared with the deferred write of a throttled record
+#[derive(Debug)]
+struct Writer {
+    path: PathBuf,
+    last_write: Option<Instant>,
+    pending: Option<String>,    // Newest record not written yet, its write is scheduled
+    disabled: bool,             // Writing failed once (e.g. not running as root), do not keep trying
+}
+
+fn json_string(value: &str) -> String {
+    let mut result = String::from("\"");
+
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+
+    result.push('"');
+    result
+}
+
+fn json_optional_string(value: &Option<String>) -> String {
+    value.as_deref().map(json_string).unwrap_or_else(|| String::from("null"))
+}
+
+// Value of a string field in a record written by Breadcrumbs (not a general JSON parser)
+fn record_field(record: &str, name: &str) -> Option<String> {
+    let start = record.find(&format!("\"{}\": \"", name))? + name.len() + 5;
+    let mut value = String::new();
+    let mut escaped = false;
+
+    for c in record[start..].chars() {
+        match (escaped, c) {
+            (false, '\\') => escaped = true,
+            (false, '"') => return Some(value),
+            (_, c) => {
+                escaped = false;
+                value.push(c);
+            }
+        }
+    }
+
+    None
+}
+
+impl Breadcrumbs {
+    pub fn new(path: &Path) -> Breadcrumbs {
+        Breadcrumbs {
+            state: String::from("Starting"),
+            server: None,
+            last_error: None,
+            failed_cycles: 0,
+            last_session: None,
+            device_checks: None,
+            writer: Arc::new(Mutex::new(Writer {
+                path: path.to_path_buf(),
+                last_write: None,
+                pending: None,
+                disabled: false,
+            })),
+        }
+    }
+
+    // Summary of the previous run if it did not exit cleanly
+    pub fn previous_unclean_exit(path: &Path) -> Option<String> {
+        let record = std::fs::read_to_string(path).ok()?;
+
+        if record.contains("\"clean\": true") {
+            return None;
+        }
+
+        let mut summary = format!("{} at {}", record_field(&record, "state").unwrap_or_default(), record_field(&record, "timestamp").unwrap_or_default());
+
+        if let Some(server) = record_field(&record, "server") {
+            summary.push_str(&format!(" server {}", server));
+        }
+
+        if let Some(error) = record_field(&record, "last_error") {
+            summary.push_str(&format!(": {}", error));
+        }
+
+        Some(summary.chars().take(MAX_CRASH_SUMMARY).collect())
+    }
+
+    pub fn state(&mut self, state: &str, server: Option<&str>, failed_cycles: u32) {
+        if self.state != state || self.server.as_deref() != server || self.failed_cycles != failed_cycles {
+            self.state = state.to_string();
+            self.server = server.map(|server| server.to_string());
+            self.failed_cycles = failed_cycles;
+            self.write_throttled();
+        }
+    }
+
+    pub fn error(&mut self, error: String) {
+        self.last_error = Some(error);
+        self.write_throttled();
+    }
+
+    pub fn session_ended(&mut self, duration: Duration) {
+        self.last_session = Some(duration);
+    }
+
+    pub fn device_checks(&mut self, summary: String) {
+        self.device_checks = Some(summary);
+    }
+
+    // Clean exit: the next start does not report a crash. Replaces a record still waiting for its write
+    pub fn finish(&mut self, state: &str) {
+        self.state = state.to_string();
+
+        let record = self.record(true);
+        let mut writer = self.writer.lock().unwrap();
+
+        writer.pending = None;
+        writer.write(&record);
+    }
+
+    fn write_throttled(&mut self) {
+        let record = self.record(false);
+        let mut writer = self.writer.lock().unwrap();
+        let remaining = writer.last_write.map_or(Duration::ZERO, |last_write| MIN_WRITE_INTERVAL.saturating_sub(last_write.elapsed()));
+
+        if remaining.is_zero() {
+            writer.pending = None;
+            writer.write(&record);
+        } else if writer.pending.replace(record).is_none() {
+            let writer = self.writer.clone();
+
+            tokio::spawn(async move {
+                tokio::time::sleep(remaining).await;
+
+                let mut writer = writer.lock().unwrap();
+
+                if let Some(record) = writer.pending.take() {
+                    writer.write(&record);
+                }
+            });
+        }
+    }
+
+    fn record(&self, clean: bool) -> String {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+
+        format!(
+            "{{\n  \"timestamp\": \"{}\",\n  \"clean\": {},\n  \"state\": {},\n  \"server\": {},\n  \"last_error\": {},\n  \"failed_cycles\": {},\n  \"last_session_seconds\": {},\n  \"device_checks\": {}\n}}\n",
+            timestamp, clean, json_string(&self.state), json_optional_string(&self.server), json_optional_string(&self.last_error),
+            self.failed_cycles, self.last_session.map(|duration| duration.as_secs().to_string()).unwrap_or_else(|| String::from("null")),
+            json_optional_string(&self.device_checks))
+    }
+}
+
+impl Writer {
+    // Written to a temporary file and renamed, so a power cut never leaves a torn record
+    fn write(&mut self, record: &str) {
+        if self.disabled {
+            return;
+        }
+
+        let temporary_path = self.path.with_extension("tmp");
+        let result = self.path.parent().map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&temporary_path, record))
+            .and_then(|_| std::fs::rename(&temporary_path, &self.path));
+
+        match result {
+            Ok(_) => self.last_write = Some(Instant::now()),
+            Err(e) => {
+                println!("Cannot write {}: {} - breadcrumbs disabled", self.path.display(), e);
+                self.disabled = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+
+// Parse a hex RGB color such as "1a2b3c" or "#1A2B3C"
+pub fn parse_hex_color(value: &str) -> Result<(u8, u8, u8), String> {
+    let digits = value.strip_prefix('#').unwrap_or(value);
+
+    if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid color '{}' (expected hex RGB, e.g. 1a2b3c)", value));
+    }
+
+    let channel = |index: usize| u8::from_str_radix(&digits[index..index+2], 16).unwrap();
+
+    Ok((channel(0), channel(2), channel(4)))
+}
+
+// Build a connectable "host:port" address from a --server value. Accepted forms:
+//   host              - uses the given default port
+//   host:port         - port
+//   host::port        - port (VNC convention)
+//   host:display=N    - VNC display number (port 5900 + N)
+// IPv6 addresses must be bracketed ([::1]:5900)
+pub fn server_address(value: &str, default_port: u16) -> Result<String, String> {
+    const VNC_BASE_PORT: u16 = 5900;
+
+    let (host, suffix) = match value.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((host, suffix)) => (format!("[{}]", host), suffix),
+            None => return Err(format!("Invalid server address '{}' (missing ']')", value)),
+        },
+        None => match value.find(':') {
+            Some(index) => (value[..index].to_string(), &value[index..]),
+            None => (value.to_string(), ""),
+        },
+    };
+
+    if host.is_empty() {
+        return Err(format!("Invalid server address '{}' (missing host)", value));
+    }
+
+    let parse_number = |digits: &str| digits.parse::<u16>().map_err(|_| format!("Invalid port or display number in server address '{}'", value));
+
+    let port = if suffix.is_empty() {
+        default_port
+    } else if let Some(display) = suffix.strip_prefix(":display=") {
+        parse_number(display)?.checked_add(VNC_BASE_PORT).ok_or_else(|| format!("Invalid display number in server address '{}'", value))?
+    } else if let Some(port) = suffix.strip_prefix("::").or_else(|| suffix.strip_prefix(':')) {
+        parse_number(port)?
+    } else {
+        return Err(format!("Invalid server address '{}'", value));
+    };
+
+    Ok(format!("{}:{}", host, port))
+}
+
+// One line of the --print-config dump. Without a configuration file, a value is either the default or was given
+// on the command line or in the environment
+pub fn config_entry<T: std::fmt::Debug>(name: &str, value: &T) -> String {
+    let source = if on_command_line(name) {
+        "command line"
+    } else if std::env::var_os(env_name(name)).is_some() {
+        "environment"
+    } else {
+        "default"
+    };
+
+    format!("{} = {:?}  # {}", name, value, source)
+}
+
+pub fn optional_config_entry<T: std::fmt::Debug>(name: &str, value: &Option<T>) -> String {
+    match value {
+        Some(value) => format!("{} = {:?}  # {}", name, value, given_source(name)),
+        None => format!("# {} is not set  # default", name),
+    }
+}
+
+// The command line takes precedence, so the environment is the source only if the option was not on the command line
+fn given_source(name: &str) -> &'static str {
+    if std::env::var_os(env_name(name)).is_some() && !on_command_line(name) {
+        "environment"
+    } else {
+        "command line"
+    }
+}
+
+// --option value or --option=value. A value equal to the default counts as given, so it is not overridden
+fn on_command_line(name: &str) -> bool {
+    let option = format!("--{}", name.replace('_', "-"));
+
+    std::env::args().any(|arg| arg == option || arg.starts_with(&format!("{}=", option)))
+}
+
+// Every option can also be set in the environment, in HT_ followed by the option name in upper case with
+// underscores (e.g. HT_SERVER, HT_READ_TIMEOUT for --read-timeout, HT_DOMAIN for the domain). Flags take true or
+// false. Precedence, highest first: command line, environment, default
+pub fn env_name(option: &str) -> String {
+    format!("HT_{}", option.to_uppercase())
+}
+
+fn env_value<T: std::str::FromStr>(option: &str) -> Result<Option<T>, String> {
+    match std::env::var(env_name(option)) {
+        Ok(value) => value.parse().map(Some).map_err(|_| format!("Invalid value '{}' in {}", value, env_name(option))),
+        Err(_) => Ok(None),
+    }
+}
+
+// The environment only applies to options not given on the command line
+pub fn env_default<T: std::str::FromStr>(value: &mut T, option: &str) -> Result<(), String> {
+    if !on_command_line(option) {
+        if let Some(env_value) = env_value(option)? {
+            *value = env_value;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn env_optional<T: std::str::FromStr>(value: &mut Option<T>, option: &str) -> Result<(), String> {
+    if value.is_none() {
+        *value = env_value(option)?;
+    }
+
+    Ok(())
+}
+
+// Parse a physical button mapping such as "158:0x20,159:0x40" (input key code : pointer button mask bit)
+pub fn parse_button_map(value: &str) -> Result<Vec<(u16, u8)>, String> {
+    let parse_mask = |mask: &str| match mask.strip_prefix("0x") {
+        Some(hex_digits) => u8::from_str_radix(hex_digits, 16),
+        None => mask.parse::<u8>(),
+    };
+
+    value.split(',').map(|entry| {
+        let (code, mask) = entry.split_once(':').ok_or_else(|| format!("Invalid button mapping '{}' (expected code:mask)", entry))?;
+        let code = code.trim().parse::<u16>().map_err(|_| format!("Invalid key code in button mapping '{}'", entry))?;
+        let mask = parse_mask(mask.trim()).map_err(|_| format!("Invalid button mask in button mapping '{}'", entry))?;
+
+        Ok((code, mask))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_port() {
+        assert_eq!(server_address("ht-server", 5900).unwrap(), "ht-server:5900");
+        assert_eq!(server_address("10.0.0.5", 5901).unwrap(), "10.0.0.5:5901");
+        assert_eq!(server_address("[fd00::1]", 5900).unwrap(), "[fd00::1]:5900");
+    }
+
+    #[test]
+    fn ports() {
+        assert_eq!(server_address("ht-server:5901", 5900).unwrap(), "ht-server:5901");
+        assert_eq!(server_address("ht-server:80", 5900).unwrap(), "ht-server:80");
+        assert_eq!(server_address("ht-server::80", 5900).unwrap(), "ht-server:80");
+        assert_eq!(server_address("[fd00::1]:80", 5900).unwrap(), "[fd00::1]:80");
+        assert_eq!(server_address("[fd00::1]::5902", 5900).unwrap(), "[fd00::1]:5902");
+    }
+
+    #[test]
+    fn display_numbers() {
+        assert_eq!(server_address("ht-server:display=0", 5900).unwrap(), "ht-server:5900");
+        assert_eq!(server_address("ht-server:display=2", 5900).unwrap(), "ht-server:5902");
+        assert_eq!(server_address("[fd00::1]:display=1", 5900).unwrap(), "[fd00::1]:5901");
+        assert!(server_address("ht-server:display=60000", 5900).is_err());
+    }
+
+    #[test]
+    fn invalid_addresses() {
+        assert!(server_address(":5900", 5900).is_err());
+        assert!(server_address("[fd00::1", 5900).is_err());
+        assert!(server_address("[fd00::1]5900", 5900).is_err());
+        assert!(server_address("ht-server:port", 5900).is_err());
+        assert!(server_address("ht-server:70000", 5900).is_err());
+        assert!(server_address("ht-server:display=", 5900).is_err());
+    }
+}
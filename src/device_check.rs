@@ -0,0 +1,97 @@
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+
+// Startup check of the devices the panel needs. Most "black screen" reports are permission problems, so each
+// failure is reported with the reason (missing, no permission, busy) and what to do about it
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceKind {
+    Framebuffer,
+    Console,
+    Input,
+}
+
+impl DeviceKind {
+    fn description(&self) -> &'static str {
+        match self {
+            DeviceKind::Framebuffer => "framebuffer",
+            DeviceKind::Console => "console",
+            DeviceKind::Input => "input device",
+        }
+    }
+
+    // Group giving access to the device on Raspberry Pi OS
+    fn group(&self) -> &'static str {
+        match self {
+            DeviceKind::Framebuffer => "video",
+            DeviceKind::Console => "tty",
+            DeviceKind::Input => "input",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DeviceCheck {
+    pub device: String,
+    pub kind: DeviceKind,
+    pub error: Option<std::io::Error>,
+}
+
+impl DeviceCheck {
+    pub fn run(device: &str, kind: DeviceKind) -> DeviceCheck {
+        let error = OpenOptions::new().read(true).write(kind != DeviceKind::Input).open(device).err();
+
+        DeviceCheck { device: device.to_string(), kind, error }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub fn permission_denied(&self) -> bool {
+        self.error.as_ref().is_some_and(|e| e.kind() == ErrorKind::PermissionDenied)
+    }
+
+    fn status(&self) -> &'static str {
+        match self.error {
+            None => "ok",
+            Some(ref e) if e.kind() == ErrorKind::PermissionDenied => "EACCES",
+            Some(ref e) if e.kind() == ErrorKind::NotFound => "ENOENT",
+            Some(ref e) if e.raw_os_error() == Some(libc::EBUSY) => "EBUSY",
+            Some(_) => "error",
+        }
+    }
+
+    fn guidance(&self) -> String {
+        let description = self.kind.description();
+
+        match self.error {
+            None => format!("{} {} is accessible", description, self.device),
+            Some(ref e) if e.kind() == ErrorKind::PermissionDenied =>
+                format!("No permission to open {} {}: run as root or add the service user to the '{}' group", description, self.device, self.kind.group()),
+            Some(ref e) if e.kind() == ErrorKind::NotFound =>
+                format!("{} {} does not exist: check that its driver (or device tree overlay) is loaded", description, self.device),
+            Some(ref e) if e.raw_os_error() == Some(libc::EBUSY) =>
+                format!("{} {} is busy: another process (e.g. a desktop session) holds it", description, self.device),
+            Some(ref e) => format!("Cannot open {} {}: {}", description, self.device, e),
+        }
+    }
+}
+
+// With problems_only (--probe, whose stdout is a single line of JSON) devices that are fine are not listed
+pub fn report(checks: &[DeviceCheck], problems_only: bool) {
+    for check in checks.iter() {
+        if check.is_ok() {
+            if !problems_only {
+                println!("Device check: {}", check.guidance());
+            }
+        } else {
+            eprintln!("Device check: {}", check.guidance());
+        }
+    }
+}
+
+// One line summary for the breadcrumbs (e.g. "/dev/fb0 ok, /dev/input/event0 EACCES")
+pub fn summary(checks: &[DeviceCheck]) -> String {
+    checks.iter().map(|check| format!("{} {}", check.device, check.status())).collect::<Vec<_>>().join(", ")
+}
@@ -0,0 +1,90 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::query;
+use crate::shutdown::Shutdown;
+
+#[derive(Debug, Default)]
+struct HealthState {
+    manager: Option<String>,            // Manager that assigned the current server (heartbeats go there)
+    session_start: Option<Instant>,     // Start of the running session, if any
+    last_frame: Option<Instant>,
+    session_frames: u64,                // Frames received by the running (or last) session
+    sessions: u32,
+    next_attempt: Option<Instant>,      // The state machine is backing off until then (see retry_budget.rs)
+}
+
+// Session health shared by the state machine, the RFB session (frames) and the heartbeat task. The heartbeat runs
+// on its own, so it keeps reporting while a session is wedged
+#[derive(Debug, Clone, Default)]
+pub struct SessionHealth(Arc<Mutex<HealthState>>);
+
+impl SessionHealth {
+    pub fn set_manager(&self, manager: Option<String>) {
+        self.0.lock().unwrap().manager = manager;
+    }
+
+    pub fn session_started(&self) {
+        let mut state = self.0.lock().unwrap();
+
+        state.session_start = Some(Instant::now());
+        state.session_frames = 0;
+        state.sessions += 1;
+    }
+
+    pub fn session_ended(&self) {
+        self.0.lock().unwrap().session_start = None;
+    }
+
+    pub fn frame_received(&self) {
+        let mut state = self.0.lock().unwrap();
+
+        state.last_frame = Some(Instant::now());
+        state.session_frames += 1;
+    }
+
+    pub fn session_frames(&self) -> u64 {
+        self.0.lock().unwrap().session_frames
+    }
+
+    pub fn set_next_attempt(&self, next_attempt: Option<Instant>) {
+        self.0.lock().unwrap().next_attempt = next_attempt;
+    }
+
+    // One line description for the control socket status command
+    pub fn status(&self) -> String {
+        let state = self.0.lock().unwrap();
+
+        match (state.session_start, state.next_attempt) {
+            (Some(session_start), _) => match state.last_frame.filter(|last_frame| *last_frame >= session_start) {
+                Some(last_frame) => format!("in session for {}s, last frame {}s ago", session_start.elapsed().as_secs(), last_frame.elapsed().as_secs()),
+                None => format!("in session for {}s, no frame yet", session_start.elapsed().as_secs()),
+            },
+            (None, Some(next_attempt)) if next_attempt > Instant::now() =>
+                format!("backing off, next attempt in {}s", next_attempt.saturating_duration_since(Instant::now()).as_secs_f64().ceil() as u64),
+            (None, _) => String::from("connecting"),
+        }
+    }
+}
+
+// Send an Event=Heartbeat datagram to the manager every interval until shutdown
+pub async fn run(health: SessionHealth, name: String, interval: Duration, shutdown: Shutdown) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {},
+            _ = shutdown.requested() => return,
+        }
+
+        let (manager, seconds_since_last_frame, session_uptime, reconnects) = {
+            let state = health.0.lock().unwrap();
+
+            (state.manager.clone(), state.last_frame.map(|last_frame| last_frame.elapsed().as_secs()),
+             state.session_start.map(|session_start| session_start.elapsed().as_secs()), state.sessions.saturating_sub(1))
+        };
+
+        if let Some(manager) = manager {
+            if let Err(e) = query::report_heartbeat(&manager, &name, seconds_since_last_frame, session_uptime, reconnects).await {
+                println!("Heartbeat to server manager {} failed: {:?}", manager, e);
+            }
+        }
+    }
+}
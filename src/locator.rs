@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::ffi::CStr;
+use std::net::Ipv4Addr;
 use std::time::Duration;
 use tokio::pin;
 use tokio_stream::StreamExt;
@@ -6,25 +8,46 @@ use tokio_stream::StreamExt;
 const HT_MANAGER_SERVICE: &str = "_HtVncConf._udp.local";
 const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
 
-pub async fn locate_ht_manager(domain_name: &str) -> Result<Option<String>, mdns::Error> {
+// Redundant managers may announce the same domain, so after the first answer more answers are collected for this long
+const COLLECT_WINDOW: Duration = Duration::from_secs(1);
+
+pub async fn locate_ht_manager(domain_name: &str, interface: Option<Ipv4Addr>) -> Result<Option<String>, mdns::Error> {
+    Ok(locate_ht_managers(domain_name, interface).await?.into_iter().next())
+}
+
+// All the managers announcing the domain, in the order they answered (empty if none answered within RESOLVE_TIMEOUT)
+pub async fn locate_ht_managers(domain_name: &str, interface: Option<Ipv4Addr>) -> Result<Vec<String>, mdns::Error> {
     let mut host_name = domain_name.to_owned();
     
     host_name.push('.');
     host_name.push_str(HT_MANAGER_SERVICE);
 
-    let result = mdns::resolve::one(HT_MANAGER_SERVICE, host_name, RESOLVE_TIMEOUT).await?;
+    let discovery = match interface {
+        Some(interface) => mdns::discover::interface(HT_MANAGER_SERVICE, RESOLVE_TIMEOUT, interface)?,
+        None => mdns::discover::all(HT_MANAGER_SERVICE, RESOLVE_TIMEOUT)?,
+    };
+    let stream = discovery.listen();
+    pin!(stream);
+
+    let mut managers: Vec<String> = Vec::new();
+    let deadline = tokio::time::Instant::now() + RESOLVE_TIMEOUT;
+    let mut collect_until = deadline;
+
+    while let Ok(Some(Ok(response))) = tokio::time::timeout_at(collect_until, stream.next()).await {
+        if !response.records().any(|record| record.name == host_name) {
+            continue;
+        }
 
-    match result {
-        Some(response) => {
-            let mut result = get_server_name(&response);
+        let manager = format!("{}:{}", get_server_name(&response), get_port(&response));
 
-            result.push(':');
-            result.push_str(&get_port(&response));
+        if !managers.contains(&manager) {
+            managers.push(manager);
+        }
 
-            Ok(Some(result))
-        },
-        None => Ok(None)
+        collect_until = collect_until.min(tokio::time::Instant::now() + COLLECT_WINDOW);
     }
+
+    Ok(managers)
 }
 
 fn get_server_name(response: &mdns::Response) -> String {
@@ -60,13 +83,17 @@ fn get_domain_name(response: &mdns::Response) -> String {
     full_domain_name[..full_domain_name.find('.').unwrap()].to_string()
 }
 
-pub async fn get_domains_list() -> Result<HashMap<String, String>, mdns::Error> {
+pub async fn get_domains_list(interface: Option<Ipv4Addr>) -> Result<HashMap<String, String>, mdns::Error> {
     let mut domains = HashMap::new();
     let timeout = tokio::time::sleep(Duration::from_millis(200));
     tokio::pin!(timeout);
 
     // Will yield only one request (the first one)
-    let stream = mdns::discover::all(HT_MANAGER_SERVICE,Duration::from_millis(400))?.listen();
+    let discovery = match interface {
+        Some(interface) => mdns::discover::interface(HT_MANAGER_SERVICE, Duration::from_millis(400), interface)?,
+        None => mdns::discover::all(HT_MANAGER_SERVICE,Duration::from_millis(400))?,
+    };
+    let stream = discovery.listen();
     pin!(stream);
 
     tokio::select! {
@@ -80,4 +107,37 @@ pub async fn get_domains_list() -> Result<HashMap<String, String>, mdns::Error>
         _ = &mut timeout => {},
     }
     Ok(domains)
-}
\ No newline at end of file
+}
+
+// IPv4 address of a network interface given by name (e.g. eth0) or directly by address, used to bind mDNS to it
+pub fn interface_address(interface: &str) -> Result<Ipv4Addr, String> {
+    if let Ok(address) = interface.parse::<Ipv4Addr>() {
+        return Ok(address);
+    }
+
+    let mut interfaces: *mut libc::ifaddrs = std::ptr::null_mut();
+
+    if unsafe { libc::getifaddrs(&mut interfaces) } != 0 {
+        return Err(format!("Cannot list network interfaces: {}", std::io::Error::last_os_error()));
+    }
+
+    let mut result = None;
+    let mut current = interfaces;
+
+    while !current.is_null() {
+        let entry = unsafe { &*current };
+
+        if !entry.ifa_addr.is_null() && unsafe { (*entry.ifa_addr).sa_family } as i32 == libc::AF_INET &&
+           unsafe { CStr::from_ptr(entry.ifa_name) }.to_str() == Ok(interface) {
+            let address = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+
+            result = Some(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)));
+            break;
+        }
+
+        current = entry.ifa_next;
+    }
+
+    unsafe { libc::freeifaddrs(interfaces) };
+    result.ok_or_else(|| format!("Network interface '{}' not found or has no IPv4 address", interface))
+}
@@ -0,0 +1,196 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Number of identical consecutive messages printed before they are suppressed
+const SHOWN_REPEATS: u32 = 3;
+
+// How often a summary of suppressed messages is printed
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Logger for retry loops: identical consecutive messages are printed a few times, then replaced by a periodic
+// summary, so a server that is down overnight does not fill the journal. The summary is printed by a timer, so a
+// burst followed by silence is still reported
+#[derive(Debug, Default)]
+pub struct RepeatedLog(Arc<Mutex<Suppression>>);
+
+#[derive(Debug, Default)]
+struct Suppression {
+    last_message: Option<String>,
+    repeats: u32,                       // Times the last message was logged (printed or not)
+    suppressed: u32,                    // Suppressed since the last summary
+    window: u32,                        // Current summary window, a timer only reports the window it was started for
+}
+
+impl RepeatedLog {
+    pub fn log(&mut self, message: String) {
+        let (lines, opened_window) = self.0.lock().unwrap().log(message);
+
+        for line in lines {
+            println!("{}", line);
+        }
+
+        if let Some(window) = opened_window {
+            let suppression = self.0.clone();
+
+            tokio::spawn(async move {
+                tokio::time::sleep(SUMMARY_INTERVAL).await;
+
+                if let Some(summary) = suppression.lock().unwrap().summary(window) {
+                    println!("{}", summary);
+                }
+            });
+        }
+    }
+
+    // The state advanced (or the message changed), report what was suppressed and start over
+    pub fn reset(&mut self) {
+        if let Some(summary) = self.0.lock().unwrap().reset() {
+            println!("{}", summary);
+        }
+    }
+}
+
+impl Suppression {
+    // Lines to print for the message, and the summary window opened by suppressing it (a summary is due at the end
+    // of the window)
+    fn log(&mut self, message: String) -> (Vec<String>, Option<u32>) {
+        if self.last_message.as_ref() != Some(&message) {
+            let mut lines: Vec<String> = self.reset().into_iter().collect();
+
+            lines.push(message.clone());
+            self.last_message = Some(message);
+            self.repeats = 1;
+            return (lines, None);
+        }
+
+        self.repeats += 1;
+
+        if self.repeats <= SHOWN_REPEATS {
+            return (vec![message], None);
+        }
+
+        self.suppressed += 1;
+
+        if self.suppressed == 1 {
+            self.window += 1;
+            return (Vec::new(), Some(self.window));
+        }
+
+        (Vec::new(), None)
+    }
+
+    // Summary at the end of a window, None if nothing was suppressed in it (or it was already reported by a reset)
+    fn summary(&mut self, window: u32) -> Option<String> {
+        if window != self.window || self.suppressed == 0 {
+            return None;
+        }
+
+        let summary = format!("{} ({} times in the last {} minutes)", self.last_message.as_deref().unwrap_or_default(), self.suppressed, SUMMARY_INTERVAL.as_secs() / 60);
+
+        self.suppressed = 0;
+        Some(summary)
+    }
+
+    fn reset(&mut self) -> Option<String> {
+        let summary = match self.last_message {
+            Some(ref message) if self.suppressed > 0 => Some(format!("{} ({} more times)", message, self.suppressed)),
+            _ => None,
+        };
+
+        *self = Suppression { window: self.window, ..Suppression::default() };
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_times(suppression: &mut Suppression, message: &str, times: u32) -> (Vec<String>, Vec<u32>) {
+        let mut lines = Vec::new();
+        let mut windows = Vec::new();
+
+        for _ in 0..times {
+            let (logged, window) = suppression.log(message.to_string());
+
+            lines.extend(logged);
+            windows.extend(window);
+        }
+
+        (lines, windows)
+    }
+
+    #[test]
+    fn repeats_are_shown_then_suppressed() {
+        let mut suppression = Suppression::default();
+        let (lines, windows) = log_times(&mut suppression, "Connection failed", 10);
+
+        assert_eq!(lines, vec!["Connection failed"; SHOWN_REPEATS as usize]);
+        assert_eq!(windows, vec![1]);
+        assert_eq!(suppression.suppressed, 10 - SHOWN_REPEATS);
+    }
+
+    #[test]
+    fn summary_reports_the_window_count() {
+        let mut suppression = Suppression::default();
+
+        log_times(&mut suppression, "Connection failed", SHOWN_REPEATS + 412);
+
+        assert_eq!(suppression.summary(1).unwrap(), "Connection failed (412 times in the last 10 minutes)");
+        assert_eq!(suppression.summary(1), None);
+
+        // Suppressing goes on in a new window
+        let (lines, windows) = log_times(&mut suppression, "Connection failed", 2);
+        assert!(lines.is_empty());
+        assert_eq!(windows, vec![2]);
+        assert_eq!(suppression.summary(2).unwrap(), "Connection failed (2 times in the last 10 minutes)");
+    }
+
+    #[test]
+    fn changed_message_reports_and_starts_over() {
+        let mut suppression = Suppression::default();
+
+        log_times(&mut suppression, "Connection failed", SHOWN_REPEATS + 5);
+
+        let (lines, windows) = log_times(&mut suppression, "Query failed", 1);
+        assert_eq!(lines, vec!["Connection failed (5 more times)", "Query failed"]);
+        assert!(windows.is_empty());
+        assert_eq!(suppression.repeats, 1);
+        assert_eq!(suppression.suppressed, 0);
+    }
+
+    #[test]
+    fn stale_timer_does_not_report_a_later_window() {
+        let mut suppression = Suppression::default();
+
+        log_times(&mut suppression, "Connection failed", SHOWN_REPEATS + 1);
+        assert_eq!(suppression.reset().unwrap(), "Connection failed (1 more times)");
+
+        let (_, windows) = log_times(&mut suppression, "Connection failed", SHOWN_REPEATS + 1);
+        assert_eq!(windows, vec![2]);
+        assert_eq!(suppression.summary(1), None);
+        assert!(suppression.summary(2).is_some());
+    }
+
+    #[test]
+    fn reset_without_suppressed_messages_is_silent() {
+        let mut suppression = Suppression::default();
+
+        log_times(&mut suppression, "Connection failed", SHOWN_REPEATS);
+        assert_eq!(suppression.reset(), None);
+        assert_eq!(Suppression::default().reset(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn summary_is_flushed_after_silence() {
+        let mut log = RepeatedLog::default();
+
+        for _ in 0..SHOWN_REPEATS + 3 {
+            log.log("Connection failed".to_string());
+        }
+        assert_eq!(log.0.lock().unwrap().suppressed, 3);
+
+        tokio::time::sleep(SUMMARY_INTERVAL + Duration::from_secs(1)).await;
+        assert_eq!(log.0.lock().unwrap().suppressed, 0);
+    }
+}
@@ -1,8 +1,10 @@
 
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, ToSocketAddrs};
 use tokio::sync::Mutex;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use rustop::opts;
 
 mod rfb_session;
@@ -10,43 +12,166 @@ mod screen;
 mod locator;
 mod query;
 mod resources;
+mod config;
+mod scale;
+mod night;
+mod font;
+mod ui;
+mod provision;
+mod shutdown;
+mod logging;
+mod vnc_mirror;
+mod remote_view;
+mod simulate;
+mod heartbeat;
+mod allowlist;
+mod breadcrumbs;
+mod test_pattern;
+mod device_check;
+mod retry_budget;
 
-use screen::Screen;
+use screen::{ColorAdjustment, DevicePixel, FramebufferPixelFormat, Screen, ScreenRegion, SplitLayout};
+use night::{NightMode, NightSchedule};
+use rfb_session::{NegotiationCache, Point, Rect, RfbSessionError, RfbSessionErrorKind, HandshakeError, HandshakeErrorClassifier, ServerReport, SessionOptions, Size, TouchInput, TouchOptions, TouchProtocol, LatencyProbe, EncodingRegistry, CurTextPosition};
+use query::QueryError;
+use shutdown::Shutdown;
+use logging::RepeatedLog;
+use heartbeat::SessionHealth;
+use allowlist::ServerAllowlist;
+use breadcrumbs::Breadcrumbs;
+use ui::UiState;
+use device_check::{DeviceCheck, DeviceKind};
+use retry_budget::RetryBudget;
 
 pub type ScreenLock = Arc<Mutex<Screen>>;
 
 #[derive(Debug, Clone, Copy)]
 enum SessionState {
+    LocalSession,           // Session with a server on this machine while the manager is asked for the assignment
     LocateServersManager,
     ConnectToServer,
     QueryServersManager,
     RfbSession,
 }
 
+#[derive(Debug, Clone)]
+struct DiscoveryOptions {
+    slow_link_threshold: Option<Duration>,     // Connecting slower than this asks the manager for another server
+    mdns_interface: Option<Ipv4Addr>,
+    local_server_port: Option<u16>,             // Start with a server on this machine if one answers on this port
+    prefer_local: bool,                         // Stay with the local server even if the manager assigns another one
+    allowed_servers: Option<ServerAllowlist>,   // Refuse servers assigned by the manager that are not on this list
+    connect_failures_before_requery: u32,       // Failed connections to the assigned server before the manager is asked again
+    query_failures_before_relocate: u32,        // Failed queries of each manager before the domain managers are located again
+    handshake_errors: HandshakeErrorClassifier, // Which handshake failures mean the server refuses the panel
+}
+
+// Outcome of a session with the local server
+enum LocalSessionResult {
+    Ended,
+    Reassigned(String, String),     // The manager assigned another server (manager, server)
+    Shutdown,
+}
+
+const LOCAL_CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+
+// A session lasting at least this long counts as successful and resets the --max-reconnects counter
+const MIN_SUCCESSFUL_SESSION: Duration = Duration::from_secs(60);
+
+// Managers not answering --query-failures-before-relocate queries in a row each (every query already retried for lost
+// datagrams) are located again. Fewer failures are taken as transient loss and the next manager announcing the domain
+// (the same one if there is only one) is asked after QUERY_RETRY_INTERVAL. Likewise a failed connection to the assigned
+// server is retried after QUERY_RETRY_INTERVAL until --connect-failures-before-requery
+const QUERY_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+// A server refusing the panel (e.g. "panel not authorized") is tried again only after this long
+const PERMANENT_ERROR_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+// After ctrl-c or SIGTERM, the process is forced to exit if the state machine has not wound down by then
+const FORCED_EXIT_DELAY: Duration = Duration::from_secs(2);
+
+// Exit status when giving up after --max-reconnects consecutive failures
+const EXIT_RECONNECTS_EXHAUSTED: i32 = 3;
+
+// Minimum time between framebuffer flushes (see --max-refresh-ms)
+const DEFAULT_REFRESH_MS: u64 = 16;
+const SLOW_DISPLAY_REFRESH_MS: u64 = 50;
+
+// Exit status when the framebuffer cannot be opened
+const EXIT_NO_FRAMEBUFFER: i32 = 4;
+
+// Exit status of --verify-server when the server cannot be reached or this client cannot work with it
+const EXIT_SERVER_NOT_COMPATIBLE: i32 = 5;
+
 struct StateManager {
+    name: String,
     screen: ScreenLock,
+    ui: tokio::sync::watch::Sender<UiState>,    // What the renderer shows while no session is drawing
     query_bytes: Vec<u8>,
+    last_crash_query: Option<Vec<u8>>,    // Query with LastCrash, sent until the manager answers once
+    session_options: SessionOptions,
+    shutdown: Shutdown,
+    negotiation_cache: NegotiationCache,
+    color_adjustment: ColorAdjustment,  // Fixed at startup, the negotiation cache tables are prepared for it
+    discovery_options: DiscoveryOptions,
+    max_reconnects: Option<u32>,
+    keep_frame: bool,
 
+    last_frame_shown: bool,         // The screen still shows the last frame of the previous session
+    failed_cycles: u32,             // Consecutive failed connections or too short sessions
+    failed_queries: u32,            // Consecutive failed manager queries
+    failed_connects: u32,           // Consecutive failed connections to the assigned server
+    retry_budget: RetryBudget,      // Paces the network attempts of all the states
+    manager_candidates: Vec<String>,    // All the managers announcing the domain, queried in turn
+    retry_log: RepeatedLog,
+    breadcrumbs: Breadcrumbs,
+    slow_link_reported: bool,
     servers_manager: Option<String>,
     server_address: Option<String>,
-    stream: Option<TcpStream>,
+    stream: Option<(TcpStream, Instant)>,  // The connected server and when the connection was made
 }
 
 impl StateManager {
-    fn new(name: &str) -> StateManager {
-        let screen = Screen::new().expect("Error while creating screen object");
-        let query_bytes = query::prepare_query(name, &screen);
+    fn new(name: &str, screen: Screen, session_options: SessionOptions, shutdown: Shutdown, discovery_options: DiscoveryOptions, max_reconnects: Option<u32>, keep_frame: bool,
+           breadcrumbs_file: &Path) -> StateManager {
+        let query_bytes = query::prepare_query_with(name, &screen, &input_capability(&session_options));
+        let color_adjustment = screen.color_adjustment.clone();
+        let screen = Arc::new(Mutex::new(screen));
 
         StateManager {
-            screen: Arc::new(Mutex::new(screen)),
+            name: name.to_string(),
+            ui: ui::start(screen.clone()),
+            screen,
             query_bytes,
+            last_crash_query: None,
+            session_options,
+            shutdown,
+            negotiation_cache: NegotiationCache::default(),
+            color_adjustment,
+            discovery_options,
+            max_reconnects,
+            keep_frame,
+            last_frame_shown: false,
+            failed_cycles: 0,
+            failed_queries: 0,
+            failed_connects: 0,
+            retry_budget: RetryBudget::default(),
+            manager_candidates: Vec::new(),
+            retry_log: RepeatedLog::default(),
+            breadcrumbs: Breadcrumbs::new(breadcrumbs_file),
+            slow_link_reported: false,
             servers_manager: None,
             server_address: None,
             stream: None,
         }
     }
 
-    async fn connect_to_server(server_address: &str) -> Option<TcpStream> {
+    // Get the pixel conversion table of a server seen before ready while the connection is made
+    fn prepare_negotiation(&self, server_address: &str) {
+        self.negotiation_cache.prepare(server_address, &self.color_adjustment);
+    }
+
+    async fn connect_to_server(server_address: impl ToSocketAddrs, shutdown: &Shutdown) -> Option<TcpStream> {
         let timeout = tokio::time::sleep(Duration::from_secs(3));
         tokio::pin!(timeout);
     
@@ -57,126 +182,576 @@ impl StateManager {
                     Err(_) => None,
                 }
             },
-            _ = &mut timeout => None
+            _ = &mut timeout => None,
+            _ = shutdown.requested() => None,
+        }
+    }
+
+    // Connect to the server assigned by the manager. If the connection is slow, the assignment is reported back to
+    // the manager and it is queried once more, giving it a chance to assign a closer server
+    async fn connect_to_assigned_server(&mut self, servers_manager: &str) -> SessionState {
+        let server_address = self.server_address.clone().unwrap();
+
+        // With an allowlist the connection goes to the addresses that were checked, not to a second resolution
+        let allowed_addresses = match self.discovery_options.allowed_servers {
+            Some(ref allowed_servers) => match allowed_servers.allowed_addresses(&server_address).await {
+                Some(addresses) => Some(addresses),
+                None => {
+                    self.retry_log.log(format!("Server {} assigned by {} is not allowed, retry in 3 seconds", server_address, servers_manager));
+                    self.show(UiState::Error { message: format!("Server {} is not allowed", server_address), retry_in: Duration::from_secs(3) });
+                    self.failed_cycles += 1;
+                    self.last_frame_shown = false;
+                    self.server_address = None;
+                    self.pause(Duration::from_secs(3)).await;
+                    return SessionState::QueryServersManager;
+                }
+            },
+            None => None,
+        };
+
+        self.prepare_negotiation(&server_address);
+        let connect_start = Instant::now();
+
+        let connection = match allowed_addresses {
+            Some(ref addresses) => Self::connect_to_server(addresses.as_slice(), &self.shutdown).await,
+            None => Self::connect_to_server(server_address.as_str(), &self.shutdown).await,
+        };
+
+        let stream = match connection {
+            Some(stream) => stream,
+            None => {
+                self.retry_budget.failed();
+                self.failed_cycles += 1;
+                self.failed_connects += 1;
+                self.last_frame_shown = false;
+
+                if self.failed_connects < self.discovery_options.connect_failures_before_requery {
+                    self.retry_log.log(format!("Connection to {} failed, retry in {} seconds", server_address, QUERY_RETRY_INTERVAL.as_secs()));
+                    self.pause(QUERY_RETRY_INTERVAL).await;
+                    return SessionState::ConnectToServer;
+                }
+
+                self.failed_connects = 0;
+                self.server_address = None;
+                return SessionState::QueryServersManager;
+            },
+        };
+
+        self.failed_connects = 0;
+
+        let connected_at = Instant::now();
+        let connect_time = connected_at - connect_start;
+
+        if let Some(threshold) = self.discovery_options.slow_link_threshold {
+            println!("Connected to {} in {} ms", server_address, connect_time.as_millis());
+
+            if connect_time > threshold && !self.slow_link_reported {
+                println!("Slow link to {}, asking {} for another server", server_address, servers_manager);
+
+                if let Err(e) = query::report_slow_link(servers_manager, &self.name, &server_address, connect_time).await {
+                    println!("Failed to report slow link to {}: {:?}", servers_manager, e);
+                }
+
+                self.slow_link_reported = true;
+                self.server_address = None;
+                return SessionState::QueryServersManager;
+            }
+        }
+
+        self.slow_link_reported = false;
+        self.stream = Some((stream, connected_at));
+        SessionState::RfbSession
+    }
+
+    // Locate the manager (unless already known) and query it until a server is assigned. Never returns once
+    // shutdown is requested, so it is only used raced against the shutdown request
+    async fn find_assignment(&self, domain_name: Option<&str>, known_manager: Option<&str>) -> (String, String) {
+        while !self.shutdown.is_requested() {
+            let servers_manager = match (known_manager, domain_name) {
+                (Some(servers_manager), _) => Some(servers_manager.to_string()),
+                (None, Some(domain_name)) => locator::locate_ht_manager(domain_name, self.discovery_options.mdns_interface).await.ok().flatten(),
+                (None, None) => None,
+            };
+
+            if let Some(servers_manager) = servers_manager {
+                if let Ok(server_address) = query::query_for_hometouch_server(&servers_manager, self.current_query(), &self.shutdown).await {
+                    return (servers_manager, server_address);
+                }
+            }
+
+            self.pause(Duration::from_secs(3)).await;
+        }
+
+        std::future::pending().await
+    }
+
+    // If a server answers on this machine, start a session with it right away while the manager is asked for the
+    // assignment in the background. Returns the state to continue with, or None on shutdown
+    async fn do_local_session(&mut self, port: u16, domain_name: Option<&str>, known_manager: Option<&str>) -> Option<SessionState> {
+        let discovery_state = if known_manager.is_some() { SessionState::QueryServersManager } else { SessionState::LocateServersManager };
+        let local_address = format!("127.0.0.1:{}", port);
+
+        self.prepare_negotiation(&local_address);
+
+        let stream = match tokio::time::timeout(LOCAL_CONNECT_TIMEOUT, TcpStream::connect(&local_address)).await {
+            Ok(Ok(stream)) => stream,
+            _ => return Some(discovery_state),
+        };
+        let connected_at = Instant::now();
+
+        println!("Local server at {}, starting session while waiting for the manager assignment", local_address);
+        self.show(UiState::Session);
+
+        let result = {
+            let session = rfb_session::run(stream, connected_at, self.screen.clone(), self.session_options.clone(), self.negotiation_cache.clone(), local_address.clone());
+            let assignment = self.find_assignment(domain_name, known_manager);
+            let mut assigned = false;
+
+            tokio::pin!(session);
+            tokio::pin!(assignment);
+
+            loop {
+                tokio::select! {
+                    _ = &mut session => break LocalSessionResult::Ended,
+                    (servers_manager, server_address) = &mut assignment, if !assigned => {
+                        assigned = true;
+
+                        if self.discovery_options.prefer_local || is_local_address(&server_address, port) {
+                            println!("{} -> {}, staying with the local server", servers_manager, server_address);
+                        } else {
+                            println!("{} -> {}, switching from the local server", servers_manager, server_address);
+                            break LocalSessionResult::Reassigned(servers_manager, server_address);
+                        }
+                    },
+                    _ = self.shutdown.requested() => break LocalSessionResult::Shutdown,
+                }
+            }
+        };
+
+        match result {
+            LocalSessionResult::Ended => Some(discovery_state),
+            LocalSessionResult::Reassigned(servers_manager, server_address) => {
+                self.servers_manager = Some(servers_manager);
+                self.server_address = Some(server_address);
+                Some(SessionState::ConnectToServer)
+            },
+            LocalSessionResult::Shutdown => None,
+        }
+    }
+
+    // With --keep-frame, reconnecting after a dropped session keeps the last frame on the screen (the first full
+    // update of the new session overwrites it) instead of flashing the splash. The splash comes back once a
+    // connection attempt fails
+    fn show_connecting(&self, server: &str) {
+        if !(self.keep_frame && self.last_frame_shown) {
+            self.show(UiState::Connecting { server: server.to_string() });
+        }
+    }
+
+    fn show(&self, state: UiState) {
+        self.ui.send_if_modified(|current| {
+            let modified = *current != state;
+
+            *current = state;
+            modified
+        });
+    }
+
+    fn gave_up(&self) -> bool {
+        self.max_reconnects.is_some_and(|max_reconnects| self.failed_cycles >= max_reconnects)
+    }
+
+    // The previous run ended without a clean exit, tell the manager with the first query
+    fn report_last_crash(&mut self, last_crash: String) {
+        let screen = self.screen.try_lock().expect("Screen is not in use before the sessions start");
+
+        let mut extra = input_capability(&self.session_options);
+
+        extra.push(("LastCrash", last_crash));
+        self.last_crash_query = Some(query::prepare_query_with(&self.name, &screen, &extra));
+    }
+
+    // Rotate to the next manager announcing the domain
+    fn next_manager_candidate(&mut self) {
+        let current = self.manager_candidates.iter().position(|manager| Some(manager) == self.servers_manager.as_ref());
+
+        if let Some(current) = current {
+            self.servers_manager = Some(self.manager_candidates[(current + 1) % self.manager_candidates.len()].clone());
+        }
+    }
+
+    fn current_query(&self) -> &[u8] {
+        self.last_crash_query.as_deref().unwrap_or(&self.query_bytes)
+    }
+
+    fn session_started(&mut self) {
+        self.show(UiState::Session);
+        self.retry_log.reset();
+        self.session_options.health.set_manager(self.servers_manager.clone());
+        self.session_options.health.session_started();
+    }
+
+    fn session_ended(&mut self, session_start: Instant, result: &Result<(), RfbSessionError>) {
+        // Only frames of this very session count, not those of an earlier session or of the other half of a split
+        // display
+        let frames_received = self.session_options.health.session_frames() > 0;
+
+        if frames_received {
+            self.retry_budget.reset();
+        }
+
+        self.last_frame_shown = true;
+        self.breadcrumbs.session_ended(session_start.elapsed());
+        self.session_options.health.session_ended();
+
+        // Closing an idle session is not a failure, however short the session was
+        if is_idle_disconnect(result) {
+            return;
+        }
+
+        if !frames_received {
+            self.retry_budget.failed();
+        }
+
+        if session_start.elapsed() >= MIN_SUCCESSFUL_SESSION {
+            self.failed_cycles = 0;
+        } else {
+            self.failed_cycles += 1;
+        }
+    }
+
+    // After an idle disconnect, show the sleeping screen until the panel is touched. Returns false if shutdown is
+    // requested first
+    async fn sleep_until_touched(&mut self) -> bool {
+        let mut activity = self.session_options.touch_input.subscribe_activity();
+
+        activity.borrow_and_update();
+        println!("Session closed after {:?} without touch, reconnecting on the next touch", self.session_options.idle_disconnect.unwrap_or_default());
+
+        self.show(UiState::Sleeping);
+        self.last_frame_shown = false;
+
+        tokio::select! {
+            changed = activity.changed() => changed.is_ok(),
+            _ = self.shutdown.requested() => false,
+        }
+    }
+
+    // A server refusing the panel is tried again only after a long pause, with the reason on the screen. Returns true
+    // if the session ended that way. Other reasons given by the server are logged and retried at the usual pace
+    async fn back_off_permanent_error(&mut self, result: &Result<(), RfbSessionError>) -> bool {
+        let reason = match result.as_ref().err().and_then(|e| self.discovery_options.handshake_errors.classify(e)) {
+            Some(HandshakeError::Permanent(reason)) => reason,
+            Some(HandshakeError::Transient(reason)) => {
+                self.retry_log.log(format!("Server rejected the session: {}, retrying", reason));
+                return false;
+            },
+            None => return false,
+        };
+
+        self.retry_log.log(format!("Server refused the panel: {}, retry in {} minutes", reason, PERMANENT_ERROR_BACKOFF.as_secs() / 60));
+        self.show(UiState::Error { message: reason, retry_in: PERMANENT_ERROR_BACKOFF });
+        self.last_frame_shown = false;
+        self.pause(PERMANENT_ERROR_BACKOFF).await;
+        true
+    }
+
+    // Wait until the retry budget allows the next network attempt, counting down on the screen while backing off.
+    // Returns false if shutdown is requested first
+    async fn wait_for_retry_budget(&mut self) -> bool {
+        loop {
+            let delay = self.retry_budget.delay(Instant::now());
+
+            if delay.is_zero() {
+                break;
+            }
+
+            self.session_options.health.set_next_attempt(Some(Instant::now() + delay));
+
+            if self.retry_budget.is_backing_off() {
+                self.last_frame_shown = false;
+                self.show(UiState::BackingOff { retry_in: Duration::from_secs(delay.as_secs_f64().ceil() as u64) });
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(delay.min(Duration::from_secs(1))) => {},
+                _ = self.shutdown.requested() => return false,
+            }
+        }
+
+        self.session_options.health.set_next_attempt(None);
+        self.retry_budget.attempt(Instant::now());
+        true
+    }
+
+    // Sleep unless shutdown is requested first
+    async fn pause(&self, duration: Duration) {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {},
+            _ = self.shutdown.requested() => {},
+        }
+    }
+
+    async fn run(&mut self, domain_name: Option<&str>, server_manager: Option<&str>, server_address: Option<&str>) {
+        if let Some(domain_name) = domain_name {
+            self.do_domain_session(domain_name).await;
+        }
+        else if let Some(server_manager) = server_manager {
+            self.do_manager_session(server_manager).await;
+        }
+        else if let Some(server_address) = server_address {
+            self.do_server_session(server_address).await;
+        }
+        else {
+            eprintln!("Either --server <server>, --manager <manager> or <domain name> must be specified");
         }
     }
 
     async fn do_domain_session(&mut self, domain_name: &str) {
-        let mut state: SessionState = SessionState::LocateServersManager;
+        let mut state = if self.discovery_options.local_server_port.is_some() { SessionState::LocalSession } else { SessionState::LocateServersManager };
 
         loop {
+            if self.shutdown.is_requested() || self.gave_up() {
+                return;
+            }
+
+            self.breadcrumbs.state(&format!("{:?}", state), self.server_address.as_deref(), self.failed_cycles);
+
             match state {
-                SessionState::LocateServersManager => {
-                    {
-                        let mut screen = self.screen.lock().await;
-                        
-                        screen.display_png_resource(resources::LOOKING_FOR_MANAGER_IMAGE);
+                SessionState::LocalSession => {
+                    match self.do_local_session(self.discovery_options.local_server_port.unwrap(), Some(domain_name), None).await {
+                        Some(next_state) => state = next_state,
+                        None => return,
                     }
+                },
+
+                SessionState::LocateServersManager => {
+                    let mut attempts = 0;
 
                     loop {
-                        if let Ok(Some(servers_manager)) = locator::locate_ht_manager(domain_name).await {
-                            self.servers_manager = Some(servers_manager);
-                            state = SessionState::QueryServersManager;
-                            break;
+                        if !self.wait_for_retry_budget().await {
+                            return;
+                        }
+
+                        self.show(UiState::LookingForManager { attempts });
+
+                        let located = tokio::select! {
+                            located = locator::locate_ht_managers(domain_name, self.discovery_options.mdns_interface) => located,
+                            _ = self.shutdown.requested() => return,
+                        };
+
+                        if let Ok(managers) = located {
+                            if let Some(servers_manager) = managers.first() {
+                                if managers.len() > 1 {
+                                    println!("Domain '{}' is managed by {}", domain_name, managers.join(", "));
+                                }
+
+                                self.servers_manager = Some(servers_manager.clone());
+                                self.manager_candidates = managers;
+                                state = SessionState::QueryServersManager;
+                                break;
+                            }
                         }
-                        println!("Could not locate domain '{}'", domain_name);
+                        self.retry_log.log(format!("Could not locate domain '{}'", domain_name));
+                        self.retry_budget.failed();
+                        attempts += 1;
                     };
                 },
 
                 SessionState::QueryServersManager => {
-                    {
-                        let mut screen = self.screen.lock().await;
-                        
-                        screen.display_png_resource(resources::QUERY_FOR_SERVER_IMAGE);
+                    if !self.wait_for_retry_budget().await {
+                        return;
                     }
 
-                    match query::query_for_hometouch_server(self.servers_manager.as_ref().unwrap(), &self.query_bytes).await {
-                        Some(server_address) => {
+                    self.show(UiState::Querying);
+
+                    match query::query_for_hometouch_server(self.servers_manager.as_ref().unwrap(), self.current_query(), &self.shutdown).await {
+                        Ok(server_address) => {
+                            self.last_crash_query = None;
+                            self.failed_queries = 0;
                             self.server_address = Some(server_address);
                             state = SessionState::ConnectToServer;
                         },
-                        None => {
-                            self.servers_manager = None;
-                            state = SessionState::LocateServersManager;
+                        Err(QueryError::Cancelled) => return,
+                        Err(e) => {
+                            if let QueryError::Network(e) = e {
+                                self.retry_log.log(format!("Query of server manager {} failed: {}", self.servers_manager.as_ref().unwrap(), e));
+                            }
+
+                            self.retry_budget.failed();
+                            self.failed_queries += 1;
+
+                            if self.failed_queries < self.discovery_options.query_failures_before_relocate * self.manager_candidates.len().max(1) as u32 {
+                                self.next_manager_candidate();
+
+                                tokio::select! {
+                                    _ = tokio::time::sleep(QUERY_RETRY_INTERVAL) => {},
+                                    _ = self.shutdown.requested() => return,
+                                }
+                            } else {
+                                self.failed_queries = 0;
+                                self.servers_manager = None;
+                                state = SessionState::LocateServersManager;
+                            }
                         }
                     };
                 },
 
                 SessionState::ConnectToServer => {
-                    {
-                        let mut screen = self.screen.lock().await;
-                        
-                        screen.display_png_resource(resources::CONNECTING_TO_SERVER_IMAGE);
+                    if !self.wait_for_retry_budget().await {
+                        return;
                     }
 
-                    match Self::connect_to_server(self.server_address.as_ref().unwrap()).await {
-                        Some(stream) => {
-                            self.stream = Some(stream);
-                            state = SessionState::RfbSession;
-                        },
-                        None => {
-                            self.server_address = None;
-                            state = SessionState::QueryServersManager;
-                        },
-                    };
+                    self.show_connecting(self.server_address.as_deref().unwrap());
+
+                    let servers_manager = self.servers_manager.clone().unwrap();
+
+                    state = self.connect_to_assigned_server(&servers_manager).await;
                 },
 
                 SessionState::RfbSession => {
                     println!("{} managed by {} -> {}", domain_name, self.servers_manager.as_ref().unwrap(), self.server_address.as_ref().unwrap());
-                    let _ = rfb_session::run(self.stream.take().unwrap(), self.screen.clone()).await;
-                    state = SessionState::ConnectToServer;
+                    let session_start = Instant::now();
+
+                    self.session_started();
+                    let (stream, connected_at) = self.stream.take().unwrap();
+                    let result = tokio::select! {
+                        result = rfb_session::run(stream, connected_at, self.screen.clone(), self.session_options.clone(),
+                                                  self.negotiation_cache.clone(), self.server_address.clone().unwrap()) => result,
+                        _ = self.shutdown.requested() => return,
+                    };
+
+                    self.session_ended(session_start, &result);
+
+                    if is_idle_disconnect(&result) {
+                        if !self.sleep_until_touched().await {
+                            return;
+                        }
+
+                        state = SessionState::ConnectToServer;
+                        continue;
+                    }
+
+                    if let Err(ref e) = result {
+                        self.breadcrumbs.error(format!("{:?}", e));
+                    }
+
+                    // The manager may assign a server that accepts the panel
+                    if self.back_off_permanent_error(&result).await {
+                        state = SessionState::QueryServersManager;
+                        continue;
+                    }
+
+                    // A server that does not complete the handshake may be wedged, so ask the manager again. Otherwise
+                    // reconnect to the same server
+                    state = match result {
+                        Err(e) if matches!(e.kind(), RfbSessionErrorKind::Timeout { phase } if phase.is_handshake()) => SessionState::QueryServersManager,
+                        _ => SessionState::ConnectToServer,
+                    };
                 },
             }
         }
     }
 
     async fn do_manager_session(&mut self, server_manager: &str) {
-        let mut state: SessionState = SessionState::QueryServersManager;
+        // The heartbeat goes to the manager of the current session
+        self.servers_manager = Some(server_manager.to_string());
+
+        let mut state = if self.discovery_options.local_server_port.is_some() { SessionState::LocalSession } else { SessionState::QueryServersManager };
 
         loop {
+            if self.shutdown.is_requested() || self.gave_up() {
+                return;
+            }
+
+            self.breadcrumbs.state(&format!("{:?}", state), self.server_address.as_deref(), self.failed_cycles);
+
             match state {
+                SessionState::LocalSession => {
+                    match self.do_local_session(self.discovery_options.local_server_port.unwrap(), None, Some(server_manager)).await {
+                        Some(next_state) => state = next_state,
+                        None => return,
+                    }
+                },
+
                 SessionState::QueryServersManager => {
-                    {
-                        let mut screen = self.screen.lock().await;
-                        
-                        screen.display_png_resource(resources::QUERY_FOR_SERVER_IMAGE);
+                    if !self.wait_for_retry_budget().await {
+                        return;
                     }
 
-                    match query::query_for_hometouch_server(server_manager, &self.query_bytes).await {
-                        Some(server_address) => {
+                    self.show(UiState::Querying);
+
+                    match query::query_for_hometouch_server(server_manager, self.current_query(), &self.shutdown).await {
+                        Ok(server_address) => {
+                            self.last_crash_query = None;
                             self.server_address = Some(server_address);
                             state = SessionState::ConnectToServer;
                         },
-                        None => {
-                            println!("Query of server manager {} failed, retry in 3 seconds", server_manager);
-                            tokio::time::sleep(Duration::from_secs(3)).await;
+                        Err(QueryError::Cancelled) => return,
+                        Err(QueryError::Network(e)) => {
+                            self.retry_budget.failed();
+                            self.retry_log.log(format!("Query of server manager {} failed: {}, retry in 3 seconds", server_manager, e));
+                            self.show(UiState::Error { message: format!("Server manager {} is not answering", server_manager), retry_in: Duration::from_secs(3) });
+                            self.pause(Duration::from_secs(3)).await;
+                        },
+                        Err(QueryError::Timeout) => {
+                            self.retry_budget.failed();
+                            self.retry_log.log(format!("Query of server manager {} failed, retry in 3 seconds", server_manager));
+                            self.show(UiState::Error { message: format!("Server manager {} is not answering", server_manager), retry_in: Duration::from_secs(3) });
+                            self.pause(Duration::from_secs(3)).await;
                         }
                     };
                 },
 
                 SessionState::ConnectToServer => {
-                    {
-                        let mut screen = self.screen.lock().await;
-                        
-                        screen.display_png_resource(resources::CONNECTING_TO_SERVER_IMAGE);
+                    if !self.wait_for_retry_budget().await {
+                        return;
                     }
 
-                    match Self::connect_to_server(self.server_address.as_ref().unwrap()).await {
-                        Some(stream) => {
-                            self.stream = Some(stream);
-                            state = SessionState::RfbSession;
-                        },
-                        None => {
-                            self.server_address = None;
-                            state = SessionState::QueryServersManager;
-                        },
-                    };
+                    self.show_connecting(self.server_address.as_deref().unwrap());
+
+                    state = self.connect_to_assigned_server(server_manager).await;
                 },
 
                 SessionState::RfbSession => {
                     println!("{} -> {}", server_manager, self.server_address.as_ref().unwrap());
-                    let _ = rfb_session::run(self.stream.take().unwrap(), self.screen.clone()).await;
-                    state = SessionState::ConnectToServer;
+                    let session_start = Instant::now();
+
+                    self.session_started();
+                    let (stream, connected_at) = self.stream.take().unwrap();
+                    let result = tokio::select! {
+                        result = rfb_session::run(stream, connected_at, self.screen.clone(), self.session_options.clone(),
+                                                  self.negotiation_cache.clone(), self.server_address.clone().unwrap()) => result,
+                        _ = self.shutdown.requested() => return,
+                    };
+
+                    self.session_ended(session_start, &result);
+
+                    if is_idle_disconnect(&result) {
+                        if !self.sleep_until_touched().await {
+                            return;
+                        }
+
+                        state = SessionState::ConnectToServer;
+                        continue;
+                    }
+
+                    if let Err(ref e) = result {
+                        self.breadcrumbs.error(format!("{:?}", e));
+                    }
+
+                    // The manager may assign a server that accepts the panel
+                    if self.back_off_permanent_error(&result).await {
+                        state = SessionState::QueryServersManager;
+                        continue;
+                    }
+
+                    // A server that does not complete the handshake may be wedged, so ask the manager again. Otherwise
+                    // reconnect to the same server
+                    state = match result {
+                        Err(e) if matches!(e.kind(), RfbSessionErrorKind::Timeout { phase } if phase.is_handshake()) => SessionState::QueryServersManager,
+                        _ => SessionState::ConnectToServer,
+                    };
                 },
                 s => panic!("Unexpected state: {:?}", s),
             }
@@ -187,27 +762,64 @@ impl StateManager {
         let mut state = SessionState::ConnectToServer;
 
         loop {
+            if self.shutdown.is_requested() || self.gave_up() {
+                return;
+            }
+
+            self.breadcrumbs.state(&format!("{:?}", state), Some(server_address), self.failed_cycles);
+
             match state {
                 SessionState::ConnectToServer => {
-                    {
-                        let mut screen = self.screen.lock().await;
-                        
-                        screen.display_png_resource(resources::CONNECTING_TO_SERVER_IMAGE);
+                    if !self.wait_for_retry_budget().await {
+                        return;
                     }
 
-                    match Self::connect_to_server(server_address).await {
+                    self.show_connecting(server_address);
+                    self.prepare_negotiation(server_address);
+
+                    match Self::connect_to_server(server_address, &self.shutdown).await {
                         Some(stream) => {
-                            self.stream = Some(stream);
+                            self.stream = Some((stream, Instant::now()));
                             state = SessionState::RfbSession;
                         },
                         None => {
-                            println!("Connection to {} failed, retry in 3 seconds", server_address);
-                            tokio::time::sleep(Duration::from_secs(3)).await;
+                            self.retry_budget.failed();
+                            self.failed_cycles += 1;
+                            self.last_frame_shown = false;
+                            self.retry_log.log(format!("Connection to {} failed, retry in 3 seconds", server_address));
+                            self.show(UiState::Error { message: format!("Cannot connect to {}", server_address), retry_in: Duration::from_secs(3) });
+                            self.pause(Duration::from_secs(3)).await;
                         }
                     }
                 }
                 SessionState::RfbSession => {
-                    let _ = rfb_session::run(self.stream.take().unwrap(), self.screen.clone()).await;
+                    let session_start = Instant::now();
+
+                    self.session_started();
+
+                    let (stream, connected_at) = self.stream.take().unwrap();
+                    let result = tokio::select! {
+                        result = rfb_session::run(stream, connected_at, self.screen.clone(), self.session_options.clone(),
+                                                  self.negotiation_cache.clone(), server_address.to_string()) => result,
+                        _ = self.shutdown.requested() => return,
+                    };
+
+                    self.session_ended(session_start, &result);
+
+                    if is_idle_disconnect(&result) {
+                        if !self.sleep_until_touched().await {
+                            return;
+                        }
+
+                        state = SessionState::ConnectToServer;
+                        continue;
+                    }
+
+                    if let Err(ref e) = result {
+                        self.breadcrumbs.error(format!("{:?}", e));
+                    }
+
+                    self.back_off_permanent_error(&result).await;
                     state = SessionState::ConnectToServer;
                 },
                 s => panic!("Unexpected state: {:?}", s),
@@ -216,19 +828,261 @@ impl StateManager {
     }
 }
 
+// Touch coordinates of a region of the display (--split)
+fn touch_region(region: &ScreenRegion) -> Rect {
+    Rect {
+        location: Point { x: region.x as u16, y: region.y as u16 },
+        size: Size { width: region.width as u16, height: region.height as u16 },
+    }
+}
+
+// Handshake with the server up to ServerInit (--verify-server and --probe), no frames are requested
+async fn check_server(server_address: &str, screen: &mut Screen, session_options: SessionOptions) -> ServerReport {
+    match tokio::time::timeout(Duration::from_secs(3), TcpStream::connect(server_address)).await {
+        Ok(Ok(connection)) => rfb_session::verify_server(connection, screen, session_options, server_address).await,
+        Ok(Err(e)) => ServerReport::unreachable(server_address, e.to_string()),
+        Err(_) => ServerReport::unreachable(server_address, String::from("timeout")),
+    }
+}
+
+// --verify-server: report what the server offers and whether this client can work with it
+async fn verify_server(server_address: &str, screen: &mut Screen, session_options: SessionOptions, on_screen: bool) -> bool {
+    let report = check_server(server_address, screen, session_options).await;
+    let lines = report.lines();
+
+    lines.iter().for_each(|line| println!("{}", line));
+
+    if on_screen {
+        const SCALE: usize = 2;
+        let line_height = font::text_height(SCALE) + 4;
+
+        screen.clear();
+
+        for (index, line) in lines.iter().enumerate() {
+            screen.draw_text(8, 8 + index * line_height, line, SCALE, DevicePixel::from_rgb(255, 255, 255), None);
+        }
+
+        screen.update();
+    }
+
+    report.is_compatible()
+}
+
+fn is_idle_disconnect(result: &Result<(), RfbSessionError>) -> bool {
+    result.as_ref().is_err_and(|e| matches!(e.kind(), RfbSessionErrorKind::IdleDisconnect))
+}
+
+// Extra query keys telling the manager the panel does not send input, so the server can hide interactive elements
+fn input_capability(session_options: &SessionOptions) -> Vec<(&'static str, String)> {
+    if session_options.view_only {
+        vec![("InputCapable", String::from("false"))]
+    } else {
+        vec![]
+    }
+}
+
+// True if the address is this machine at the given port (either loopback or one of its own addresses)
+fn is_local_address(address: &str, port: u16) -> bool {
+    match address.parse::<std::net::SocketAddr>() {
+        Ok(address) => address.port() == port && (address.ip().is_loopback() || std::net::UdpSocket::bind((address.ip(), 0)).is_ok()),
+        Err(_) => false,
+    }
+}
+
+// Every option once, optional ones marked with ?. The environment is applied and --print-config lists the options
+// from this table, the defaults are only those in opts!
+macro_rules! each_option {
+    ($action:ident, $args:ident) => {
+        $action!($args, domain?);
+        $action!($args, server?);
+        $action!($args, port);
+        $action!($args, manager?);
+        $action!($args, name);
+        $action!($args, gamma);
+        $action!($args, color_temp);
+        $action!($args, background_color);
+        $action!($args, prefer_raw);
+        $action!($args, handshake_timeout);
+        $action!($args, read_timeout);
+        $action!($args, write_timeout);
+        $action!($args, dead_link_secs);
+        $action!($args, mirror_fb?);
+        $action!($args, mirror_fps);
+        $action!($args, mirror_port?);
+        $action!($args, remote_view_port?);
+        $action!($args, lenient);
+        $action!($args, night?);
+        $action!($args, night_wake_minutes);
+        $action!($args, pressure_threshold?);
+        $action!($args, cur_text_position);
+        $action!($args, stats_overlay);
+        $action!($args, slow_frame_ms?);
+        $action!($args, button_device?);
+        $action!($args, button_map);
+        $action!($args, touch_protocol);
+        $action!($args, touch_moves);
+        $action!($args, tap_delay_ms?);
+        $action!($args, control_socket?);
+        $action!($args, latency_probe_secs?);
+        $action!($args, idle_disconnect_secs?);
+        $action!($args, drift_check_secs?);
+        $action!($args, view_only);
+        $action!($args, no_touch_device);
+        $action!($args, verbose_touch);
+        $action!($args, jpeg_quality?);
+        $action!($args, compression?);
+        $action!($args, show_cursor);
+        $action!($args, max_refresh_ms?);
+        $action!($args, pace_fps?);
+        $action!($args, pixel_format);
+        $action!($args, highlight_updates);
+        $action!($args, frame_log);
+        $action!($args, decode_buffer_cap_kb);
+        $action!($args, strict);
+        $action!($args, test_pattern);
+        $action!($args, verify_server?);
+        $action!($args, verify_on_screen);
+        $action!($args, probe);
+        $action!($args, provision);
+        $action!($args, provision_file);
+        $action!($args, slow_link_probe);
+        $action!($args, probe_threshold_ms);
+        $action!($args, connect_failures_before_requery);
+        $action!($args, query_failures_before_relocate);
+        $action!($args, permanent_error_reasons?);
+        $action!($args, mdns_interface?);
+        $action!($args, local_server?);
+        $action!($args, prefer_local);
+        $action!($args, allowed_servers?);
+        $action!($args, max_reconnects?);
+        $action!($args, heartbeat_secs);
+        $action!($args, keep_frame);
+        $action!($args, split?);
+        $action!($args, split_server?);
+        $action!($args, simulate?);
+        $action!($args, stampede);
+        $action!($args, print_config);
+        $action!($args, domains);
+    };
+}
+
+macro_rules! apply_environment {
+    ($args:ident, $option:ident ?) => { config::env_optional(&mut $args.$option, stringify!($option))? };
+    ($args:ident, $option:ident) => { config::env_default(&mut $args.$option, stringify!($option))? };
+}
+
+macro_rules! print_config_entry {
+    ($args:ident, $option:ident ?) => { println!("{}", config::optional_config_entry(stringify!($option), &$args.$option)) };
+    ($args:ident, $option:ident) => { println!("{}", config::config_entry(stringify!($option), &$args.$option)) };
+}
+
 #[tokio::main]
 async fn main() {
-    let (args, _) = opts! {
+    let (mut args, _) = opts! {
         synopsis "Hometouch server client";
         opt server:Option<String>, desc: "Connect to specific HomeTouch (RFB) server";
+        opt port:u16=5900, desc: "RFB port used when --server does not include one (host:port or host::port, host:display=N for VNC display N)";
         opt manager:Option<String>, desc: "Use manager at specific address (default is the use mDNS for finding manager address";
         opt name:String = gethostname::gethostname().into_string().unwrap();
+        opt gamma:f64=1.0, desc: "Gamma correction applied to the panel (> 1 brightens mid tones)";
+        opt color_temp:String=String::from("neutral"), desc: "Color temperature correction: neutral, warm, cool or red,green,blue gains (e.g. 1.0,0.95,0.8)";
+        opt background_color:String=String::from("000000"), desc: "Background color (hex RGB) behind splash images and around a smaller remote screen";
+        opt prefer_raw:bool=false, desc: "Prefer Raw over HexTile encoding (faster on gigabit LAN where decoding is the bottleneck)";
+        opt handshake_timeout:u64=10, desc: "Seconds to wait for each server read during the RFB handshake";
+        opt read_timeout:u64=60, desc: "Seconds without data from the server within a frame update before reconnecting (a stalled read)";
+        opt write_timeout:u64=30, desc: "Seconds to wait for each message to be written to the server before reconnecting";
+        opt dead_link_secs:u64=30, desc: "Drop a connection that stopped answering (TCP keepalive, unacknowledged writes) after about this many seconds, 0 to rely on the kernel defaults";
+        opt mirror_fb:Option<String>, desc: "Mirror a scaled down copy of the screen to another framebuffer (e.g. /dev/fb1)";
+        opt mirror_fps:f64=2.0, desc: "Maximum refresh rate of the mirror framebuffer and the VNC mirror";
+        opt mirror_port:Option<u16>, desc: "Serve a read-only view of the screen to up to 4 VNC viewers on this port (e.g. 5901)";
+        opt remote_view_port:Option<u16>, desc: "Serve the current screen as http://<panel>:<port>/screen.png for remote support (exposes whatever the panel shows)";
+        opt lenient:bool=false, desc: "Skip SetColourMapEntries, Bell and ServerCutText messages instead of disconnecting";
+        opt night:Option<String>, desc: "Turn the display off between these local times (e.g. 23:00-06:30), touch to wake";
+        opt night_wake_minutes:u64=5, desc: "Minutes the display stays on after the last touch during the night";
+        opt pressure_threshold:Option<i32>, desc: "Detect touches by pressure above this value (for touch controllers without a reliable BTN_TOUCH)";
+        opt cur_text_position:String=String::from("bottom"), desc: "Where the status text sent by the server is shown: top, bottom or off";
+        opt stats_overlay:bool=false, desc: "Show frame rate, bandwidth and decode time in the top right corner";
+        opt slow_frame_ms:Option<u64>, desc: "Log a timing breakdown for frames taking longer than this (milliseconds)";
+        opt button_device:Option<String>, desc: "Input device with physical navigation buttons (e.g. /dev/input/event1 from gpio-keys)";
+        opt button_map:String=String::from("158:0x20,159:0x40"), desc: "Button key codes and the pointer button mask they send (default KEY_BACK/KEY_FORWARD to extended buttons 0x20/0x40)";
+        opt touch_protocol:String=String::from("auto"), desc: "Touch coordinates from multitouch (mt) or single-touch ABS_X/ABS_Y (st) axes, auto selects by the device axes";
+        opt touch_moves:bool=false, desc: "Send pointer moves while the panel is touched (drags and swipes), by default only the press and release are sent";
+        opt tap_delay_ms:Option<u64>, desc: "Hold back the release of a quick tap until this many milliseconds after the press (e.g. 20, for servers dropping instant clicks)";
+        opt control_socket:Option<String>, desc: "Unix socket (mode 0600) accepting tap/press/release/move X Y, key KEYSYM down|up and latency commands for scripted testing (e.g. /run/ht.sock)";
+        opt latency_probe_secs:Option<u64>, desc: "Measure the input to screen latency this often (seconds), needs a server echoing the probe marker";
+        opt idle_disconnect_secs:Option<u64>, desc: "Close the session after this many seconds without touch, reconnecting on the next touch";
+        opt drift_check_secs:Option<u64>, desc: "Every this many seconds request a full frame and log where it differs from the screen (detects decoder drift)";
+        opt view_only:bool=false, desc: "Status display only: touch and buttons are not read and no input is sent to the server";
+        opt no_touch_device:bool=false, desc: "Display without touch hardware: the touch device is not opened, pointer events come from the control socket (--control-socket)";
+        opt verbose_touch:bool=false, desc: "Log raw touch input events and the pointer events sent to the server";
+        opt jpeg_quality:Option<u8>, desc: "Use Tight encoding with JPEG at this quality (0-9) for photo-like content";
+        opt compression:Option<u8>, desc: "Use Tight encoding with this compression level (0 fastest - 9 smallest, higher suits Wi-Fi)";
+        opt show_cursor:bool=false, desc: "Draw the server cursor (for servers sending cursor shape and position updates)";
+        opt max_refresh_ms:Option<u64>, desc: "Coalesce frame updates into at most one framebuffer flush per this many milliseconds, 0 to flush every update (default 16, 50 for SPI panels)";
+        opt pace_fps:Option<f64>, desc: "Present frames at this steady rate to smooth animations (adds up to one frame of latency)";
+        opt pixel_format:String=String::from("auto"), desc: "Framebuffer pixel format: rgb565, bgr565, grayscale8 (8 bits per pixel grayscale and e-ink panels) or auto to detect it";
+        opt highlight_updates:bool=false, desc: "Outline the rectangles of each frame update until the next update, to see what the server sends (diagnostic)";
+        opt frame_log:bool=false, desc: "Log the encoding and geometry of each rectangle of each frame update (diagnostic)";
+        opt decode_buffer_cap_kb:usize=1024, desc: "Release the reusable decode buffer after a rectangle needing more than this (KB), instead of keeping it";
+        opt strict:bool=false, desc: "Validate the RFB stream structure and reconnect on violations (diagnostic)";
+        opt test_pattern:bool=false, desc: "Show a test pattern (color bars, grid, corner markers) to check a new panel without a server, until ctrl-c";
+        opt verify_server:Option<String>, desc: "Check that the RFB server (host:port) can be used: print its protocol version, security types, framebuffer size, pixel format and name, then exit (0 if compatible)";
+        opt verify_on_screen:bool=false, desc: "With --verify-server, also show the result on the screen until ctrl-c";
+        opt probe:bool=false, desc: "Handshake with --server, print its name, size, pixel format and security types as one line of JSON and exit (for inventory scripts)";
+        opt provision:bool=false, desc: "Pick the domain on the touchscreen from the domains found on the network and save it in the provision file (also done when no domain, manager or server is configured)";
+        opt provision_file:String=String::from(provision::PROVISION_FILE), desc: "File keeping the domain picked with --provision, used when no domain, manager or server is given";
+        opt print_config:bool=false, desc: "Print the effective configuration (TOML) with the source of each value and exit";
+        opt slow_link_probe:bool=false, desc: "Measure the connection to the server assigned by the manager, a slow link is reported to the manager and another server asked for";
+        opt probe_threshold_ms:u64=150, desc: "With --slow-link-probe, connect time (milliseconds) above which the link is slow";
+        opt connect_failures_before_requery:u32=1, desc: "Failed connections to the server assigned by the manager before the manager is asked again";
+        opt query_failures_before_relocate:u32=3, desc: "Failed queries of each manager of the domain before the managers are located again";
+        opt permanent_error_reasons:Option<String>, desc: "Comma separated (case insensitive) parts of server handshake errors meaning the panel is refused, retried only every 5 minutes (added to 'not authorized', 'access denied' etc.)";
+        opt mdns_interface:Option<String>, desc: "Network interface (name or IPv4 address) used for mDNS discovery";
+        opt local_server:Option<u16>, desc: "Start right away with a server on this machine at this port while the manager is queried";
+        opt prefer_local:bool=false, desc: "Stay with the local server (--local-server) even if the manager assigns another one";
+        opt allowed_servers:Option<String>, desc: "Only connect to manager assigned servers on this list of addresses, CIDR networks or host names (e.g. 10.0.1.0/24,fd00::/64,ht-server)";
+        opt max_reconnects:Option<u32>, desc: "Exit with status 3 after this many consecutive failed connections or sessions shorter than a minute";
+        opt heartbeat_secs:u64=60, desc: "Seconds between health reports (last frame age, uptime, reconnects) to the manager, 0 to disable";
+        opt keep_frame:bool=false, desc: "Keep showing the last frame while reconnecting after a dropped session (no splash flash)";
+        opt split:Option<String>, desc: "Show two panels on this display, side by side (vertical) or one above the other (horizontal). Each queries the manager as <name>-1 and <name>-2";
+        opt split_server:Option<String>, desc: "Server of the second panel with --split and --server (default: the same server)";
+        opt simulate:Option<u32>, desc: "Load test the manager: simulate this many panels querying it and connecting to the assigned servers, then exit";
+        opt stampede:bool=false, desc: "Start all --simulate panels at once instead of staggered with limited concurrency";
         opt domains:bool=false, desc: "List available Hometoucher domains (_HtVncConf._udp.local)";
         param domain:Option<String>, desc: "Domain to connect to (e.g 'Beit Zait House' or 'Tel-Aviv Apt')";
     }.parse_or_exit();
 
+    // Options not given on the command line may be set in the environment (see config::env_name), for container
+    // and systemd deployments. Precedence: command line, environment, default
+    let environment = (|| -> Result<(), String> {
+        each_option!(apply_environment, args);
+        Ok(())
+    })();
+
+    if let Err(e) = environment {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let mdns_interface = match args.mdns_interface.as_ref().map(|interface| locator::interface_address(interface)).transpose() {
+        Ok(mdns_interface) => mdns_interface,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // The domain picked when the panel was provisioned
+    if args.domain.is_none() && args.manager.is_none() && args.server.is_none() && !args.provision {
+        args.domain = provision::read_domain(Path::new(&args.provision_file));
+
+        if let Some(ref domain) = args.domain {
+            println!("Domain '{}' (from {})", domain, args.provision_file);
+        }
+    }
+
     if args.domains {
-        match locator::get_domains_list().await {
+        match locator::get_domains_list(mdns_interface).await {
             Ok(domains) => {
                 println!("Found {} domains:", domains.len());
                 for (name, address) in domains.iter() {
@@ -241,28 +1095,479 @@ async fn main() {
         std::process::exit(0);
     }
 
-    if Screen::set_console_to_graphic_mode().is_ok() {
-        ctrlc::set_handler(move || {
-            let _ = Screen::set_console_to_text_mode();
+    if args.print_config {
+        each_option!(print_config_entry, args);
+
+        std::process::exit(0);
+    }
+
+    if let Some(panel_count) = args.simulate {
+        let manager_source = match (&args.manager, &args.domain) {
+            (Some(manager), _) => simulate::ManagerSource::Address(manager.clone()),
+            (None, Some(domain)) => simulate::ManagerSource::Domain(domain.clone(), mdns_interface),
+            (None, None) => {
+                eprintln!("--simulate needs --manager <manager> or <domain name>");
+                std::process::exit(1);
+            }
+        };
+
+        simulate::run(panel_count, &args.name, manager_source, args.stampede).await;
+        std::process::exit(0);
+    }
+
+    // Each panel of a split display keeps its own record. Read before anything (e.g. a failed device check) overwrites
+    // the record of the previous run
+    let breadcrumbs_files: Vec<PathBuf> = match args.split {
+        Some(_) => (1..=2).map(breadcrumbs::panel_file).collect(),
+        None => vec![PathBuf::from(breadcrumbs::BREADCRUMBS_FILE)],
+    };
+    let last_crashes: Vec<Option<String>> = breadcrumbs_files.iter().map(|file| Breadcrumbs::previous_unclean_exit(file)).collect();
+
+    let mut device_checks = vec![
+        DeviceCheck::run("/dev/fb0", DeviceKind::Framebuffer),
+        DeviceCheck::run("/dev/console", DeviceKind::Console),
+    ];
+
+    if !args.no_touch_device {
+        device_checks.push(DeviceCheck::run(rfb_session::INPUT_DEVICE_NAME, DeviceKind::Input));
+    }
+
+    if let Some(ref button_device) = args.button_device {
+        device_checks.push(DeviceCheck::run(button_device, DeviceKind::Input));
+    }
+
+    device_check::report(&device_checks, args.probe);
+
+    if !device_checks[0].is_ok() {
+        for breadcrumbs_file in breadcrumbs_files.iter() {
+            let mut breadcrumbs = Breadcrumbs::new(breadcrumbs_file);
+
+            breadcrumbs.device_checks(device_check::summary(&device_checks));
+            breadcrumbs.finish("NoFramebuffer");
+        }
+
+        std::process::exit(EXIT_NO_FRAMEBUFFER);
+    }
+
+    // Best effort: it only keeps the console text and cursor off the screen. On KMS/DRM display stacks the KD ioctl
+    // may not apply while the framebuffer works regardless. Text mode is restored on exit only if it was changed here.
+    // --probe draws nothing and its stdout is a single line of JSON, so the console is left alone
+    let graphic_mode = !args.probe && match Screen::set_console_to_graphic_mode() {
+        Ok(_) => {
+            println!("Console switched to graphics mode, text mode is restored on exit");
+            true
+        },
+        Err(e) => {
+            eprintln!("Console mode not changed, cannot set /dev/console to graphics mode: {:?} (run with sudo or as service if console text shows through). Continuing", e);
+            false
+        }
+    };
+
+    let (shutdown_sender, shutdown) = shutdown::channel();
+    let (wound_down_sender, wound_down) = std::sync::mpsc::channel::<()>();
+
+    ctrlc::set_handler(move || {
+        let _ = shutdown_sender.send(true);
+
+        // The state machine should wind down right away (main then exits on its own), if it does not, force the exit
+        if let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = wound_down.recv_timeout(FORCED_EXIT_DELAY) {
+            if graphic_mode {
+                let _ = Screen::set_console_to_text_mode();
+            }
             std::process::exit(0);
-        }).expect("Failed to set ctrl-c handler");
+        }
+    }).expect("Failed to set ctrl-c handler");
+
+    let night_mode = match args.night {
+        Some(ref night) => match NightSchedule::parse(night) {
+            Ok(schedule) => {
+                let night_mode = Arc::new(NightMode::new(schedule, Duration::from_secs(args.night_wake_minutes * 60)));
+
+                tokio::spawn(night_mode.clone().run());
+                Some(night_mode)
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // Input runs for the whole application, so a touch wakes the display even between sessions
+    let button_map = match config::parse_button_map(&args.button_map) {
+        Ok(button_map) => button_map,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let touch_protocol = match TouchProtocol::parse(&args.touch_protocol) {
+        Ok(touch_protocol) => touch_protocol,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Missing input devices may still show up, but without permission to read them the panel runs display-only
+    let permission_denied = |device: &str| device_checks.iter().any(|check| check.device == device && check.permission_denied());
+    let display_only = permission_denied(rfb_session::INPUT_DEVICE_NAME);
+    let button_device = args.button_device.clone().filter(|button_device| !permission_denied(button_device));
+
+    if display_only {
+        eprintln!("No permission to read the touch input, running display-only");
+    }
+
+    if args.no_touch_device && !args.probe {
+        println!("No touch device: pointer events only come from the control socket{}", if button_device.is_some() { " and the buttons" } else { "" });
+    }
+
+    // Nothing but a touch wakes the panel from an idle disconnect or picks the domain when provisioning
+    let no_touch = display_only || args.no_touch_device;
+
+    if args.view_only && !args.probe {
+        println!("View-only: touch and button input is not read");
+    }
+
+    let touch_input = if display_only || args.view_only || args.probe { TouchInput::default() } else { TouchInput::start(night_mode.clone(), TouchOptions {
+        pressure_threshold: args.pressure_threshold,
+        verbose: args.verbose_touch,
+        protocol: touch_protocol,
+        moves: args.touch_moves,
+        tap_delay: args.tap_delay_ms.map(Duration::from_millis),
+        touch_device: !args.no_touch_device,
+        button_device,
+        button_map,
+    }) };
+
+    // Without touch input nothing would wake the panel again
+    if args.idle_disconnect_secs.is_some() && (no_touch || args.view_only) {
+        eprintln!("--idle-disconnect-secs needs touch input, it is ignored");
     }
-    else {
-        eprintln!("Failed to set /dev/console to graphics mode (run with sudo or as service)")
+
+    let idle_disconnect = args.idle_disconnect_secs.filter(|seconds| *seconds > 0 && !no_touch && !args.view_only).map(Duration::from_secs);
+
+    let cur_text_position = match CurTextPosition::parse(&args.cur_text_position) {
+        Ok(cur_text_position) => cur_text_position,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let split = match args.split.as_deref().map(SplitLayout::parse).transpose() {
+        Ok(split) => split,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Each panel of a split display has its own health: heartbeat, status and retry budget
+    let latency_probe = LatencyProbe::default();
+    let panel_health: Vec<SessionHealth> = (0..if split.is_some() { 2 } else { 1 }).map(|_| SessionHealth::default()).collect();
+
+    if args.view_only && (args.control_socket.is_some() || args.latency_probe_secs.is_some()) {
+        eprintln!("--control-socket and --latency-probe-secs send input, they are ignored with --view-only");
+    }
+
+    if let Some(control_socket) = args.control_socket.clone().filter(|_| !args.view_only) {
+        tokio::spawn(rfb_session::serve_control_socket(control_socket, touch_input.clone(), latency_probe.clone(), panel_health.clone()));
+    }
+
+    let latency_probe_secs = args.latency_probe_secs.filter(|seconds| *seconds > 0 && !args.view_only);
+
+    if let Some(latency_probe_secs) = latency_probe_secs {
+        tokio::spawn(rfb_session::run_latency_probe(latency_probe.clone(), touch_input.clone(), Duration::from_secs(latency_probe_secs)));
+    }
+
+    // Probes are sent periodically or on request from the control socket
+    let latency_marker = latency_probe_secs.is_some() || (args.control_socket.is_some() && !args.view_only);
+
+    let mut session_options = SessionOptions {
+        strict: args.strict,
+        lenient: args.lenient,
+        prefer_raw: args.prefer_raw,
+        handshake_timeout: Duration::from_secs(args.handshake_timeout),
+        read_timeout: Duration::from_secs(args.read_timeout),
+        write_timeout: Duration::from_secs(args.write_timeout),
+        dead_link_timeout: Some(args.dead_link_secs).filter(|seconds| *seconds > 0).map(Duration::from_secs),
+        night_mode,
+        stats_overlay: args.stats_overlay,
+        slow_frame_threshold: args.slow_frame_ms.map(Duration::from_millis),
+        pace_interval: args.pace_fps.filter(|fps| *fps > 0.0).map(|fps| Duration::from_secs_f64(1.0 / fps)),
+        jpeg_quality: args.jpeg_quality,
+        compression: args.compression,
+        show_cursor: args.show_cursor,
+        touch_input,
+        health: panel_health[0].clone(),
+        latency_probe,
+        latency_marker,
+        custom_encodings: EncodingRegistry::default(),
+        view_only: args.view_only,
+        highlight_updates: args.highlight_updates,
+        frame_log: args.frame_log,
+        decode_buffer_cap: args.decode_buffer_cap_kb * 1024,
+        cur_text_position,
+        idle_disconnect,
+        drift_check_interval: args.drift_check_secs.filter(|seconds| *seconds > 0).map(Duration::from_secs),
+    };
+
+    let color_gains = match ColorAdjustment::parse_color_temperature(&args.color_temp) {
+        Ok(gains) => gains,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let background_color = match config::parse_hex_color(&args.background_color) {
+        Ok(color) => color,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let server_address = match args.server.as_ref().map(|server| config::server_address(server, args.port)).transpose() {
+        Ok(server_address) => server_address,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let allowed_servers = match args.allowed_servers.as_ref().map(|allowed_servers| ServerAllowlist::parse(allowed_servers)).transpose() {
+        Ok(allowed_servers) => allowed_servers,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if cfg!(not(feature = "tight")) && (args.jpeg_quality.is_some() || args.compression.is_some()) {
+        eprintln!("--jpeg-quality and --compression are not available, this build does not include Tight encoding");
+        std::process::exit(1);
+    }
+
+    if args.compression.is_some_and(|compression| compression > 9) {
+        eprintln!("Invalid compression level {} (must be 0-9)", args.compression.unwrap());
+        std::process::exit(1);
+    }
+
+    if args.jpeg_quality.is_some_and(|jpeg_quality| jpeg_quality > 9) {
+        eprintln!("Invalid JPEG quality {} (must be 0-9)", args.jpeg_quality.unwrap());
+        std::process::exit(1);
+    }
+
+    // NaN compares false with everything, is_finite() rejects it (and infinity)
+    if !args.gamma.is_finite() || args.gamma <= 0.0 {
+        eprintln!("Invalid gamma {} (must be a positive number)", args.gamma);
+        std::process::exit(1);
+    }
+
+    // The mirrors copy the screen of a single panel
+    if split.is_some() && (args.mirror_fb.is_some() || args.mirror_port.is_some() || args.remote_view_port.is_some()) {
+        eprintln!("--split cannot be combined with --mirror-fb, --mirror-port or --remote-view-port");
+        std::process::exit(1);
+    }
+
+    let split_server_address = match args.split_server.as_ref().map(|server| config::server_address(server, args.port)).transpose() {
+        Ok(split_server_address) => split_server_address.or_else(|| server_address.clone()),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut screen = Screen::new().expect("Error while creating screen object");
+
+    if let Err(e) = FramebufferPixelFormat::parse(&args.pixel_format).and_then(|pixel_format| screen.set_pixel_format(pixel_format)) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    screen.color_adjustment = ColorAdjustment::new(args.gamma, color_gains);
+    screen.background_color = background_color;
+
+    if let Some(ref mirror_fb) = args.mirror_fb {
+        // The mirror is a nice to have, never let it prevent the main panel from working
+        if let Err(e) = screen.attach_mirror(mirror_fb, args.mirror_fps) {
+            eprintln!("{} - running without mirror", e);
+        }
+    }
+
+    if let Some(mirror_port) = args.mirror_port.filter(|_| !args.probe) {
+        if args.mirror_fps > 0.0 {
+            tokio::spawn(vnc_mirror::run(mirror_port, screen.publish_snapshots(args.mirror_fps), args.name.clone()));
+        } else {
+            eprintln!("Invalid mirror refresh rate {} - running without VNC mirror", args.mirror_fps);
+        }
+    }
+
+    if let Some(remote_view_port) = args.remote_view_port.filter(|_| !args.probe) {
+        tokio::spawn(remote_view::run(remote_view_port, screen.publish_snapshots(remote_view::REMOTE_VIEW_FPS)));
+    }
+
+    if args.test_pattern {
+        test_pattern::draw(&mut screen);
+        shutdown.requested().await;
+
+        if graphic_mode {
+            let _ = Screen::set_console_to_text_mode();
+        }
+        std::process::exit(0);
     }
 
-    let mut state_manager = StateManager::new(&args.name);
+    if args.probe {
+        let report = match server_address {
+            Some(ref server_address) => check_server(server_address, &mut screen, session_options.clone()).await,
+            None => {
+                eprintln!("--probe needs --server");
+                std::process::exit(1);
+            }
+        };
 
-    if let Some(domain) = args.domain {
-        state_manager.do_domain_session(&domain).await;
+        println!("{}", report.to_json());
+
+        if graphic_mode {
+            let _ = Screen::set_console_to_text_mode();
+        }
+        std::process::exit(if report.reached_server_init() { 0 } else { EXIT_SERVER_NOT_COMPATIBLE });
     }
-    else if let Some(manager) = args.manager {
-        state_manager.do_manager_session(&manager).await;
+
+    if let Some(ref server_address) = args.verify_server {
+        let compatible = verify_server(server_address, &mut screen, session_options.clone(), args.verify_on_screen).await;
+
+        if args.verify_on_screen {
+            shutdown.requested().await;
+        }
+
+        if graphic_mode {
+            let _ = Screen::set_console_to_text_mode();
+        }
+        std::process::exit(if compatible { 0 } else { EXIT_SERVER_NOT_COMPATIBLE });
     }
-    else if let Some(server) = args.server {
-        state_manager.do_server_session(&server).await;
+
+    // Nothing to connect to: let the installer pick the domain on the touchscreen
+    if args.provision || (args.domain.is_none() && args.manager.is_none() && server_address.is_none()) {
+        if no_touch || args.view_only {
+            eprintln!("Either --server <server>, --manager <manager> or <domain name> must be specified (provisioning needs touch input)");
+            std::process::exit(1);
+        }
+
+        let (touch_sender, mut touches) = tokio::sync::mpsc::channel(10);
+        let _touch_attachment = session_options.touch_input.attach(touch_sender, tokio::sync::watch::channel(true).1);
+
+        let domain = tokio::select! {
+            domain = provision::run(&mut screen, &mut touches, move || async move {
+                locator::get_domains_list(mdns_interface).await.map(|domains| domains.into_keys().collect()).unwrap_or_default()
+            }) => domain,
+            _ = shutdown.requested() => None,
+        };
+
+        match domain {
+            Some(domain) => {
+                match provision::write_domain(Path::new(&args.provision_file), &domain) {
+                    Ok(_) => println!("Domain '{}' saved in {}", domain, args.provision_file),
+                    Err(e) => eprintln!("Cannot save the domain in {}: {}", args.provision_file, e),
+                }
+
+                args.domain = Some(domain);
+            },
+            None => {
+                if graphic_mode {
+                    let _ = Screen::set_console_to_text_mode();
+                }
+                std::process::exit(0);
+            }
+        }
     }
-    else {
-        eprintln!("Either --server <server>, --manager <manager> or <domain name> must be specified");
+
+    // Without --pace-fps, flushes are still coalesced, so a slow (e.g. SPI) panel does not fall behind a server sending
+    // many small updates. A frame arriving after a quiet period is flushed right away
+    if session_options.pace_interval.is_none() {
+        let default_refresh_ms = if screen.is_slow_display() { SLOW_DISPLAY_REFRESH_MS } else { DEFAULT_REFRESH_MS };
+
+        session_options.pace_interval = Some(args.max_refresh_ms.unwrap_or(default_refresh_ms)).filter(|ms| *ms > 0).map(Duration::from_millis);
+    }
+
+    let discovery_options = DiscoveryOptions {
+        slow_link_threshold: if args.slow_link_probe { Some(Duration::from_millis(args.probe_threshold_ms)) } else { None },
+        mdns_interface,
+        local_server_port: args.local_server,
+        prefer_local: args.prefer_local,
+        allowed_servers,
+        connect_failures_before_requery: args.connect_failures_before_requery.max(1),
+        query_failures_before_relocate: args.query_failures_before_relocate.max(1),
+        handshake_errors: HandshakeErrorClassifier::default().with_permanent_reasons(args.permanent_error_reasons.as_deref().unwrap_or_default()),
+    };
+
+    // With --split each half of the display is a panel of its own: its own screen, touch region, manager query and
+    // server. Touches are delivered to the panel where they start
+    let (mut state_manager, mut split_state_manager) = match split {
+        Some(split) => {
+            let [first, second] = [0, 1].map(|panel| {
+                let region = split.regions(screen.xres(), screen.yres())[panel];
+                let region_screen = screen.open_region(region).expect("Error while creating screen region");
+                let mut region_options = session_options.clone();
+
+                region_options.touch_input = session_options.touch_input.for_region(touch_region(&region));
+                region_options.health = panel_health[panel].clone();
+                (region_screen, region_options)
+            });
+
+            println!("Split display: {}x{} and {}x{} panels", first.0.xres(), first.0.yres(), second.0.xres(), second.0.yres());
+            drop(screen);
+
+            (StateManager::new(&format!("{}-1", args.name), first.0, first.1, shutdown.clone(), discovery_options.clone(), args.max_reconnects, args.keep_frame, &breadcrumbs_files[0]),
+             Some(StateManager::new(&format!("{}-2", args.name), second.0, second.1, shutdown.clone(), discovery_options, args.max_reconnects, args.keep_frame, &breadcrumbs_files[1])))
+        },
+        None => (StateManager::new(&args.name, screen, session_options, shutdown.clone(), discovery_options, args.max_reconnects, args.keep_frame, &breadcrumbs_files[0]), None),
+    };
+
+    for (state_manager, last_crash) in std::iter::once(&mut state_manager).chain(split_state_manager.iter_mut()).zip(last_crashes) {
+        state_manager.breadcrumbs.device_checks(device_check::summary(&device_checks));
+
+        if let Some(last_crash) = last_crash {
+            eprintln!("*** Previous run of {} did not exit cleanly: {} ***", state_manager.name, last_crash);
+            state_manager.report_last_crash(last_crash);
+        }
+
+        if args.heartbeat_secs > 0 {
+            tokio::spawn(heartbeat::run(state_manager.session_options.health.clone(), state_manager.name.clone(), Duration::from_secs(args.heartbeat_secs), shutdown.clone()));
+        }
+    }
+
+    // The panel exits once either half does (shutdown or giving up)
+    match split_state_manager {
+        Some(ref mut split_state_manager) => {
+            tokio::select! {
+                _ = state_manager.run(args.domain.as_deref(), args.manager.as_deref(), server_address.as_deref()) => {},
+                _ = split_state_manager.run(args.domain.as_deref(), args.manager.as_deref(), split_server_address.as_deref()) => {},
+            }
+        },
+        None => state_manager.run(args.domain.as_deref(), args.manager.as_deref(), server_address.as_deref()).await,
+    }
+
+    let _ = wound_down_sender.send(());
+
+    if graphic_mode {
+        let _ = Screen::set_console_to_text_mode();
+    }
+
+    let gave_up_after = std::iter::once(&state_manager).chain(split_state_manager.iter()).find(|state_manager| state_manager.gave_up()).map(|state_manager| state_manager.failed_cycles);
+
+    for state_manager in std::iter::once(&mut state_manager).chain(split_state_manager.iter_mut()) {
+        let final_state = if state_manager.gave_up() { "GaveUp" } else { "Exited" };
+
+        state_manager.breadcrumbs.finish(final_state);
+    }
+
+    if let Some(failed_cycles) = gave_up_after {
+        eprintln!("Giving up after {} consecutive failures", failed_cycles);
+        std::process::exit(EXIT_RECONNECTS_EXHAUSTED);
     }
 }
@@ -0,0 +1,149 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+const BACKLIGHT_CLASS: &str = "/sys/class/backlight";
+const EVALUATE_INTERVAL: Duration = Duration::from_secs(15);
+
+// Night period in local time, given as minutes since midnight. The period may wrap around midnight
+#[derive(Debug, Clone, Copy)]
+pub struct NightSchedule {
+    start: u32,
+    end: u32,
+}
+
+impl NightSchedule {
+    // Parse a period such as "23:00-06:30"
+    pub fn parse(value: &str) -> Result<NightSchedule, String> {
+        let invalid = || format!("Invalid night schedule '{}' (expected HH:MM-HH:MM)", value);
+        let parse_time = |time: &str| -> Option<u32> {
+            let (hours, minutes) = time.trim().split_once(':')?;
+            let hours = hours.parse::<u32>().ok()?;
+            let minutes = minutes.parse::<u32>().ok()?;
+
+            if hours < 24 && minutes < 60 { Some(hours * 60 + minutes) } else { None }
+        };
+
+        let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+
+        Ok(NightSchedule {
+            start: parse_time(start).ok_or_else(invalid)?,
+            end: parse_time(end).ok_or_else(invalid)?,
+        })
+    }
+
+    pub fn contains(&self, minute_of_day: u32) -> bool {
+        if self.start <= self.end {
+            minute_of_day >= self.start && minute_of_day < self.end
+        } else {
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+}
+
+// Turns the display off during the night schedule. A touch wakes the display, which then stays on until there was
+// no interaction for `wake_duration`. The display is powered down using the backlight `bl_power` control, if there is
+// no backlight device, the session blanks the screen and stops requesting frame updates instead
+#[derive(Debug)]
+pub struct NightMode {
+    schedule: NightSchedule,
+    wake_duration: Duration,
+    last_interaction: Mutex<Instant>,
+    display_on: watch::Sender<bool>,
+    backlight: Option<PathBuf>,
+}
+
+impl NightMode {
+    pub fn new(schedule: NightSchedule, wake_duration: Duration) -> NightMode {
+        let backlight = find_backlight_power_control();
+
+        match backlight {
+            Some(ref path) => println!("Night mode: using {} to turn the display off", path.display()),
+            None => println!("Night mode: no backlight control found, display will be blanked instead"),
+        }
+
+        NightMode {
+            schedule,
+            wake_duration,
+            last_interaction: Mutex::new(Instant::now()),
+            display_on: watch::Sender::new(true),
+            backlight,
+        }
+    }
+
+    pub fn is_display_on(&self) -> bool {
+        *self.display_on.borrow()
+    }
+
+    pub fn has_backlight(&self) -> bool {
+        self.backlight.is_some()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.display_on.subscribe()
+    }
+
+    // Record a user interaction. Returns true if the display was off, in which case the touch only wakes the
+    // display and should not be delivered to the server
+    pub fn touched(&self) -> bool {
+        *self.last_interaction.lock().unwrap() = Instant::now();
+
+        if self.is_display_on() {
+            false
+        } else {
+            self.set_display(true);
+            true
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            self.evaluate();
+            tokio::time::sleep(EVALUATE_INTERVAL).await;
+        }
+    }
+
+    fn evaluate(&self) {
+        let recently_touched = self.last_interaction.lock().unwrap().elapsed() < self.wake_duration;
+        let display_on = recently_touched || !self.schedule.contains(local_minute_of_day());
+
+        if display_on != self.is_display_on() {
+            self.set_display(display_on);
+        }
+    }
+
+    fn set_display(&self, on: bool) {
+        println!("Night mode: turning display {}", if on { "on" } else { "off" });
+
+        if let Some(ref path) = self.backlight {
+            // bl_power uses the FB_BLANK values: 0 is on, 4 is powered down
+            if let Err(e) = fs::write(path, if on { "0" } else { "4" }) {
+                eprintln!("Night mode: failed to write {}: {}", path.display(), e);
+            }
+        }
+
+        self.display_on.send_replace(on);
+    }
+}
+
+fn find_backlight_power_control() -> Option<PathBuf> {
+    fs::read_dir(BACKLIGHT_CLASS).ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().join("bl_power"))
+        .find(|path| path.exists())
+}
+
+fn local_minute_of_day() -> u32 {
+    // SAFETY: localtime_r only writes into the provided tm structure
+    let local_time = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut local_time: libc::tm = std::mem::zeroed();
+
+        libc::localtime_r(&now, &mut local_time);
+        local_time
+    };
+
+    (local_time.tm_hour * 60 + local_time.tm_min) as u32
+}
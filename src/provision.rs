@@ -0,0 +1,293 @@
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+
+use crate::font;
+use crate::rfb_session::{PointerEventArgs, ToServerMessage};
+use crate::screen::{DevicePixel, Screen};
+
+// Commissioning from the touchscreen (--provision): the domains found by mDNS are shown as large buttons, the one the
+// installer taps is saved in the provision file and used from then on. The locator and the touches are passed in, so
+// the flow can run against stubbed sources
+
+pub const PROVISION_FILE: &str = "/etc/hometoucher/provision.conf";
+
+const DOMAINS_PER_PAGE: usize = 6;
+const MARGIN: usize = 8;
+const CONFIRMATION_TIME: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Area {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Area {
+    fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Domain(usize),      // Index in the list of all domains
+    Previous,
+    Rescan,
+    Next,
+}
+
+// Buttons of a page: a full width row per domain below the title, and the previous, rescan and next buttons at the
+// bottom (previous and next only when there is such a page). A screen too small for the margins gets empty buttons
+pub fn layout(width: usize, height: usize, domain_count: usize, page: usize) -> Vec<(Target, Area)> {
+    let title_height = font::text_height(3) + 2 * MARGIN;
+    let bar_height = height / 6;
+    let row_height = height.saturating_sub(title_height + bar_height) / DOMAINS_PER_PAGE;
+    let first = page * DOMAINS_PER_PAGE;
+    let mut targets: Vec<(Target, Area)> = (first..domain_count.min(first + DOMAINS_PER_PAGE)).enumerate().map(|(row, index)| {
+        (Target::Domain(index), Area { x: MARGIN, y: title_height + row * row_height, width: width.saturating_sub(2 * MARGIN), height: row_height.saturating_sub(MARGIN) })
+    }).collect();
+
+    let button_width = width.saturating_sub(4 * MARGIN) / 3;
+    let bar_y = height - bar_height;
+    let button = |column: usize| Area { x: MARGIN + column * (button_width + MARGIN), y: bar_y, width: button_width, height: bar_height.saturating_sub(MARGIN) };
+
+    if page > 0 {
+        targets.push((Target::Previous, button(0)));
+    }
+
+    targets.push((Target::Rescan, button(1)));
+
+    if first + DOMAINS_PER_PAGE < domain_count {
+        targets.push((Target::Next, button(2)));
+    }
+
+    targets
+}
+
+// Let the installer pick a domain. Returns None if the touch input went away
+pub async fn run<L, F>(screen: &mut Screen, touches: &mut Receiver<ToServerMessage>, mut locate: L) -> Option<String>
+where
+    L: FnMut() -> F,
+    F: Future<Output = Vec<String>>,
+{
+    loop {
+        show_message(screen, "Looking for domains...");
+
+        let mut domains = locate().await;
+        let mut page = 0;
+
+        domains.sort();
+        domains.dedup();
+
+        loop {
+            let targets = layout(screen.xres(), screen.yres(), domains.len(), page);
+
+            draw_page(screen, &domains, &targets);
+
+            let target = loop {
+                let (x, y) = next_tap(touches).await?;
+
+                if let Some((target, _)) = targets.iter().find(|(_, area)| area.contains(x, y)) {
+                    break *target;
+                }
+            };
+
+            match target {
+                Target::Domain(index) => {
+                    show_message(screen, &format!("Domain '{}' selected", domains[index]));
+                    tokio::time::sleep(CONFIRMATION_TIME).await;
+                    return Some(domains[index].clone());
+                },
+                Target::Previous => page -= 1,
+                Target::Next => page += 1,
+                Target::Rescan => break,
+            }
+        }
+    }
+}
+
+// Location of the next press (a touch going down)
+async fn next_tap(touches: &mut Receiver<ToServerMessage>) -> Option<(usize, usize)> {
+    let mut down = false;
+
+    loop {
+        if let ToServerMessage::PointerEvent(PointerEventArgs{button_mask, location, ..}) = touches.recv().await? {
+            let pressed = button_mask & 1 != 0;
+
+            if pressed && !down {
+                return Some((location.x as usize, location.y as usize));
+            }
+
+            down = pressed;
+        }
+    }
+}
+
+fn draw_page(screen: &mut Screen, domains: &[String], targets: &[(Target, Area)]) {
+    let title = if domains.is_empty() { String::from("No domains found") } else { format!("Select the domain ({} found)", domains.len()) };
+
+    screen.clear();
+    screen.draw_text(MARGIN, MARGIN, &title, 3, DevicePixel::from_rgb(255, 255, 255), None);
+
+    for (target, area) in targets {
+        let label = match target {
+            Target::Domain(index) => domains[*index].as_str(),
+            Target::Previous => "< Previous",
+            Target::Rescan => "Rescan",
+            Target::Next => "Next >",
+        };
+
+        draw_button(screen, area, label);
+    }
+
+    screen.update();
+}
+
+fn draw_button(screen: &mut Screen, area: &Area, label: &str) {
+    // The largest text scale the label fits at
+    let scale = (1..=3).rev().find(|scale| font::text_width(label, *scale) + 2 * MARGIN <= area.width && font::text_height(*scale) <= area.height).unwrap_or(1);
+
+    screen.fill_rect(area.x, area.y, area.width, area.height, DevicePixel::from_rgb(48, 48, 96));
+    screen.draw_text(area.x + MARGIN, area.y + area.height.saturating_sub(font::text_height(scale)) / 2, label, scale, DevicePixel::from_rgb(255, 255, 255), None);
+}
+
+fn show_message(screen: &mut Screen, message: &str) {
+    let x = screen.xres().saturating_sub(font::text_width(message, 3)) / 2;
+    let y = screen.yres().saturating_sub(font::text_height(3)) / 2;
+
+    screen.clear();
+    screen.draw_text(x, y, message, 3, DevicePixel::from_rgb(255, 255, 255), None);
+    screen.update();
+}
+
+// The domain saved by a previous provisioning, if any
+pub fn read_domain(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok()?.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+
+        (key.trim() == "domain").then(|| unquote(value.trim()))
+    })
+}
+
+// Save the domain, keeping the other lines of the file. The file is replaced atomically (written to a temporary file
+// which is renamed over it), so a power cut never leaves a half written file
+pub fn write_domain(path: &Path, domain: &str) -> std::io::Result<()> {
+    let domain_line = format!("domain = \"{}\"", domain.replace('\\', "\\\\").replace('"', "\\\""));
+    let existing = std::fs::read_to_string(path).unwrap_or_default();
+    let mut lines: Vec<String> = existing.lines().filter(|line| line.split_once('=').map(|(key, _)| key.trim()) != Some("domain")).map(String::from).collect();
+
+    lines.push(domain_line);
+
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+
+    let temporary_path = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&temporary_path)?;
+
+    writeln!(file, "{}", lines.join("\n"))?;
+    file.sync_all()?;
+    std::fs::rename(&temporary_path, path)
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temporary_file(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("hometoucher-provision-{}-{}", std::process::id(), name));
+
+        let _ = std::fs::remove_dir_all(&directory);
+        directory.join("provision.conf")
+    }
+
+    fn targets(width: usize, height: usize, domain_count: usize, page: usize) -> Vec<Target> {
+        layout(width, height, domain_count, page).into_iter().map(|(target, _)| target).collect()
+    }
+
+    #[test]
+    fn buttons_are_on_the_screen_and_apart() {
+        for (width, height) in [(800, 480), (1024, 600), (320, 240)] {
+            let areas: Vec<Area> = layout(width, height, 20, 1).into_iter().map(|(_, area)| area).collect();
+
+            for (index, area) in areas.iter().enumerate() {
+                assert!(area.x + area.width <= width && area.y + area.height <= height, "{:?} on {}x{}", area, width, height);
+
+                for other in areas[index + 1..].iter() {
+                    let apart = area.x + area.width <= other.x || other.x + other.width <= area.x || area.y + area.height <= other.y || other.y + other.height <= area.y;
+                    assert!(apart, "{:?} and {:?} overlap", area, other);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pages() {
+        assert_eq!(targets(800, 480, 0, 0), vec![Target::Rescan]);
+        assert_eq!(targets(800, 480, 3, 0), vec![Target::Domain(0), Target::Domain(1), Target::Domain(2), Target::Rescan]);
+        assert_eq!(targets(800, 480, 7, 0).last(), Some(&Target::Next));
+        assert_eq!(targets(800, 480, 7, 1), vec![Target::Domain(6), Target::Previous, Target::Rescan]);
+        assert_eq!(targets(800, 480, 12, 1).len(), DOMAINS_PER_PAGE + 2);
+    }
+
+    #[test]
+    fn tiny_screens_do_not_panic() {
+        for (width, height) in [(0, 0), (1, 1), (10, 10), (40, 30)] {
+            assert!(!layout(width, height, 8, 0).is_empty());
+        }
+    }
+
+    #[test]
+    fn domain_round_trip() {
+        let path = temporary_file("round-trip");
+
+        assert_eq!(read_domain(&path), None);
+
+        for domain in ["Beit Zait House", "Tel-Aviv \"Apt\"", "back\\slash\\", "\\\""] {
+            write_domain(&path, domain).unwrap();
+            assert_eq!(read_domain(&path).as_deref(), Some(domain));
+        }
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn other_lines_are_kept() {
+        let path = temporary_file("other-lines");
+
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "# Installed by the setup script\ndomain = \"Old\"\nroom = kitchen\n").unwrap();
+        write_domain(&path, "New").unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+
+        assert_eq!(content, "# Installed by the setup script\nroom = kitchen\ndomain = \"New\"\n");
+        assert_eq!(read_domain(&path).as_deref(), Some("New"));
+        assert!(!path.with_extension("tmp").exists());
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn unquoted_domain() {
+        let path = temporary_file("unquoted");
+
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "  domain=Beit Zait House  \n").unwrap();
+        assert_eq!(read_domain(&path).as_deref(), Some("Beit Zait House"));
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
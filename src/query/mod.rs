@@ -0,0 +1,150 @@
+
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use super::screen::Screen;
+use super::shutdown::Shutdown;
+
+pub mod protocol;
+
+use protocol::Capabilities;
+
+#[derive(Debug)]
+pub enum QueryError {
+    Timeout,
+    Cancelled,
+    Network(std::io::Error),    // e.g. ICMP port unreachable or the interface is down
+}
+
+pub fn prepare_query(my_name: &str, screen: &Screen) -> Vec<u8> {
+    prepare_panel_query(my_name, screen.xres(), screen.yres(), &[])
+}
+
+// Query with additional keys (e.g. LastCrash)
+pub fn prepare_query_with(my_name: &str, screen: &Screen, extra: &[(&str, String)]) -> Vec<u8> {
+    prepare_panel_query(my_name, screen.xres(), screen.yres(), extra)
+}
+
+// Query of a panel with the given resolution (also used by --simulate, which has no screen)
+pub fn prepare_panel_query(my_name: &str, width: usize, height: usize, extra: &[(&str, String)]) -> Vec<u8> {
+    let mut query: HashMap<&str, String> = IntoIterator::into_iter(
+        [
+            ("Name", String::from(my_name)),
+            ("ScreenWidth", width.to_string()),
+            ("ScreenHeight", height.to_string()),
+            ("FormFactor", String::from("InWallPanel")),
+            ("Caps", Capabilities::SUPPORTED.to_string()),
+        ]
+    ).collect();
+
+    query.extend(extra.iter().cloned());
+
+    get_query_bytes(&query)
+}
+
+async fn do_query_for_hometouch_server(servers_manager_address: &str, query_bytes: &[u8], timeout: Duration) -> Result<String, QueryError> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await.expect("Query socket binding failed");
+    let mut reply_bytes: Vec<u8> = vec![0; 1024];
+
+    socket.send_to(query_bytes, servers_manager_address).await.map_err(QueryError::Network)?;
+
+    let timeout = tokio::time::sleep(timeout);
+    tokio::pin!(timeout);
+
+    // A malformed reply is ignored, the manager may still send a valid one before the timeout
+    loop {
+        tokio::select! {
+            result = socket.recv_from(&mut reply_bytes[..]) => {
+                let (count, _) = result.map_err(QueryError::Network)?;
+
+                match protocol::parse_fields(&reply_bytes[..count]).and_then(|fields| protocol::parse_reply(&fields)) {
+                    Some(reply) => return Ok(reply.server_address),
+                    None => println!("Ignoring malformed reply from server manager {}", servers_manager_address),
+                }
+            },
+            _ = &mut timeout => return Err(QueryError::Timeout)
+        }
+    }
+}
+
+pub async fn query_for_hometouch_server(servers_manager_address: &str, query_bytes: &[u8], shutdown: &Shutdown) -> Result<String, QueryError> {
+    for _ in 0..3 {
+        let result = tokio::select! {
+            result = do_query_for_hometouch_server(servers_manager_address, query_bytes, Duration::from_secs(3)) => result,
+            _ = shutdown.requested() => return Err(QueryError::Cancelled),
+        };
+
+        // Only a lost datagram is worth retrying right away, a network error is reported to the caller
+        match result {
+            Err(QueryError::Timeout) => continue,
+            result => return result,
+        }
+    }
+
+    Err(QueryError::Timeout)
+}
+
+// Tell the manager that the assigned server is behind a slow link (e.g. across the WAN), so the next query
+// can be answered with a better server
+pub async fn report_slow_link(servers_manager_address: &str, my_name: &str, server_address: &str, connect_time: Duration) -> Result<(), QueryError> {
+    let report = IntoIterator::into_iter(
+        [
+            ("Name", String::from(my_name)),
+            ("Event", String::from("SlowLink")),
+            ("Server", String::from(server_address)),
+            ("ConnectMs", connect_time.as_millis().to_string()),
+        ]
+    ).collect();
+
+    let socket = UdpSocket::bind("0.0.0.0:0").await.map_err(QueryError::Network)?;
+
+    socket.send_to(&get_query_bytes(&report), servers_manager_address).await.map_err(QueryError::Network)?;
+    Ok(())
+}
+
+// Periodic health report, so the manager can flag panels that are connected but do not receive frames. Values
+// that are unknown (no frame yet, no running session) are left out
+pub async fn report_heartbeat(servers_manager_address: &str, my_name: &str, seconds_since_last_frame: Option<u64>, session_uptime: Option<u64>, reconnects: u32) -> Result<(), QueryError> {
+    let mut report: HashMap<&str, String> = IntoIterator::into_iter(
+        [
+            ("Name", String::from(my_name)),
+            ("Event", String::from("Heartbeat")),
+            ("Reconnects", reconnects.to_string()),
+        ]
+    ).collect();
+
+    if let Some(seconds_since_last_frame) = seconds_since_last_frame {
+        report.insert("SecondsSinceLastFrame", seconds_since_last_frame.to_string());
+    }
+
+    if let Some(session_uptime) = session_uptime {
+        report.insert("SessionUptime", session_uptime.to_string());
+    }
+
+    let socket = UdpSocket::bind("0.0.0.0:0").await.map_err(QueryError::Network)?;
+
+    socket.send_to(&get_query_bytes(&report), servers_manager_address).await.map_err(QueryError::Network)?;
+    Ok(())
+}
+
+fn get_query_bytes(query: &HashMap<&str, String>) -> Vec<u8> {
+    let mut query_bytes = Vec::<u8>::new();
+    query.iter().for_each(|(k, v)| {
+        add_value(k, &mut query_bytes);
+        add_value(v, &mut query_bytes);
+    });
+
+    add_value("", &mut query_bytes);
+    add_value("", &mut query_bytes);
+
+    query_bytes
+}
+
+fn add_value(value: &str, query_bytes: &mut Vec<u8>) {
+    let byte_count = value.bytes().len();
+
+    query_bytes.push((byte_count >> 8) as u8);
+    query_bytes.push((byte_count & 0xFF) as u8);
+
+    query_bytes.extend_from_slice(value.as_bytes());
+}
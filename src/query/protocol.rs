@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::fmt;
+
+// Manager protocol capabilities. The query lists the reply features this client understands in its "Caps" key and
+// the reply lists the ones it uses in its own. Reply keys of a feature are only interpreted if both sides listed
+// it, so the manager protocol can grow without old panels misparsing the new keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    pub const FAILOVER: Capabilities = Capabilities(1 << 0);       // Alternative servers
+    pub const WAIT: Capabilities = Capabilities(1 << 1);           // Retry later directive
+    pub const HMAC: Capabilities = Capabilities(1 << 2);           // Signed replies
+    pub const MULTI_DGRAM: Capabilities = Capabilities(1 << 3);    // Reply split over several datagrams
+
+    // Features implemented by this client
+    pub const SUPPORTED: Capabilities = Capabilities::NONE;
+
+    const NAMES: [(&'static str, Capabilities); 4] = [
+        ("failover", Capabilities::FAILOVER),
+        ("wait", Capabilities::WAIT),
+        ("hmac", Capabilities::HMAC),
+        ("multi-dgram", Capabilities::MULTI_DGRAM),
+    ];
+
+    // Reply keys belonging to each feature
+    const REPLY_KEYS: [(&'static str, Capabilities); 4] = [
+        ("Failover", Capabilities::FAILOVER),
+        ("Wait", Capabilities::WAIT),
+        ("Hmac", Capabilities::HMAC),
+        ("Part", Capabilities::MULTI_DGRAM),
+    ];
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+
+    // Parse a comma separated token list, unknown tokens are logged and ignored
+    pub fn parse(value: &str) -> Capabilities {
+        let mut result = Capabilities::NONE;
+
+        for token in value.split(',').map(str::trim).filter(|token| !token.is_empty()) {
+            match Self::NAMES.iter().find(|(name, _)| *name == token) {
+                Some((_, capability)) => result.0 |= capability.0,
+                None => println!("Ignoring unknown manager capability '{}'", token),
+            }
+        }
+
+        result
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = Self::NAMES.iter().filter(|(_, capability)| self.contains(*capability)).map(|(name, _)| *name).collect();
+
+        write!(f, "{}", names.join(","))
+    }
+}
+
+#[derive(Debug)]
+#[allow(dead_code)]     // capabilities is consulted by the reply features as they are implemented
+pub struct Reply {
+    pub server_address: String,
+    pub capabilities: Capabilities,     // Features both advertised by the query and used by the reply
+}
+
+// Fields of a query or reply: length prefixed (16 bit big endian) name and value strings, ended by an empty name.
+// None if a length runs past the end of the datagram or a string is not UTF-8
+pub fn parse_fields(bytes: &[u8]) -> Option<HashMap<String, String>> {
+    let mut result = HashMap::<String, String>::new();
+    let mut i = 0;
+    let mut get_value = || -> Option<String> {
+        let count = ((*bytes.get(i)? as usize) << 8) + *bytes.get(i + 1)? as usize;
+        let value = String::from_utf8(bytes.get(i + 2..i + 2 + count)?.to_vec()).ok()?;
+
+        i += 2 + count;
+        Some(value)
+    };
+
+    loop {
+        let name = get_value()?;
+
+        if name.is_empty() {
+            break;
+        }
+
+        let value = get_value()?;
+        result.insert(name, value);
+    }
+
+    Some(result)
+}
+
+// None if the server or its port is missing
+pub fn parse_reply(reply: &HashMap<String, String>) -> Option<Reply> {
+    let server = reply.get("Server")?;
+    let port = reply.get("Port")?;
+    let capabilities = Capabilities::SUPPORTED.intersection(reply.get("Caps").map(|caps| Capabilities::parse(caps)).unwrap_or_default());
+
+    for key in reply.keys().filter(|key| !matches!(key.as_str(), "Server" | "Port" | "Caps")) {
+        match Capabilities::REPLY_KEYS.iter().find(|(name, _)| name == key) {
+            Some((_, capability)) if capabilities.contains(*capability) => {},
+            Some(_) => println!("Ignoring manager reply key '{}' (capability not negotiated)", key),
+            None => println!("Ignoring unknown manager reply key '{}'", key),
+        }
+    }
+
+    Some(Reply {
+        server_address: format!("{}:{}", server, port),
+        capabilities,
+    })
+}
@@ -0,0 +1,141 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Mutex, Semaphore};
+use crate::screen::Snapshot;
+
+// Minimal HTTP server (--remote-view-port) answering GET /screen.png with what the panel displays, for remote
+// support. It exposes whatever is on the screen, so it only runs when explicitly enabled. The PNG is encoded from a
+// published snapshot (never under the screen lock), at most once per snapshot, and snapshots are published at
+// REMOTE_VIEW_FPS at most. Each request is served in its own task with timeouts, so a slow or stalled client cannot
+// hold up the others
+
+pub const REMOTE_VIEW_FPS: f64 = 1.0;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REQUEST_SIZE: usize = 4096;
+const MAX_REQUESTS: usize = 4;      // Served at the same time, further connections are closed right away
+
+// Last encoded snapshot, shared by all requests until a new snapshot is published
+type EncodedSnapshot = Arc<Mutex<Option<(Arc<Snapshot>, Arc<Vec<u8>>)>>>;
+
+pub async fn run(port: u16, snapshots: watch::Receiver<Arc<Snapshot>>) {
+    let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Remote view cannot listen on port {}: {} - running without it", port, e);
+            return;
+        }
+    };
+
+    println!("Remote view at http://<panel>:{}/screen.png", port);
+
+    let encoded: EncodedSnapshot = Default::default();
+    let request_slots = Arc::new(Semaphore::new(MAX_REQUESTS));
+
+    loop {
+        let (stream, client_address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Remote view accept failed: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let request_slot = match request_slots.clone().try_acquire_owned() {
+            Ok(request_slot) => request_slot,
+            Err(_) => {
+                println!("Remote view request from {} refused, already serving {} requests", client_address, MAX_REQUESTS);
+                continue;
+            }
+        };
+
+        let snapshots = snapshots.clone();
+        let encoded = encoded.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = serve_request(stream, &snapshots, &encoded).await {
+                println!("Remote view request from {} failed: {}", client_address, e);
+            }
+
+            drop(request_slot);
+        });
+    }
+}
+
+async fn serve_request(mut stream: TcpStream, snapshots: &watch::Receiver<Arc<Snapshot>>, encoded: &EncodedSnapshot) -> Result<(), std::io::Error> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request_line(&mut stream)).await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out"))??;
+
+    let path = match request.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["GET", path, ..] => path.to_string(),
+        _ => return respond(&mut stream, "405 Method Not Allowed", "text/plain", b"Only GET is supported\n").await,
+    };
+
+    if path != "/screen.png" {
+        return respond(&mut stream, "404 Not Found", "text/plain", b"Not found\n").await;
+    }
+
+    let snapshot = snapshots.borrow().clone();
+    let png_image = {
+        // Held while encoding, so requests arriving meanwhile wait for this encoding instead of repeating it
+        let mut encoded = encoded.lock().await;
+
+        match *encoded {
+            Some((ref encoded_snapshot, ref png_image)) if Arc::ptr_eq(encoded_snapshot, &snapshot) => Ok(png_image.clone()),
+            _ => {
+                let to_encode = snapshot.clone();
+
+                match tokio::task::spawn_blocking(move || to_encode.encode_png()).await {
+                    Ok(Ok(png_image)) => {
+                        let png_image = Arc::new(png_image);
+
+                        *encoded = Some((snapshot, png_image.clone()));
+                        Ok(png_image)
+                    },
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+        }
+    };
+
+    match png_image {
+        Ok(png_image) => respond(&mut stream, "200 OK", "image/png", &png_image).await,
+        Err(e) => respond(&mut stream, "500 Internal Server Error", "text/plain", format!("PNG encoding failed: {}\n", e).as_bytes()).await,
+    }
+}
+
+// The request line, the rest of the request (headers) is not needed
+async fn read_request_line(stream: &mut TcpStream) -> Result<String, std::io::Error> {
+    let mut request = Vec::new();
+    let mut buffer: [u8; 512] = [0; 512];
+
+    while !request.contains(&b'\n') {
+        let count = stream.read(&mut buffer[..]).await?;
+
+        if count == 0 || request.len() + count > MAX_REQUEST_SIZE {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "incomplete or oversized request"));
+        }
+
+        request.extend_from_slice(&buffer[..count]);
+    }
+
+    let line_end = request.iter().position(|byte| *byte == b'\n').unwrap();
+
+    Ok(String::from_utf8_lossy(&request[..line_end]).trim().to_string())
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> Result<(), std::io::Error> {
+    let header = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n", status, content_type, body.len());
+    let write = async {
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(body).await?;
+        stream.shutdown().await
+    };
+
+    tokio::time::timeout(WRITE_TIMEOUT, write).await.map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "response timed out"))?
+}
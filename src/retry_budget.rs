@@ -0,0 +1,130 @@
+use std::time::{Duration, Instant};
+
+// Pace of the network attempts of the state machine (locating the manager, querying it, connecting to the server).
+// Each phase has its own retries, but with the manager up and the server down the whole locate, query and connect
+// cycle can repeat several times per second, which adds up across dozens of panels. All phases consult one budget:
+// attempts are at least MIN_INTERVAL apart, and once FAILURES_BEFORE_BACKOFF attempts in a row failed (of any kind)
+// the interval doubles with each further failure up to MAX_INTERVAL. Only a session receiving frames resets it, a
+// manager that answers while the server stays down does not
+
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_INTERVAL: Duration = Duration::from_secs(60);
+const FAILURES_BEFORE_BACKOFF: u32 = 5;
+
+#[derive(Debug, Default)]
+pub struct RetryBudget {
+    last_attempt: Option<Instant>,
+    failures: u32,          // Consecutive failed attempts since frames were last received
+}
+
+impl RetryBudget {
+    // Time to wait at `now` before the next attempt may start
+    pub fn delay(&self, now: Instant) -> Duration {
+        match self.last_attempt {
+            Some(last_attempt) => (last_attempt + self.interval()).saturating_duration_since(now),
+            None => Duration::ZERO,
+        }
+    }
+
+    // The budget is above the minimum interval, so the wait is worth showing
+    pub fn is_backing_off(&self) -> bool {
+        self.interval() > MIN_INTERVAL
+    }
+
+    pub fn attempt(&mut self, now: Instant) {
+        self.last_attempt = Some(now);
+    }
+
+    pub fn failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    // A session received frames, the network is fine again
+    pub fn reset(&mut self) {
+        *self = RetryBudget::default();
+    }
+
+    fn interval(&self) -> Duration {
+        match self.failures.checked_sub(FAILURES_BEFORE_BACKOFF) {
+            Some(excess) => MIN_INTERVAL.saturating_mul(2u32.saturating_pow(excess + 1)).min(MAX_INTERVAL),
+            None => MIN_INTERVAL,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget_after(failures: u32) -> RetryBudget {
+        RetryBudget { last_attempt: None, failures }
+    }
+
+    fn delay_after_attempt(failures: u32) -> Duration {
+        let now = Instant::now();
+        let mut budget = budget_after(failures);
+
+        budget.attempt(now);
+        budget.delay(now)
+    }
+
+    #[test]
+    fn first_attempt_is_immediate() {
+        assert_eq!(RetryBudget::default().delay(Instant::now()), Duration::ZERO);
+        assert_eq!(budget_after(20).delay(Instant::now()), Duration::ZERO);
+    }
+
+    #[test]
+    fn free_failures_keep_the_minimum_interval() {
+        for failures in 0..FAILURES_BEFORE_BACKOFF {
+            assert_eq!(delay_after_attempt(failures), MIN_INTERVAL, "{} failures", failures);
+            assert!(!budget_after(failures).is_backing_off());
+        }
+    }
+
+    #[test]
+    fn interval_doubles_after_the_free_failures() {
+        for (failures, seconds) in [(5, 2), (6, 4), (7, 8), (8, 16), (9, 32)] {
+            assert_eq!(delay_after_attempt(failures), Duration::from_secs(seconds), "{} failures", failures);
+            assert!(budget_after(failures).is_backing_off());
+        }
+    }
+
+    #[test]
+    fn interval_is_capped() {
+        for failures in [10, 11, 100, u32::MAX] {
+            assert_eq!(delay_after_attempt(failures), MAX_INTERVAL, "{} failures", failures);
+        }
+    }
+
+    #[test]
+    fn delay_counts_down_from_the_last_attempt() {
+        let now = Instant::now();
+        let mut budget = budget_after(6);
+
+        budget.attempt(now);
+        assert_eq!(budget.delay(now + Duration::from_secs(1)), Duration::from_secs(3));
+        assert_eq!(budget.delay(now + Duration::from_secs(4)), Duration::ZERO);
+        assert_eq!(budget.delay(now + Duration::from_secs(10)), Duration::ZERO);
+    }
+
+    #[test]
+    fn failures_accumulate_and_reset() {
+        let now = Instant::now();
+        let mut budget = RetryBudget::default();
+
+        for _ in 0..7 {
+            budget.failed();
+        }
+
+        budget.attempt(now);
+        assert_eq!(budget.delay(now), Duration::from_secs(8));
+
+        budget.reset();
+        assert_eq!(budget.delay(now), Duration::ZERO);
+        assert!(!budget.is_backing_off());
+
+        budget.attempt(now);
+        assert_eq!(budget.delay(now), MIN_INTERVAL);
+    }
+}
@@ -0,0 +1,178 @@
+use super::rfb_messages::{
+    ToServerMessage,
+    PointerEventArgs,
+    KeyEventArgs,
+    Point,
+};
+use super::TouchInput;
+use super::latency::LatencyProbe;
+use crate::heartbeat::SessionHealth;
+
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+// Control socket (--control-socket) injecting input into the active session, for scripted testing of the server
+// through the panel. One command per line, each answered with "ok" or "error: <reason>":
+//
+//   tap X Y                 press and release the first button at (X, Y)
+//   press X Y               press the first button at (X, Y)
+//   release X Y             release all buttons at (X, Y)
+//   move X Y                move the pointer to (X, Y), keeping the buttons pressed by press
+//   key KEYSYM down|up      key event, KEYSYM is an X11 keysym in decimal or hex (e.g. 0xff0d for Return)
+//   latency                 measure the input to screen latency, answered with "ok <ms> ms" (see latency.rs)
+//   status                  connection state, e.g. "ok backing off, next attempt in 24s" (with --split, of each
+//                           panel: "ok panel 1: ...; panel 2: ...")
+//
+// Input commands count as panel activity (see --idle-disconnect-secs): they keep the session open, and while the panel
+// sleeps they wake it (the command itself fails with "no active session" until the new session is up).
+//
+// The socket has mode 0600, so only the user running the client (and root) can use it. It is created and restricted
+// inside a private (0700) directory and only then moved to its path, so it is never reachable with wider permissions
+
+pub async fn serve_control_socket(path: String, touch_input: TouchInput, latency_probe: LatencyProbe, panel_health: Vec<SessionHealth>) {
+    let listener = match create_socket(Path::new(&path)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Cannot create control socket {}: {} - running without it", path, e);
+            return;
+        }
+    };
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let touch_input = touch_input.clone();
+                let latency_probe = latency_probe.clone();
+                let panel_health = panel_health.clone();
+
+                tokio::spawn(async move { handle_connection(stream, touch_input, latency_probe, panel_health).await });
+            },
+            Err(e) => {
+                println!("Control socket accept failed: {}", e);
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+fn create_socket(path: &Path) -> Result<UnixListener, std::io::Error> {
+    // A socket left over from a previous run is replaced, anything else at the path is left alone
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "the path exists and is not a socket")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+
+    let private_directory = path.with_extension(format!("{}.tmp", std::process::id()));
+    let private_path = private_directory.join("socket");
+
+    std::fs::DirBuilder::new().mode(0o700).create(&private_directory)?;
+
+    let result = UnixListener::bind(&private_path).and_then(|listener| {
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+
+    let _ = std::fs::remove_file(&private_path);
+    let _ = std::fs::remove_dir(&private_directory);
+    result
+}
+
+async fn handle_connection(stream: UnixStream, touch_input: TouchInput, latency_probe: LatencyProbe, panel_health: Vec<SessionHealth>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut button_mask = 0u8;
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let reply = if line.trim() == "latency" {
+            match latency_probe.measure(&touch_input).await {
+                Ok(latency) => format!("ok {} ms\n", latency.as_millis()),
+                Err(e) => format!("error: {}\n", e),
+            }
+        } else if line.trim() == "status" {
+            format!("ok {}\n", status(&panel_health))
+        } else {
+            match execute(&line, &mut button_mask, &touch_input).await {
+                Ok(_) => String::from("ok\n"),
+                Err(e) => format!("error: {}\n", e),
+            }
+        };
+
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn status(panel_health: &[SessionHealth]) -> String {
+    match panel_health {
+        [health] => health.status(),
+        _ => panel_health.iter().enumerate().map(|(index, health)| format!("panel {}: {}", index + 1, health.status())).collect::<Vec<_>>().join("; "),
+    }
+}
+
+async fn execute(line: &str, button_mask: &mut u8, touch_input: &TouchInput) -> Result<(), String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let location = || -> Result<Point, String> {
+        match words.as_slice() {
+            [_, x, y] => Ok(Point {
+                x: x.parse().map_err(|_| format!("invalid x '{}'", x))?,
+                y: y.parse().map_err(|_| format!("invalid y '{}'", y))?,
+            }),
+            _ => Err(String::from("expected X Y")),
+        }
+    };
+
+    let messages = match words.first().copied() {
+        Some("tap") => {
+            let location = location()?;
+
+            vec![
+                ToServerMessage::PointerEvent(PointerEventArgs{button_mask: *button_mask | 1, location, timestamp: None}),
+                ToServerMessage::PointerEvent(PointerEventArgs{button_mask: *button_mask, location, timestamp: None}),
+            ]
+        },
+        Some("press") => {
+            *button_mask |= 1;
+            vec![ToServerMessage::PointerEvent(PointerEventArgs{button_mask: *button_mask, location: location()?, timestamp: None})]
+        },
+        Some("release") => {
+            *button_mask = 0;
+            vec![ToServerMessage::PointerEvent(PointerEventArgs{button_mask: 0, location: location()?, timestamp: None})]
+        },
+        Some("move") => vec![ToServerMessage::PointerEvent(PointerEventArgs{button_mask: *button_mask, location: location()?, timestamp: None})],
+        Some("key") => {
+            let (key, down) = match words.as_slice() {
+                [_, key, direction] => (*key, *direction),
+                _ => return Err(String::from("expected KEYSYM down|up")),
+            };
+            let key = match key.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => key.parse(),
+            }.map_err(|_| format!("invalid keysym '{}'", key))?;
+            let down = match down {
+                "down" => true,
+                "up" => false,
+                _ => return Err(format!("invalid key direction '{}' (down or up)", down)),
+            };
+
+            vec![ToServerMessage::KeyEvent(KeyEventArgs{down, key})]
+        },
+        Some(command) => return Err(format!("unknown command '{}'", command)),
+        None => return Ok(()),
+    };
+
+    touch_input.touched();
+
+    for message in messages {
+        if !touch_input.inject(message).await {
+            return Err(String::from("no active session"));
+        }
+    }
+
+    Ok(())
+}
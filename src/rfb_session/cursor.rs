@@ -0,0 +1,95 @@
+use tokio::io::AsyncRead;
+use super::{RfbSessionError, RfbSessionErrorKind};
+use super::rfb_messages::{Point, Rect};
+use crate::screen::{DevicePixel, Screen};
+
+// Real cursors are at most 64x64, a larger one is a broken or hostile server
+const MAX_CURSOR_SIZE: usize = 256;
+
+// Cursor image sent by the server. The mask has one bit per pixel (rows padded to a byte), set for opaque pixels
+#[derive(Debug)]
+pub struct CursorShape {
+    hotspot: Point,
+    width: usize,
+    height: usize,
+    pixels: Vec<DevicePixel>,
+    mask: Vec<u8>,
+}
+
+impl CursorShape {
+    fn is_opaque(&self, x: usize, y: usize) -> bool {
+        let mask_byte = self.mask[y * self.width.div_ceil(8) + x / 8];
+
+        mask_byte & (0x80 >> (x % 8)) != 0
+    }
+}
+
+impl<R: AsyncRead + Unpin> super::FromServerThread<'_, R> {
+
+    // Cursor pseudo-encoding: width*height server pixels followed by the mask. An empty rectangle hides the cursor
+    pub async fn decode_cursor_rect(&mut self, rect: &Rect) -> Result<(), RfbSessionError> {
+        let width = rect.size.width as usize;
+        let height = rect.size.height as usize;
+
+        if width > MAX_CURSOR_SIZE || height > MAX_CURSOR_SIZE {
+            return Err(RfbSessionError(RfbSessionErrorKind::ProtocolViolation(format!("Cursor of {}x{} is too large", width, height))));
+        }
+
+        let server_bytes_per_pixel = self.bytes_per_server_pixel()?;
+        let mut server_pixels = self.take_decode_buffer(width * height * server_bytes_per_pixel);
+        let mut mask: Vec<u8> = vec![0; width.div_ceil(8) * height];
+
+        self.read_with_timeout(server_pixels.as_mut_slice()).await?;
+        self.read_with_timeout(mask.as_mut_slice()).await?;
+
+        let pixels: Vec<DevicePixel> = server_pixels.chunks(server_bytes_per_pixel).map(|server_pixel| self.to_device_pixel(server_pixel)).collect();
+
+        self.return_decode_buffer(server_pixels);
+
+        self.cursor = if width == 0 || height == 0 {
+            None
+        } else {
+            Some(CursorShape { hotspot: rect.location, width, height, pixels, mask })
+        };
+
+        Ok(())
+    }
+
+    // Draw the cursor at its current position, remembering the pixels it covers
+    pub fn show_cursor(&mut self) {
+        let cursor = match self.cursor {
+            Some(ref cursor) => cursor,
+            None => return,
+        };
+
+        let left = self.cursor_position.x as isize - cursor.hotspot.x as isize;
+        let top = self.cursor_position.y as isize - cursor.hotspot.y as isize;
+
+        for y in 0..cursor.height {
+            let screen_y = top + y as isize;
+
+            if screen_y < 0 || screen_y as usize >= self.screen.yres() {
+                continue;
+            }
+
+            for x in 0..cursor.width {
+                let screen_x = left + x as isize;
+
+                if screen_x < 0 || screen_x as usize >= self.screen.xres() || !cursor.is_opaque(x, y) {
+                    continue;
+                }
+
+                let offset = screen_y as usize * self.screen.bytes_per_row() + screen_x as usize * Screen::bytes_per_pixel();
+
+                self.cursor_saved.push((offset, self.screen.get_at_offset(offset)));
+                self.screen.set_at_offset(offset, cursor.pixels[y * cursor.width + x]);
+            }
+        }
+    }
+
+    pub fn hide_cursor(&mut self) {
+        for (offset, pixel) in self.cursor_saved.drain(..) {
+            self.screen.set_at_offset(offset, pixel);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use super::handshake::ServerReader;
+use super::rfb_messages::Rect;
+use super::{RfbSessionError, RfbSessionErrorKind};
+use crate::screen::Screen;
+
+// Custom (e.g. HomeTouch specific) encodings, added without touching the core decoder.
+//
+// A custom encoding implements CustomDecoder and is listed in CUSTOM_ENCODINGS with its encoding number. Listed
+// encodings are advertised before the standard ones. The payload layout belongs to the encoding, so the core decoder
+// reads it in steps, asking the decoder how many more bytes it needs given what was read so far (e.g. the pixels of
+// the rectangle, or a length field and then the data it announces). Once nothing more is needed the decoder draws the
+// rectangle into the screen image. An error from the decoder ends the session
+
+pub trait CustomDecoder: Send + Sync {
+    // Bytes still needed after `payload` (empty at the start of the rectangle), 0 once the payload is complete
+    fn bytes_needed(&self, rect: &Rect, payload: &[u8]) -> Result<usize, String>;
+
+    fn decode(&self, rect: &Rect, payload: &[u8], screen: &mut Screen) -> Result<(), String>;
+}
+
+// Custom encodings known to this client, with their encoding numbers
+const CUSTOM_ENCODINGS: &[(i32, &dyn CustomDecoder)] = &[];
+
+// Largest payload accepted for a custom encoding rectangle
+const MAX_CUSTOM_PAYLOAD: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy)]
+pub struct EncodingRegistry {
+    encodings: &'static [(i32, &'static dyn CustomDecoder)],
+}
+
+impl Default for EncodingRegistry {
+    fn default() -> Self {
+        EncodingRegistry { encodings: CUSTOM_ENCODINGS }
+    }
+}
+
+impl std::fmt::Debug for EncodingRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_list().entries(self.encodings.iter().map(|(encoding, _)| encoding)).finish()
+    }
+}
+
+impl EncodingRegistry {
+    pub fn find(&self, encoding: i32) -> Option<&'static dyn CustomDecoder> {
+        self.encodings.iter().find(|(registered, _)| *registered == encoding).map(|(_, decoder)| *decoder)
+    }
+
+    pub fn encoding_numbers(&self) -> impl Iterator<Item = i32> + '_ {
+        self.encodings.iter().map(|(encoding, _)| *encoding)
+    }
+}
+
+pub fn custom_encoding_error(encoding: i32, error: String) -> RfbSessionError {
+    RfbSessionError(RfbSessionErrorKind::ProtocolViolation(format!("Custom encoding {}: {}", encoding, error)))
+}
+
+// Payload of a custom encoding rectangle, read in the steps asked by its decoder
+pub async fn read_payload(server: &mut impl ServerReader, encoding: i32, decoder: &dyn CustomDecoder, rect: &Rect) -> Result<Vec<u8>, RfbSessionError> {
+    let mut payload = Vec::new();
+
+    loop {
+        let needed = decoder.bytes_needed(rect, &payload).map_err(|e| custom_encoding_error(encoding, e))?;
+
+        if needed == 0 {
+            return Ok(payload);
+        }
+
+        if needed > MAX_CUSTOM_PAYLOAD - payload.len() {
+            return Err(custom_encoding_error(encoding, format!("payload of more than {} bytes", MAX_CUSTOM_PAYLOAD)));
+        }
+
+        let start = payload.len();
+
+        payload.resize(start + needed, 0);
+        server.read_from_server(&mut payload[start..]).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::handshake::ServerBytes;
+    use super::super::rfb_messages::{Point, Size};
+
+    // RGB565 pixels of the rectangle
+    struct RawLike;
+
+    impl CustomDecoder for RawLike {
+        fn bytes_needed(&self, rect: &Rect, payload: &[u8]) -> Result<usize, String> {
+            Ok(rect.size.width as usize * rect.size.height as usize * 2 - payload.len())
+        }
+
+        fn decode(&self, _rect: &Rect, _payload: &[u8], _screen: &mut Screen) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    // u32 length and that many bytes of compressed data, which must not be empty
+    struct LengthPrefixed;
+
+    impl CustomDecoder for LengthPrefixed {
+        fn bytes_needed(&self, _rect: &Rect, payload: &[u8]) -> Result<usize, String> {
+            match payload.len() {
+                0 => Ok(4),
+                4 if payload == [0, 0, 0, 0] => Err("empty data".to_string()),
+                4 => Ok(u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize),
+                _ => Ok(0),
+            }
+        }
+
+        fn decode(&self, _rect: &Rect, _payload: &[u8], _screen: &mut Screen) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    const TEST_ENCODINGS: &[(i32, &dyn CustomDecoder)] = &[(0x48540001, &RawLike), (0x48540002, &LengthPrefixed)];
+
+    fn rect(width: u16, height: u16) -> Rect {
+        Rect { location: Point { x: 0, y: 0 }, size: Size { width, height } }
+    }
+
+    #[test]
+    fn registry_finds_and_advertises_its_encodings() {
+        let registry = EncodingRegistry { encodings: TEST_ENCODINGS };
+
+        assert!(registry.find(0x48540002).is_some());
+        assert!(registry.find(5).is_none());
+        assert_eq!(registry.encoding_numbers().collect::<Vec<_>>(), vec![0x48540001, 0x48540002]);
+        assert_eq!(format!("{:?}", registry), "[1213464577, 1213464578]");
+    }
+
+    #[tokio::test]
+    async fn payload_sized_by_the_rectangle() {
+        let bytes: Vec<u8> = (0..20).collect();
+        let mut server = ServerBytes(&bytes);
+
+        assert_eq!(read_payload(&mut server, 0x48540001, &RawLike, &rect(4, 2)).await.unwrap(), (0..16).collect::<Vec<u8>>());
+        assert_eq!(server.0, [16, 17, 18, 19]);
+    }
+
+    #[tokio::test]
+    async fn payload_with_its_own_length() {
+        let bytes = [0, 0, 0, 3, 7, 8, 9, 0xff];
+        let mut server = ServerBytes(&bytes);
+
+        assert_eq!(read_payload(&mut server, 0x48540002, &LengthPrefixed, &rect(100, 100)).await.unwrap(), vec![0, 0, 0, 3, 7, 8, 9]);
+        assert_eq!(server.0, [0xff]);
+    }
+
+    #[tokio::test]
+    async fn decoder_errors_end_the_rectangle() {
+        assert!(read_payload(&mut ServerBytes(&[0, 0, 0, 0]), 0x48540002, &LengthPrefixed, &rect(1, 1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_payload_is_rejected_before_reading() {
+        let result = read_payload(&mut ServerBytes(&[0xff, 0xff, 0xff, 0xff]), 0x48540002, &LengthPrefixed, &rect(1, 1)).await;
+
+        assert!(matches!(result, Err(RfbSessionError(RfbSessionErrorKind::ProtocolViolation(_)))));
+    }
+
+    #[tokio::test]
+    async fn truncated_payload_is_a_closed_connection() {
+        let result = read_payload(&mut ServerBytes(&[1, 2, 3]), 0x48540001, &RawLike, &rect(2, 2)).await;
+
+        assert!(matches!(result, Err(RfbSessionError(RfbSessionErrorKind::SessionClosedByServer))));
+    }
+}
@@ -1,9 +1,11 @@
 
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use super::{
     RfbSessionError,
     RfbSessionErrorKind,
     PixelFormat,
+    ProtocolPhase,
+    CurTextPosition,
 };
 use super::rfb_messages::{
     Rect,
@@ -12,11 +14,28 @@ use super::rfb_messages::{
     RfbEncodingType,
 };
 
-use crate::screen::{DevicePixel, Screen};
+use super::stats::FrameTiming;
+use super::custom_encoding;
+use super::latency;
+use super::tile_geometry;
+use super::handshake;
+use crate::screen::{ColorAdjustment, DevicePixel, Screen};
+use crate::font;
+use std::time::{Duration, Instant};
+
+// A decode buffer above this size is logged as a warning (a full 1920x1080 screen at 32 bits per pixel is 8 MB)
+const DECODE_MEMORY_WARNING: usize = 16 * 1024 * 1024;
+
+// Raw rectangles are read in bands of about this size (at least one row)
+const RAW_BAND_SIZE: usize = 256 * 1024;
+
+// A Raw rectangle larger than this (a 4096x4096 screen at 32 bits per pixel) ends the session
+pub(super) const MAX_RAW_RECT_SIZE: usize = 64 * 1024 * 1024;
 
 #[derive(Debug)]
 struct RectHeader {
-    encoding: RfbEncodingType,
+    encoding: Option<RfbEncodingType>,      // None for pseudo-encodings we do not know
+    raw_encoding: i32,
     rect: Rect,
 }
 
@@ -57,31 +76,219 @@ impl CompactRect for Subrect {
     fn get_wh(&self) -> u8 { self.wh }
 }
 
-impl super::FromServerThread<'_> {
+impl<R: AsyncRead + Unpin> super::FromServerThread<'_, R> {
     
     pub async fn frame_update(&mut self) -> Result<(), RfbSessionError> {
+        let frame_start = Instant::now();
+        let bytes_before = self.stats.bytes_received;
+
+        self.read_time = Duration::ZERO;
+
+        let _padding = self.read_u8().await?;
         let rectangle_count = self.read_u16().await?;
+        let frame_size = self.server_frame_size()?;
+
+        self.validate(rectangle_count as usize <= frame_size.width as usize * frame_size.height as usize,
+            || format!("Frame update with implausible rectangle count {}", rectangle_count))?;
+
+        if self.options.frame_log {
+            println!("Frame update: {} rectangles", rectangle_count);
+        }
+
+        // The cursor and the update highlights are drawn on top of the frame, restore what is under them (in reverse
+        // drawing order) before the server content is updated
+        self.hide_update_highlights();
+        self.hide_cursor();
+        self.start_progress();
 
         for _ in 0..rectangle_count {
             let header = self.read_rect_header().await?;
 
+            let is_pseudo_rect = header.raw_encoding < 0;
+
+            if self.options.frame_log {
+                let encoding = match header.encoding {
+                    Some(encoding) => format!("{:?}", encoding),
+                    None => header.raw_encoding.to_string(),
+                };
+
+                println!("  {} at {},{} size {}x{}", encoding, header.rect.location.x, header.rect.location.y, header.rect.size.width, header.rect.size.height);
+            }
+
+            // Checked before the payload is read: the payload length of Raw and HexTile follows from the geometry, so a
+            // rectangle outside the frame buffer announced by ServerInit means the stream is out of step
+            self.validate(is_pseudo_rect || rect_in_frame(&header.rect, &frame_size),
+                || format!("Rectangle {:?} is outside the server frame buffer {:?}", header.rect, frame_size))?;
+
             match header.encoding {
-                RfbEncodingType::Raw => self.decode_raw_rect(&header).await?,
-                RfbEncodingType::HexTile => self.decode_hextile_rect(&header).await?,
+                Some(RfbEncodingType::Raw) => self.decode_raw_rect(&header).await?,
+                Some(RfbEncodingType::HexTile) => self.decode_hextile_rect(&header).await?,
+                #[cfg(feature = "tight")]
+                Some(RfbEncodingType::Tight) => self.decode_tight_rect(&header.rect).await?,
+                Some(RfbEncodingType::Cursor) => self.decode_cursor_rect(&header.rect).await?,
+                Some(RfbEncodingType::PointerPos) => self.cursor_position = header.rect.location,
+                Some(RfbEncodingType::DesktopName) => self.desktop_name_update().await?,
+                Some(RfbEncodingType::DesktopSize) => self.desktop_size_update(header.rect.size),
+                Some(RfbEncodingType::LastRect) => break,
+                Some(encoding) => return Err(RfbSessionError(RfbSessionErrorKind::InvalidEncoding(encoding as i32))),
+                None if self.options.latency_marker && header.raw_encoding == latency::MARKER_ENCODING => {
+                    self.decode_raw_rect(&header).await?;
+                    self.options.latency_probe.marker_decoded();
+                },
+                None if self.options.custom_encodings.find(header.raw_encoding).is_some() => self.decode_custom_rect(&header).await?,
+                None => {
+                    // Pseudo-encodings without a known payload carry no data, so the rectangle can be skipped
+                    if self.ignored_pseudo_encodings.insert(header.raw_encoding) {
+                        println!("Ignoring unknown pseudo-encoding {}", header.raw_encoding);
+                    }
+                },
+            }
+
+            if !is_pseudo_rect && self.options.highlight_updates {
+                self.updated_rects.push(header.rect);
             }
+
+            // HexTile reports its progress tile by tile
+            if !is_pseudo_rect && !matches!(header.encoding, Some(RfbEncodingType::HexTile)) {
+                self.advance_progress(header.rect.size.width as u64 * header.rect.size.height as u64);
+            }
+        }
+
+        self.end_progress();
+        self.show_cursor();
+        self.show_update_highlights();
+
+        let decode_time = frame_start.elapsed();
+
+        if self.options.stats_overlay {
+            self.draw_stats_overlay();
+        }
+
+        self.draw_cur_text();
+
+        let flush_start = Instant::now();
+        self.present();
+
+        let timing = FrameTiming {
+            read: self.read_time,
+            convert: decode_time.saturating_sub(self.read_time),
+            flush: flush_start.elapsed(),
+        };
+
+        self.stats.frame_completed(self.stats.bytes_received - bytes_before, timing);
+        self.options.health.frame_received();
+
+        if self.stats.first_frame_time.is_none() {
+            let first_frame_time = self.stats.connected_at.elapsed();
+
+            self.stats.first_frame_time = Some(first_frame_time);
+            println!("First frame {} ms after connect", first_frame_time.as_millis());
         }
 
-        self.screen.update();
+        if let Some(threshold) = self.options.slow_frame_threshold {
+            if timing.total() > threshold {
+                println!("Slow frame: {} ms (read {} ms, convert {} ms, flush {} ms) {} rectangles, {} bytes",
+                    timing.total().as_millis(), timing.read.as_millis(), timing.convert.as_millis(), timing.flush.as_millis(),
+                    rectangle_count, self.stats.bytes_received - bytes_before);
+            }
+        }
 
         Ok(())
     }
 
-    pub async fn read(&mut self, buffer: &mut [u8]) ->Result<usize, RfbSessionError> {
+    async fn desktop_name_update(&mut self) -> Result<(), RfbSessionError> {
+        let name = handshake::read_string(self).await?;
+
+        if let Some(ref mut server_info) = self.server_info {
+            println!("Server name changed from '{}' to '{}'", server_info.name, name);
+            server_info.name = name;
+        }
+
+        Ok(())
+    }
+
+    // With frame pacing, frames are presented at a steady cadence. A frame decoded before its slot stays in the
+    // shadow buffer (possibly overwritten by newer frames) and is presented when the slot comes, so bursts of small
+    // updates are coalesced into one flush
+    pub fn present(&mut self) {
+        match self.options.pace_interval {
+            Some(interval) => {
+                let now = Instant::now();
+
+                if now >= self.next_present {
+                    self.screen.update();
+                    self.stats.flushes += 1;
+                    self.present_pending = false;
+                    self.next_present = (self.next_present + interval).max(now);
+                } else {
+                    self.present_pending = true;
+                }
+            },
+            None => {
+                self.screen.update();
+                self.stats.flushes += 1;
+            },
+        }
+    }
+
+    fn draw_stats_overlay(&mut self) {
+        const SCALE: usize = 2;
+        const MARGIN: usize = 4;
+
+        let text = format!("{:.1} FPS {:.0} KB/S {:.1} KB/F {} MS", self.stats.fps(), self.stats.kbps(), self.stats.average_frame_kb(), self.stats.average_decode_time().as_millis());
+        let x = self.screen.xres().saturating_sub(font::text_width(&text, SCALE) + MARGIN);
+
+        self.screen.draw_text(x, MARGIN, &text, SCALE, DevicePixel::from_rgb(255, 255, 0), Some(DevicePixel::from_rgb(0, 0, 0)));
+    }
+
+    // The server status text is shown on a line of its own, on top of the server content
+    pub fn draw_cur_text(&mut self) {
+        const SCALE: usize = 2;
+        const MARGIN: usize = 4;
+
+        let (position, text) = match (self.options.cur_text_position, &self.cur_text) {
+            (Some(position), Some(text)) => (position, text.clone()),
+            _ => return,
+        };
+
+        let height = font::text_height(SCALE) + 2 * MARGIN;
+        let y = match position {
+            CurTextPosition::Top => 0,
+            CurTextPosition::Bottom => self.screen.yres().saturating_sub(height),
+        };
+
+        self.screen.fill_rect(0, y, self.screen.xres(), height.min(self.screen.yres()), DevicePixel::from_rgb(0, 0, 0));
+        self.screen.draw_text(MARGIN, y + MARGIN, &text, SCALE, DevicePixel::from_rgb(255, 255, 255), None);
+    }
+
+    // The deadline applies to each read from the socket rather than to the whole buffer, so a large rectangle
+    // over a slow link is fine as long as data keeps arriving, while a peer that stops sending mid-message is
+    // detected
+    pub async fn read_with_timeout(&mut self, buffer: &mut [u8]) -> Result<usize, RfbSessionError> {
+        let phase = self.phase;
+        let timeout = if phase.is_handshake() { self.options.handshake_timeout } else { self.options.read_timeout };
+
+        self.read_until_stalled(buffer, Some((timeout, phase))).await
+    }
+
+    // Read without a deadline, only used while waiting for the next server message since a static screen
+    // legitimately produces no traffic
+    pub async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, RfbSessionError> {
+        self.read_until_stalled(buffer, None).await
+    }
+
+    async fn read_until_stalled(&mut self, buffer: &mut [u8], stall_timeout: Option<(Duration, ProtocolPhase)>) -> Result<usize, RfbSessionError> {
         let need_to_read = buffer.len();
         let mut actually_read = 0;
 
+        let read_start = Instant::now();
+
         while actually_read < need_to_read {
-            let bytes_read = self.reader.read(&mut buffer[actually_read..]).await?;
+            let read = self.reader.read(&mut buffer[actually_read..]);
+            let bytes_read = match stall_timeout {
+                Some((timeout, phase)) => tokio::time::timeout(timeout, read).await.map_err(|_| RfbSessionError(RfbSessionErrorKind::Timeout { phase }))??,
+                None => read.await?,
+            };
 
             if bytes_read == 0 {
                 return Err(RfbSessionError(RfbSessionErrorKind::SessionClosedByServer));
@@ -90,68 +297,119 @@ impl super::FromServerThread<'_> {
             actually_read += bytes_read;
         }
 
+        self.read_time += read_start.elapsed();
+        self.stats.add_bytes_received(actually_read);
+
         Ok(actually_read)
     }
 
+    // Large rectangles are read a band of rows at a time, so the decode buffer stays bounded whatever size the server
+    // claims. Only the part of the rectangle on the screen is drawn
     async fn decode_raw_rect(&mut self, header: &RectHeader) -> Result<(), RfbSessionError> {
-        let server_bytes_per_pixel = self.bytes_per_server_pixel();
-        let mut server_pixels: Vec<u8>= vec![0; (header.rect.size.height as usize) * (header.rect.size.width as usize) * server_bytes_per_pixel];
-        let mut in_index:usize = 0;
+        let server_bytes_per_pixel = self.bytes_per_server_pixel()?;
+        let row_bytes = (header.rect.size.width as usize) * server_bytes_per_pixel;
+        let rect_bytes = (header.rect.size.height as usize) * row_bytes;
+
+        if rect_bytes > MAX_RAW_RECT_SIZE {
+            return Err(RfbSessionError(RfbSessionErrorKind::ProtocolViolation(format!("Raw rectangle {:?} of {} bytes is too large", header.rect, rect_bytes))));
+        }
+
+        let band_rows = (RAW_BAND_SIZE / row_bytes.max(1)).max(1);
+        let visible = tile_geometry::clamp_to_screen(&header.rect, self.screen.xres(), self.screen.yres());
+        let mut row = 0;
+
+        while row < header.rect.size.height as usize {
+            let rows = band_rows.min(header.rect.size.height as usize - row);
+            let mut server_pixels = self.take_decode_buffer(rows * row_bytes);
 
-        self.read(server_pixels.as_mut_slice()).await?;
+            self.read_with_timeout(server_pixels.as_mut_slice()).await?;
 
-        for row in 0..header.rect.size.height {
-            let mut device_offset = ((row as usize) * self.screen.xres() + (header.rect.location.x as usize)) * Screen::bytes_per_pixel();
+            if let Some(visible) = visible {
+                for band_row in 0..rows.min((visible.size.height as usize).saturating_sub(row)) {
+                    let mut device_offset = (header.rect.location.y as usize + row + band_row) * self.screen.bytes_per_row() +
+                        (header.rect.location.x as usize) * Screen::bytes_per_pixel();
+                    let mut in_index = band_row * row_bytes;
 
-            for _ in 0..header.rect.size.width {
-                let device_pixel = self.to_device_pixel(&server_pixels[in_index..]);
-                in_index += server_bytes_per_pixel;
+                    for _ in 0..visible.size.width {
+                        let device_pixel = self.to_device_pixel(&server_pixels[in_index..]);
+                        in_index += server_bytes_per_pixel;
 
-                self.screen.set_at_offset(device_offset, device_pixel);
-                device_offset += Screen::bytes_per_pixel();
+                        self.screen.set_at_offset(device_offset, device_pixel);
+                        device_offset += Screen::bytes_per_pixel();
+                    }
+                }
             }
+
+            self.return_decode_buffer(server_pixels);
+            row += rows;
         }
+
         Ok(())
     }
 
+    // The decode buffer is reused between rectangles to avoid an allocation per rectangle, which fragments the heap
+    // over days of uptime. A read error ends the session, so a buffer not returned is simply dropped
+    pub fn take_decode_buffer(&mut self, size: usize) -> Vec<u8> {
+        let mut buffer = std::mem::take(&mut self.decode_buffer);
+
+        if buffer.capacity() < size && self.stats.decode_buffer_grown(size) && size > DECODE_MEMORY_WARNING {
+            println!("Warning: decode buffer of {} KB needed ({})", size / 1024, self.stats.decode_memory_summary());
+        }
+
+        buffer.clear();
+        buffer.resize(size, 0);
+        buffer
+    }
+
+    pub fn return_decode_buffer(&mut self, buffer: Vec<u8>) {
+        if buffer.capacity() > self.options.decode_buffer_cap {
+            self.stats.decode_buffer_shrinks += 1;
+        } else {
+            self.decode_buffer = buffer;
+        }
+    }
+
+
+    // Custom encoding rectangle, the registered decoder tells how much of the payload to read and draws it
+    async fn decode_custom_rect(&mut self, header: &RectHeader) -> Result<(), RfbSessionError> {
+        let decoder = match self.options.custom_encodings.find(header.raw_encoding) {
+            Some(decoder) => decoder,
+            None => return Err(RfbSessionError(RfbSessionErrorKind::InvalidEncoding(header.raw_encoding))),
+        };
+        let payload = custom_encoding::read_payload(self, header.raw_encoding, decoder, &header.rect).await?;
+
+        decoder.decode(&header.rect, &payload, self.screen).map_err(|e| custom_encoding::custom_encoding_error(header.raw_encoding, e))
+    }
 
     async fn decode_hextile_rect(&mut self, header: &RectHeader) -> Result<(), RfbSessionError> {
-        let h_tile_count = (header.rect.size.width + 15) >> 4;
-        let v_tile_count = (header.rect.size.height + 15) >> 4;
         let mut hex_tile_decoder = HexTileDecoder::new(self);
 
-        for v_tile in 0..v_tile_count {
-            for h_tile in 0..h_tile_count {
-                let x_offset = h_tile * 16;
-                let y_offset = v_tile * 16;
-                let x = header.rect.location.x + x_offset;
-                let y = header.rect.location.y + y_offset;
-                let tile_rect = Rect {
-                    location: Point{ x, y },
-                    size: Size{
-                        width: if x_offset + 16 > header.rect.size.width { header.rect.size.width - x_offset } else { 16 },
-                        height: if y_offset + 16 > header.rect.size.height { header.rect.size.height - y_offset } else { 16 },
-                    }
-                };
-
-                hex_tile_decoder.process_tile(&tile_rect).await?;
-            }
+        for tile_rect in tile_geometry::tiles(&header.rect) {
+            hex_tile_decoder.process_tile(&tile_rect).await?;
+            hex_tile_decoder.fst.advance_progress(tile_rect.size.width as u64 * tile_rect.size.height as u64);
         }
 
         Ok(())
     }
 
+    pub async fn read_u8(&mut self) -> Result<u8, RfbSessionError> {
+        let mut buffer: [u8; 1] = [0; 1];
+
+        self.read_with_timeout(&mut buffer[..]).await?;
+        Ok(buffer[0])
+    }
+
     async fn read_u16(&mut self) -> Result<u16, RfbSessionError> {
         let mut buffer: [u8; 2] = [0; 2];
 
-        self.read(&mut buffer[..]).await?;
+        self.read_with_timeout(&mut buffer[..]).await?;
         Ok(<u16>::from_be_bytes(buffer))
     }
 
     async fn read_i32(&mut self) -> Result<i32, RfbSessionError> {
         let mut buffer: [u8; 4] = [0; 4];
 
-        self.read(&mut buffer[..]).await?;
+        self.read_with_timeout(&mut buffer[..]).await?;
         Ok(<i32>::from_be_bytes(buffer))
     }
 
@@ -160,10 +418,24 @@ impl super::FromServerThread<'_> {
         let y = self.read_u16().await?;
         let width = self.read_u16().await?;
         let height = self.read_u16().await?;
-        let encoding = self.read_i32().await?;
+        let raw_encoding = self.read_i32().await?;
+
+        // Unknown pseudo-encodings (negative values) are tolerated, but the payload length of an unknown real encoding
+        // cannot be known, so the session cannot continue
+        let encoding = if raw_encoding < 0 {
+            RfbEncodingType::pseudo(raw_encoding)
+        } else {
+            match RfbEncodingType::new(raw_encoding) {
+                Ok(encoding) => Some(encoding),
+                Err(_) if self.options.latency_marker && raw_encoding == latency::MARKER_ENCODING => None,     // See latency.rs
+                Err(_) if self.options.custom_encodings.find(raw_encoding).is_some() => None,     // See custom_encoding.rs
+                Err(e) => return Err(e),
+            }
+        };
 
         Ok(RectHeader{
-            encoding: RfbEncodingType::new(encoding)?,
+            encoding,
+            raw_encoding,
             rect: Rect{
                 location: Point{x, y},
                 size: Size{width, height}
@@ -171,15 +443,47 @@ impl super::FromServerThread<'_> {
         })
     }
 
-    fn get_server_pixel_format(&self) -> &PixelFormat {
-        if let Some(ref server_info) = self.server_info {
-            return &server_info.pixel_format;
+    // In strict mode, fail the session when a structural invariant of the server stream does not hold. This
+    // surfaces stream desynchronization (decoder bugs or buggy servers) instead of rendering garbage
+    fn validate(&self, valid: bool, reason: impl FnOnce() -> String) -> Result<(), RfbSessionError> {
+        if self.options.strict && !valid {
+            Err(RfbSessionError(RfbSessionErrorKind::ProtocolViolation(reason())))
+        } else {
+            Ok(())
+        }
+    }
+
+    // The panel keeps its size, only the bounds the rectangles are checked against change
+    fn desktop_size_update(&mut self, size: Size) {
+        println!("Server framebuffer resized to {}x{}", size.width, size.height);
+
+        if let Some(ref mut server_info) = self.server_info {
+            server_info.frame_buffer_width = size.width;
+            server_info.frame_buffer_height = size.height;
+        }
+    }
+
+    // The frame size and pixel format are known once ServerInit was received, a server sending frames before it is
+    // out of step
+    pub fn server_frame_size(&self) -> Result<Size, RfbSessionError> {
+        match self.server_info {
+            Some(ref server_info) => Ok(Size { width: server_info.frame_buffer_width, height: server_info.frame_buffer_height }),
+            None => Err(no_server_init()),
+        }
+    }
+
+    pub fn get_server_pixel_format(&self) -> Result<&PixelFormat, RfbSessionError> {
+        match self.server_info {
+            Some(ref server_info) => Ok(&server_info.pixel_format),
+            None => Err(no_server_init()),
         }
-        panic!("No server info")
     }
 
     pub fn is_same_pixel_format(&self) -> bool {
-        let pf = self.get_server_pixel_format();
+        let pf = match self.get_server_pixel_format() {
+            Ok(pf) => pf,
+            Err(_) => return false,
+        };
 
         !pf.big_endian &&
         pf.bits_per_pixel == 16 &&
@@ -188,29 +492,42 @@ impl super::FromServerThread<'_> {
         pf.blue_max == 63 && pf.green_shift == 0
     }
 
-    fn bytes_per_server_pixel(&self) -> usize {
-        self.get_server_pixel_format().depth as usize / 8
+    pub fn bytes_per_server_pixel(&self) -> Result<usize, RfbSessionError> {
+        Ok(self.get_server_pixel_format()?.bits_per_pixel as usize / 8)
     }
 
-    fn to_device_pixel(&self, server_pixel: &[u8]) -> DevicePixel {
+    // When the server already sends device pixels but a color adjustment is configured, map them through
+    // a table covering every 16 bit value so the adjustment costs a single lookup per pixel
+    pub fn build_pixel_lookup_table(&self) -> Option<Vec<DevicePixel>> {
+        pixel_lookup_table(self.same_pixel_format, &self.screen.color_adjustment)
+    }
+
+    pub fn to_device_pixel(&self, server_pixel: &[u8]) -> DevicePixel {
         if self.same_pixel_format {
-            DevicePixel::from_value(server_pixel[0] as u16 + ((server_pixel[1] as u16) << 8))
+            let value = server_pixel[0] as u16 + ((server_pixel[1] as u16) << 8);
+
+            match self.pixel_lookup_table {
+                Some(ref table) => table[value as usize],
+                None => DevicePixel::from_value(value),
+            }
         }
         else {
-            let pf = self.get_server_pixel_format();
-
-            if pf.depth == 32 {
-                let pixel_value =  if pf.big_endian {
-                    ((server_pixel[1] as u32) << 16) + ((server_pixel[2] as u32) << 8) + server_pixel[3] as u32
-                } else { 
-                    ((server_pixel[2] as u32) << 16) + ((server_pixel[1] as u32) << 8) + server_pixel[0] as u32
-                };
-
-                let r = ((pixel_value >> pf.red_shift) & (pf.red_max as u32)) as u8;
-                let g = ((pixel_value >> pf.green_shift) & (pf.green_max as u32)) as u8;
-                let b = ((pixel_value >> pf.blue_shift) & (pf.blue_max as u32)) as u8;
-
-                DevicePixel::from_rgb(r, g, b)
+            // The decoders ask for the server pixel size before converting pixels, so there is a pixel format here
+            let pf = match self.server_info {
+                Some(ref server_info) => &server_info.pixel_format,
+                None => return DevicePixel::from_rgb(0, 0, 0),
+            };
+
+            if pf.bits_per_pixel == 32 {
+                // Use all four bytes, which byte holds which channel is defined only by the shifts (ARGB, RGBA, BGRA...)
+                let pixel_bytes = [server_pixel[0], server_pixel[1], server_pixel[2], server_pixel[3]];
+                let pixel_value = if pf.big_endian { u32::from_be_bytes(pixel_bytes) } else { u32::from_le_bytes(pixel_bytes) };
+
+                let r = to_8_bits((pixel_value >> pf.red_shift) & (pf.red_max as u32), pf.red_max);
+                let g = to_8_bits((pixel_value >> pf.green_shift) & (pf.green_max as u32), pf.green_max);
+                let b = to_8_bits((pixel_value >> pf.blue_shift) & (pf.blue_max as u32), pf.blue_max);
+
+                self.screen.color_adjustment.to_device_pixel(r, g, b)
             }
             else {
                 panic!("Server pixel format is not supported {:#?}", pf);
@@ -219,14 +536,47 @@ impl super::FromServerThread<'_> {
     }
 }
 
-struct HexTileDecoder<'a, 'b> {
-    fst: &'a mut super::FromServerThread<'b>,
+fn no_server_init() -> RfbSessionError {
+    RfbSessionError(RfbSessionErrorKind::ProtocolViolation(String::from("Server data before ServerInit")))
+}
+
+// The table mapping each 16 bit device pixel sent by the server to the adjusted device pixel, None if no mapping is needed
+pub fn pixel_lookup_table(same_pixel_format: bool, color_adjustment: &ColorAdjustment) -> Option<Vec<DevicePixel>> {
+    if !same_pixel_format || color_adjustment.is_identity() {
+        return None;
+    }
+
+    Some((0..=u16::MAX).map(|value| {
+        let r = to_8_bits((value >> 11) as u32, 31);
+        let g = to_8_bits(((value >> 5) & 0x3f) as u32, 63);
+        let b = to_8_bits((value & 0x1f) as u32, 31);
+
+        color_adjustment.to_device_pixel(r, g, b)
+    }).collect())
+}
+
+fn rect_in_frame(rect: &Rect, frame_size: &Size) -> bool {
+    rect.location.x as usize + rect.size.width as usize <= frame_size.width as usize &&
+        rect.location.y as usize + rect.size.height as usize <= frame_size.height as usize
+}
+
+// Rescale a channel value in the range 0..=max (as defined by the server pixel format) to 0..=255
+fn to_8_bits(value: u32, max: u16) -> u8 {
+    if max == 255 || max == 0 {
+        value as u8
+    } else {
+        ((value * 255 + (max as u32) / 2) / (max as u32)) as u8
+    }
+}
+
+struct HexTileDecoder<'a, 'b, R: AsyncRead + Unpin> {
+    fst: &'a mut super::FromServerThread<'b, R>,
     foreground: DevicePixel,
     background: DevicePixel,
 }
 
-impl HexTileDecoder<'_, '_> {
-    fn new<'a, 'b>(fst: &'a mut super::FromServerThread<'b>) -> HexTileDecoder<'a, 'b> {
+impl<R: AsyncRead + Unpin> HexTileDecoder<'_, '_, R> {
+    fn new<'a, 'b>(fst: &'a mut super::FromServerThread<'b, R>) -> HexTileDecoder<'a, 'b, R> {
         HexTileDecoder {
             fst,
             foreground: DevicePixel::from_rgb(0, 0, 0),
@@ -235,49 +585,59 @@ impl HexTileDecoder<'_, '_> {
     }
 
     async fn process_tile(&mut self, tile_rect: &Rect) -> Result<(), RfbSessionError> {
-        let server_bytes_per_pixel = self.fst.bytes_per_server_pixel();
+        let server_bytes_per_pixel = self.fst.bytes_per_server_pixel()?;
         let mut tile_encoding: [u8; 1] = [0];
 
-        self.fst.read(&mut tile_encoding[..]).await?;
+        self.fst.read_with_timeout(&mut tile_encoding[..]).await?;
 
         if tile_encoding[0] & 1 != 0 {
-            let mut tile_pixels: Vec<u8> = vec![0; ((tile_rect.size.width * tile_rect.size.height) as usize) * server_bytes_per_pixel];
-            let mut tile_pixels_offset = 0;
+            let mut tile_pixels = self.fst.take_decode_buffer(((tile_rect.size.width * tile_rect.size.height) as usize) * server_bytes_per_pixel);
+
+            self.fst.read_with_timeout(&mut tile_pixels[..]).await?;
 
-            self.fst.read(&mut tile_pixels[..]).await?;
+            // Only the part of the tile on the screen is drawn, the rest of the tile pixels are skipped
+            if let Some(visible) = tile_geometry::clamp_to_screen(tile_rect, self.fst.screen.xres(), self.fst.screen.yres()) {
+                let source_bytes_per_row = tile_rect.size.width as usize * server_bytes_per_pixel;
 
-            for row in 0..tile_rect.size.height {
-                let mut device_offset = (tile_rect.location.y + row) as usize * self.fst.screen.bytes_per_row() +
-                     (tile_rect.location.x as usize) * Screen::bytes_per_pixel();
+                for row in 0..visible.size.height as usize {
+                    let mut device_offset = (tile_rect.location.y as usize + row) * self.fst.screen.bytes_per_row() +
+                         (tile_rect.location.x as usize) * Screen::bytes_per_pixel();
+                    let mut tile_pixels_offset = row * source_bytes_per_row;
 
-                for _ in 0..tile_rect.size.width {
-                    self.fst.screen.set_at_offset(device_offset, self.fst.to_device_pixel(&tile_pixels[tile_pixels_offset..]));
-                    device_offset += Screen::bytes_per_pixel();
-                    tile_pixels_offset += server_bytes_per_pixel;
+                    for _ in 0..visible.size.width {
+                        self.fst.screen.set_at_offset(device_offset, self.fst.to_device_pixel(&tile_pixels[tile_pixels_offset..]));
+                        device_offset += Screen::bytes_per_pixel();
+                        tile_pixels_offset += server_bytes_per_pixel;
+                    }
                 }
             }
+
+            self.fst.return_decode_buffer(tile_pixels);
         } else {
             let mut subrect_count = 0;
 
             if (tile_encoding[0] & 2) != 0 {
                 let mut pixel_buffer: Vec<u8> = vec![0; server_bytes_per_pixel];
 
-                self.fst.read(&mut pixel_buffer[..]).await?;
+                self.fst.read_with_timeout(&mut pixel_buffer[..]).await?;
                 self.background = self.fst.to_device_pixel(&pixel_buffer[..]);
             }
 
             if (tile_encoding[0] & 4) != 0 {
                 let mut pixel_buffer: Vec<u8> = vec![0; server_bytes_per_pixel];
 
-                self.fst.read(&mut pixel_buffer[..]).await?;
+                self.fst.read_with_timeout(&mut pixel_buffer[..]).await?;
                 self.foreground = self.fst.to_device_pixel(&pixel_buffer[..]);
             }
 
             if (tile_encoding[0] & 8) != 0 {
                 let mut subrect_count_buffer: [u8; 1] = [0; 1];
 
-                self.fst.read(&mut subrect_count_buffer[..]).await?;
+                self.fst.read_with_timeout(&mut subrect_count_buffer[..]).await?;
                 subrect_count = <u8>::from_be_bytes(subrect_count_buffer);
+
+                self.fst.validate((subrect_count as u16) <= tile_rect.size.width * tile_rect.size.height,
+                    || format!("Hextile tile {:?} has {} subrects (more than its area)", tile_rect, subrect_count))?;
             }
 
             let subrect_are_colors = (tile_encoding[0] & 16) != 0;
@@ -289,6 +649,7 @@ impl HexTileDecoder<'_, '_> {
                     for _ in 0..subrect_count {
                         let subrect = self.read_color_subrect().await?;
 
+                        self.validate_subrect(tile_rect, &subrect.get_rect())?;
                         self.fill_subrect(tile_rect, &subrect.get_rect(), subrect.pixel);
                     }
                 }
@@ -296,6 +657,7 @@ impl HexTileDecoder<'_, '_> {
                     for _ in 0..subrect_count {
                         let subrect = self.read_subrect().await?;
 
+                        self.validate_subrect(tile_rect, &subrect.get_rect())?;
                         self.fill_subrect(tile_rect, &subrect.get_rect(), self.foreground);
                     }
                 }
@@ -305,26 +667,34 @@ impl HexTileDecoder<'_, '_> {
         Ok(())
     }
 
-    fn fill_subrect(&mut self, tile_rect: &Rect, subrect: &Rect, pixel: DevicePixel) {
-        let bytes_per_pixel = Screen::bytes_per_pixel();
-        let top_offset = (tile_rect.location.y + subrect.location.y) as usize * self.fst.screen.bytes_per_row() + 
-            (tile_rect.location.x + subrect.location.x) as usize * bytes_per_pixel;
-
-        for y in 0..subrect.size.height {
-            let mut offset = top_offset + (y as usize) * self.fst.screen.bytes_per_row();
+    fn validate_subrect(&self, tile_rect: &Rect, subrect: &Rect) -> Result<(), RfbSessionError> {
+        self.fst.validate(subrect.location.x + subrect.size.width <= tile_rect.size.width &&
+                          subrect.location.y + subrect.size.height <= tile_rect.size.height,
+            || format!("Hextile subrect {:?} is outside tile {:?}", subrect, tile_rect))
+    }
 
-            for _ in 0..subrect.size.width { 
-                self.fst.screen.set_at_offset(offset, pixel);
-                offset += bytes_per_pixel;
-            }
+    // Without --strict a malformed subrect is not rejected, so it is clipped to its tile (and the tile to the screen)
+    // rather than spilling into the neighboring tile or past the end of the row
+    fn fill_subrect(&mut self, tile_rect: &Rect, subrect: &Rect, pixel: DevicePixel) {
+        let rect = match tile_geometry::clamp_subrect(tile_rect.size, subrect)
+            .and_then(|subrect| tile_geometry::clamp_to_screen(&tile_geometry::tile_to_screen(tile_rect, &subrect), self.fst.screen.xres(), self.fst.screen.yres())) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let bytes_per_row = self.fst.screen.bytes_per_row();
+        let top_offset = rect.location.y as usize * bytes_per_row + rect.location.x as usize * Screen::bytes_per_pixel();
+
+        // Each row of the subrect is one run of the same pixel
+        for y in 0..rect.size.height as usize {
+            self.fst.screen.fill_row(top_offset + y * bytes_per_row, rect.size.width as usize, pixel);
         }
     }
 
     async fn read_color_subrect(&mut self) -> Result<ColorSubrect, RfbSessionError> {
-        let bytes_per_server_pixel = self.fst.bytes_per_server_pixel();
+        let bytes_per_server_pixel = self.fst.bytes_per_server_pixel()?;
         let mut buffer: Vec<u8> = vec![0; 2 + bytes_per_server_pixel];
 
-        self.fst.read(&mut buffer[..]).await?;
+        self.fst.read_with_timeout(&mut buffer[..]).await?;
 
         Ok(ColorSubrect {
             pixel: self.fst.to_device_pixel(&buffer[0..]),
@@ -336,10 +706,47 @@ impl HexTileDecoder<'_, '_> {
     async fn read_subrect(&mut self) -> Result<Subrect, RfbSessionError> {
         let mut buffer: [u8; 2] = [0; 2];
 
-        self.fst.read(&mut buffer[..]).await?;
+        self.fst.read_with_timeout(&mut buffer[..]).await?;
         Ok(Subrect{
             xy: buffer[0],
             wh: buffer[1],
         })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: u16, y: u16, width: u16, height: u16) -> Rect {
+        Rect { location: Point { x, y }, size: Size { width, height } }
+    }
+
+    #[test]
+    fn rectangles_must_be_inside_the_frame() {
+        let frame_size = Size { width: 800, height: 480 };
+
+        assert!(rect_in_frame(&rect(0, 0, 800, 480), &frame_size));
+        assert!(rect_in_frame(&rect(799, 479, 1, 1), &frame_size));
+        assert!(rect_in_frame(&rect(800, 0, 0, 0), &frame_size));
+        assert!(!rect_in_frame(&rect(1, 0, 800, 480), &frame_size));
+        assert!(!rect_in_frame(&rect(0, 400, 16, 81), &frame_size));
+        assert!(!rect_in_frame(&rect(0xffff, 0, 2, 1), &frame_size));       // Would wrap around in u16
+    }
+
+    #[test]
+    fn channels_are_rescaled_to_8_bits() {
+        assert_eq!((to_8_bits(0, 1), to_8_bits(1, 1)), (0, 255));
+
+        assert_eq!((to_8_bits(0, 31), to_8_bits(31, 31)), (0, 255));
+        assert_eq!((to_8_bits(15, 31), to_8_bits(16, 31)), (123, 132));        // 123.4 and 131.6
+
+        assert_eq!((to_8_bits(0, 63), to_8_bits(63, 63)), (0, 255));
+        assert_eq!(to_8_bits(32, 63), 130);                                     // 129.5
+
+        assert_eq!((to_8_bits(0, 255), to_8_bits(128, 255), to_8_bits(255, 255)), (0, 128, 255));
+
+        // 10 bit channels (depth 30)
+        assert_eq!((to_8_bits(0, 1023), to_8_bits(1023, 1023)), (0, 255));
+        assert_eq!((to_8_bits(511, 1023), to_8_bits(512, 1023)), (127, 128));  // 127.4 and 127.6
+    }
+}
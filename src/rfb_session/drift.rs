@@ -0,0 +1,145 @@
+use std::time::Instant;
+use tokio::io::AsyncRead;
+use crate::screen::Screen;
+
+// Drift check (--drift-check-secs): now and then a full frame is requested instead of an incremental one and compared
+// with the screen it replaces. A decoder bug (e.g. a misplaced HexTile subrect) leaves wrong pixels that incremental
+// updates never touch again, the full frame repairs them and the difference is logged. Changes the server made since
+// the previous update also show up as a difference, so only larger differences are reported
+
+const MIN_DRIFT_PIXELS: usize = 64;
+
+// Pixels differing between a frame and the one it replaced, and the area they are in
+#[derive(Debug, PartialEq)]
+pub struct Drift {
+    pub pixels: usize,
+    pub left: usize,
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// Compare two screen images (rows of bytes_per_row bytes, of which the first row_length are pixels)
+pub fn compare_frames(image: &[u8], reference: &[u8], bytes_per_row: usize, row_length: usize) -> Option<Drift> {
+    let bytes_per_pixel = Screen::bytes_per_pixel();
+    let mut differing_pixels = 0;
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;     // Left, top, right and bottom (inclusive)
+
+    for (y, (row, reference_row)) in image.chunks(bytes_per_row).zip(reference.chunks(bytes_per_row)).enumerate() {
+        if row[..row_length] == reference_row[..row_length] {
+            continue;
+        }
+
+        for (x, (pixel, reference_pixel)) in row[..row_length].chunks(bytes_per_pixel).zip(reference_row[..row_length].chunks(bytes_per_pixel)).enumerate() {
+            if pixel != reference_pixel {
+                differing_pixels += 1;
+                bounds = Some(match bounds {
+                    Some((left, top, right, _)) => (left.min(x), top, right.max(x), y),
+                    None => (x, y, x, y),
+                });
+            }
+        }
+    }
+
+    bounds.map(|(left, top, right, bottom)| Drift { pixels: differing_pixels, left, top, width: right - left + 1, height: bottom - top + 1 })
+}
+
+impl<R: AsyncRead + Unpin> super::FromServerThread<'_, R> {
+
+    // Returns true if the next frame request should be a full one for the drift check
+    pub fn start_drift_check(&mut self) -> bool {
+        let interval = match self.options.drift_check_interval {
+            Some(interval) => interval,
+            None => return false,
+        };
+
+        let now = Instant::now();
+        let due = *self.next_drift_check.get_or_insert(now + interval);
+
+        if now < due {
+            return false;
+        }
+
+        self.next_drift_check = Some(now + interval);
+        self.drift_check_pending = true;
+        true
+    }
+
+    // The full frame is decoded into the drift buffer (starting as a copy of the screen, so anything the server leaves
+    // out stays as it was), which becomes the screen. The screen it replaces is kept in the other buffer to compare
+    // with. The buffer is allocated once and reused by every check
+    pub fn begin_drift_frame(&mut self) {
+        self.drift_buffer.resize(self.screen.image.len(), 0);
+        self.drift_buffer.copy_from_slice(&self.screen.image);
+        std::mem::swap(&mut self.screen.image, &mut self.drift_buffer);
+    }
+
+    // Compare the full frame with the screen it replaced
+    pub fn finish_drift_check(&mut self) {
+        let row_length = self.screen.xres() * Screen::bytes_per_pixel();
+        let drift = compare_frames(&self.screen.image, &self.drift_buffer, self.screen.bytes_per_row(), row_length);
+
+        if let Some(drift) = drift.filter(|drift| drift.pixels >= MIN_DRIFT_PIXELS) {
+            println!("Drift check: full frame differs in {} pixels within {},{} {}x{} (incremental updates drifted or the server content changed), screen repaired",
+                drift.pixels, drift.left, drift.top, drift.width, drift.height);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 8;
+    const BYTES_PER_ROW: usize = 20;    // Rows padded past the pixels, as on a framebuffer with a longer line length
+    const ROW_LENGTH: usize = WIDTH * 2;
+
+    fn image() -> Vec<u8> {
+        (0..6 * BYTES_PER_ROW).map(|index| (index % 251) as u8).collect()
+    }
+
+    fn set_pixel(image: &mut [u8], x: usize, y: usize) {
+        image[y * BYTES_PER_ROW + x * 2] ^= 0xff;
+    }
+
+    #[test]
+    fn same_frames_do_not_drift() {
+        assert_eq!(compare_frames(&image(), &image(), BYTES_PER_ROW, ROW_LENGTH), None);
+    }
+
+    #[test]
+    fn padding_is_ignored() {
+        let mut changed = image();
+
+        changed[BYTES_PER_ROW + ROW_LENGTH] ^= 0xff;
+        assert_eq!(compare_frames(&changed, &image(), BYTES_PER_ROW, ROW_LENGTH), None);
+    }
+
+    #[test]
+    fn single_pixel() {
+        let mut changed = image();
+
+        set_pixel(&mut changed, 3, 2);
+        assert_eq!(compare_frames(&changed, &image(), BYTES_PER_ROW, ROW_LENGTH), Some(Drift { pixels: 1, left: 3, top: 2, width: 1, height: 1 }));
+    }
+
+    #[test]
+    fn area_of_scattered_pixels() {
+        let mut changed = image();
+
+        set_pixel(&mut changed, 5, 1);
+        set_pixel(&mut changed, 2, 3);
+        set_pixel(&mut changed, 7, 3);
+        set_pixel(&mut changed, 4, 4);
+        assert_eq!(compare_frames(&changed, &image(), BYTES_PER_ROW, ROW_LENGTH), Some(Drift { pixels: 4, left: 2, top: 1, width: 6, height: 4 }));
+    }
+
+    #[test]
+    fn both_bytes_of_a_pixel_count_once() {
+        let mut changed = image();
+
+        changed[BYTES_PER_ROW * 5 + 2] ^= 0xff;
+        changed[BYTES_PER_ROW * 5 + 3] ^= 0xff;
+        assert_eq!(compare_frames(&changed, &image(), BYTES_PER_ROW, ROW_LENGTH), Some(Drift { pixels: 1, left: 1, top: 5, width: 1, height: 1 }));
+    }
+}
@@ -0,0 +1,186 @@
+use tokio::io::AsyncRead;
+use super::{FromServerThread, PixelFormat, RfbSessionError, RfbSessionErrorKind, ServerInfo};
+
+// Server messages of the RFB handshake. They are read through a ServerReader, so the byte exchange can be checked
+// without a session (whose screen needs a framebuffer)
+
+const PROTOCOL_VERSION_LENGTH: usize = 12;     // "RFB xxx.yyy\n"
+const MAX_STRING_LENGTH: usize = 4096;         // Failure reasons and desktop names
+
+// Reads exactly the buffer length from the server, the session applies its read timeout and statistics
+pub trait ServerReader {
+    async fn read_from_server(&mut self, buffer: &mut [u8]) -> Result<usize, RfbSessionError>;
+}
+
+impl<R: AsyncRead + Unpin> ServerReader for FromServerThread<'_, R> {
+    async fn read_from_server(&mut self, buffer: &mut [u8]) -> Result<usize, RfbSessionError> {
+        self.read_with_timeout(buffer).await
+    }
+}
+
+pub async fn read_protocol_version(server: &mut impl ServerReader) -> Result<String, RfbSessionError> {
+    let mut protocol_version = [0; PROTOCOL_VERSION_LENGTH];
+
+    if server.read_from_server(&mut protocol_version).await? != PROTOCOL_VERSION_LENGTH {
+        return Err(RfbSessionError(RfbSessionErrorKind::ServerProtocolVersion));
+    }
+
+    Ok(String::from_utf8_lossy(&protocol_version).trim_end().to_string())
+}
+
+// Security types offered by the server. An empty list is followed by the reason the connection is refused
+pub async fn read_security_types(server: &mut impl ServerReader) -> Result<Vec<u8>, RfbSessionError> {
+    let mut count = [0; 1];
+
+    server.read_from_server(&mut count).await?;
+
+    if count[0] == 0 {
+        return Err(RfbSessionError(RfbSessionErrorKind::ServerError(read_string(server).await?)));
+    }
+
+    let mut security_types = vec![0; count[0] as usize];
+    server.read_from_server(&mut security_types).await?;
+
+    Ok(security_types)
+}
+
+// SecurityResult, any value other than 0 is a failure followed by its reason
+pub async fn read_security_result(server: &mut impl ServerReader) -> Result<(), RfbSessionError> {
+    let mut result = [0; 4];
+
+    server.read_from_server(&mut result).await?;
+
+    if u32::from_be_bytes(result) != 0 {
+        return Err(RfbSessionError(RfbSessionErrorKind::ServerError(read_string(server).await?)));
+    }
+
+    Ok(())
+}
+
+// ServerInit: framebuffer size, pixel format and desktop name
+pub async fn read_server_init(server: &mut impl ServerReader) -> Result<ServerInfo, RfbSessionError> {
+    let mut buffer = [0; 2 + 2 + 16];
+
+    server.read_from_server(&mut buffer).await?;
+
+    Ok(ServerInfo {
+        frame_buffer_width: u16::from_be_bytes([buffer[0], buffer[1]]),
+        frame_buffer_height: u16::from_be_bytes([buffer[2], buffer[3]]),
+        pixel_format: PixelFormat::decode(&buffer[4..20]),
+        name: read_string(server).await?,
+    })
+}
+
+// String sent as a u32 length and the text. The length comes from the server, so it is checked before allocating
+pub async fn read_string(server: &mut impl ServerReader) -> Result<String, RfbSessionError> {
+    let mut length = [0; 4];
+
+    server.read_from_server(&mut length).await?;
+
+    let length = u32::from_be_bytes(length) as usize;
+
+    if length > MAX_STRING_LENGTH {
+        return Err(RfbSessionError(RfbSessionErrorKind::ProtocolViolation(format!("Server string of {} bytes is too long", length))));
+    }
+
+    let mut text = vec![0; length];
+    server.read_from_server(&mut text).await?;
+
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+// Server data for tests, failing like a closed connection once the bytes run out
+#[cfg(test)]
+pub struct ServerBytes<'a>(pub &'a [u8]);
+
+#[cfg(test)]
+impl ServerReader for ServerBytes<'_> {
+    async fn read_from_server(&mut self, buffer: &mut [u8]) -> Result<usize, RfbSessionError> {
+        if self.0.len() < buffer.len() {
+            return Err(RfbSessionError(RfbSessionErrorKind::SessionClosedByServer));
+        }
+
+        let (data, rest) = self.0.split_at(buffer.len());
+
+        buffer.copy_from_slice(data);
+        self.0 = rest;
+        Ok(buffer.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string_bytes(text: &str) -> Vec<u8> {
+        [&(text.len() as u32).to_be_bytes()[..], text.as_bytes()].concat()
+    }
+
+    fn server_error(result: Result<impl std::fmt::Debug, RfbSessionError>) -> String {
+        match result {
+            Err(RfbSessionError(RfbSessionErrorKind::ServerError(reason))) => reason,
+            other => panic!("Expected a server error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_exchange() {
+        let pixel_format = [16, 16, 0, 1, 0, 31, 0, 63, 0, 31, 11, 5, 0, 0, 0, 0];
+        let bytes = [
+            &b"RFB 003.008\n"[..],
+            &[2, 2, 1],                     // VNC authentication and None offered
+            &[0, 0, 0, 0],                  // Security accepted
+            &[0x03, 0x20, 0x01, 0xe0],      // 800x480
+            &pixel_format,
+            &string_bytes("Living room"),
+        ].concat();
+        let mut server = ServerBytes(&bytes);
+
+        assert_eq!(read_protocol_version(&mut server).await.unwrap(), "RFB 003.008");
+        assert_eq!(read_security_types(&mut server).await.unwrap(), vec![2, 1]);
+        read_security_result(&mut server).await.unwrap();
+
+        let server_info = read_server_init(&mut server).await.unwrap();
+
+        assert_eq!((server_info.frame_buffer_width, server_info.frame_buffer_height), (800, 480));
+        assert_eq!(server_info.pixel_format, PixelFormat::decode(&pixel_format));
+        assert_eq!(server_info.name, "Living room");
+        assert!(server.0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refused_connection_carries_the_reason() {
+        let bytes = [&[0][..], &string_bytes("panel not authorized")].concat();
+
+        assert_eq!(server_error(read_security_types(&mut ServerBytes(&bytes)).await), "panel not authorized");
+    }
+
+    #[tokio::test]
+    async fn failed_security_result_carries_the_reason() {
+        let bytes = [&[0, 0, 0, 1][..], &string_bytes("Authentication failed")].concat();
+
+        assert_eq!(server_error(read_security_result(&mut ServerBytes(&bytes)).await), "Authentication failed");
+    }
+
+    #[tokio::test]
+    async fn strings_from_server() {
+        assert_eq!(read_string(&mut ServerBytes(&string_bytes(""))).await.unwrap(), "");
+        assert_eq!(read_string(&mut ServerBytes(&string_bytes("Kitchen ☕"))).await.unwrap(), "Kitchen ☕");
+        assert_eq!(read_string(&mut ServerBytes(&[0, 0, 0, 2, 0xc3, 0x28])).await.unwrap(), "\u{fffd}(");
+    }
+
+    #[tokio::test]
+    async fn hostile_string_length_is_rejected() {
+        // Was a panic (negative length) or a huge allocation before
+        assert!(read_string(&mut ServerBytes(&[0xff, 0xff, 0xff, 0xff])).await.is_err());
+        assert!(read_string(&mut ServerBytes(&string_bytes(&"x".repeat(MAX_STRING_LENGTH + 1)))).await.is_err());
+        assert!(read_string(&mut ServerBytes(&string_bytes(&"x".repeat(MAX_STRING_LENGTH)))).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn truncated_handshake_is_a_closed_connection() {
+        let mut server = ServerBytes(&[3, 1]);
+
+        assert!(matches!(read_security_types(&mut server).await, Err(RfbSessionError(RfbSessionErrorKind::SessionClosedByServer))));
+    }
+}
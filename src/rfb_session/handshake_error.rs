@@ -0,0 +1,109 @@
+use super::{RfbSessionError, RfbSessionErrorKind};
+
+// Reasons sent by servers refusing the panel itself. Retrying these every few seconds only fills the server logs
+const DEFAULT_PERMANENT_REASONS: &[&str] = &[
+    "not authorized",
+    "unauthorized",
+    "not allowed",
+    "access denied",
+    "authentication failed",
+    "blacklisted",
+    "banned",
+];
+
+// A failed security handshake: permanent when the server refused this panel (retried only after a long pause), transient
+// otherwise (e.g. the server is still starting)
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeError {
+    Permanent(String),
+    Transient(String),
+}
+
+// Classifies the reason strings of handshake failures by case insensitive substrings. Reasons matching none of them
+// are transient
+#[derive(Debug, Clone)]
+pub struct HandshakeErrorClassifier {
+    permanent_reasons: Vec<String>,
+}
+
+impl Default for HandshakeErrorClassifier {
+    fn default() -> Self {
+        HandshakeErrorClassifier { permanent_reasons: DEFAULT_PERMANENT_REASONS.iter().map(|reason| reason.to_string()).collect() }
+    }
+}
+
+impl HandshakeErrorClassifier {
+    // Add reasons (comma separated) to the built in ones
+    pub fn with_permanent_reasons(mut self, reasons: &str) -> Self {
+        self.permanent_reasons.extend(reasons.split(',').map(|reason| reason.trim().to_lowercase()).filter(|reason| !reason.is_empty()));
+        self
+    }
+
+    // None for errors not coming from the server's answer to the handshake (network errors, timeouts...)
+    pub fn classify(&self, error: &RfbSessionError) -> Option<HandshakeError> {
+        match error.kind() {
+            RfbSessionErrorKind::ServerError(reason) => {
+                let lower_case_reason = reason.to_lowercase();
+
+                if self.permanent_reasons.iter().any(|permanent_reason| lower_case_reason.contains(permanent_reason.as_str())) {
+                    Some(HandshakeError::Permanent(reason.clone()))
+                } else {
+                    Some(HandshakeError::Transient(reason.clone()))
+                }
+            },
+            // Nothing will change until the server configuration does
+            RfbSessionErrorKind::NoSupportedSecurity(offered) => Some(HandshakeError::Permanent(format!("No supported security type (server offers {:?})", offered))),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_error(reason: &str) -> RfbSessionError {
+        RfbSessionError(RfbSessionErrorKind::ServerError(reason.to_string()))
+    }
+
+    #[test]
+    fn known_reasons_are_permanent() {
+        let classifier = HandshakeErrorClassifier::default();
+
+        assert_eq!(classifier.classify(&server_error("Panel Not Authorized")), Some(HandshakeError::Permanent("Panel Not Authorized".to_string())));
+        assert_eq!(classifier.classify(&server_error("ACCESS DENIED by policy")), Some(HandshakeError::Permanent("ACCESS DENIED by policy".to_string())));
+    }
+
+    #[test]
+    fn other_reasons_are_transient() {
+        let classifier = HandshakeErrorClassifier::default();
+
+        assert_eq!(classifier.classify(&server_error("Too many connections")), Some(HandshakeError::Transient("Too many connections".to_string())));
+        assert_eq!(classifier.classify(&server_error("")), Some(HandshakeError::Transient(String::new())));
+    }
+
+    #[test]
+    fn configured_reasons_are_added() {
+        let classifier = HandshakeErrorClassifier::default().with_permanent_reasons(" Panel Retired , ,license expired");
+
+        assert_eq!(classifier.classify(&server_error("this panel retired yesterday")), Some(HandshakeError::Permanent("this panel retired yesterday".to_string())));
+        assert_eq!(classifier.classify(&server_error("License expired")), Some(HandshakeError::Permanent("License expired".to_string())));
+        assert_eq!(classifier.classify(&server_error("not allowed")), Some(HandshakeError::Permanent("not allowed".to_string())));
+        // The empty entry must not make every reason permanent
+        assert_eq!(classifier.classify(&server_error("Server busy")), Some(HandshakeError::Transient("Server busy".to_string())));
+    }
+
+    #[test]
+    fn no_supported_security_is_permanent() {
+        let error = RfbSessionError(RfbSessionErrorKind::NoSupportedSecurity(vec![5, 16]));
+
+        assert!(matches!(HandshakeErrorClassifier::default().classify(&error), Some(HandshakeError::Permanent(_))));
+    }
+
+    #[test]
+    fn other_errors_are_not_handshake_errors() {
+        let error = RfbSessionError(RfbSessionErrorKind::ProtocolViolation("bad rect".to_string()));
+
+        assert_eq!(HandshakeErrorClassifier::default().classify(&error), None);
+    }
+}
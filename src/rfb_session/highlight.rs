@@ -0,0 +1,51 @@
+use tokio::io::AsyncRead;
+use crate::screen::{DevicePixel, Screen};
+
+// Update highlighting (--highlight-updates): a border is drawn around each rectangle of a frame update and removed
+// when the next frame update arrives, showing which regions the server actually sends
+
+const HIGHLIGHT_COLOR: (u8, u8, u8) = (255, 0, 255);
+
+impl<R: AsyncRead + Unpin> super::FromServerThread<'_, R> {
+
+    // Draw a border around each rectangle updated by this frame, remembering the pixels it covers
+    pub fn show_update_highlights(&mut self) {
+        let color = DevicePixel::from_rgb(HIGHLIGHT_COLOR.0, HIGHLIGHT_COLOR.1, HIGHLIGHT_COLOR.2);
+        let rects = std::mem::take(&mut self.updated_rects);
+
+        for rect in rects.iter() {
+            let left = rect.location.x as usize;
+            let top = rect.location.y as usize;
+            let right = (left + rect.size.width as usize).min(self.screen.xres());
+            let bottom = (top + rect.size.height as usize).min(self.screen.yres());
+
+            if left >= right || top >= bottom {
+                continue;
+            }
+
+            for x in left..right {
+                self.highlight_pixel(x, top, color);
+                self.highlight_pixel(x, bottom - 1, color);
+            }
+
+            for y in top..bottom {
+                self.highlight_pixel(left, y, color);
+                self.highlight_pixel(right - 1, y, color);
+            }
+        }
+    }
+
+    // Restore in reverse order, so pixels shared by overlapping borders get their original value
+    pub fn hide_update_highlights(&mut self) {
+        while let Some((offset, pixel)) = self.highlight_saved.pop() {
+            self.screen.set_at_offset(offset, pixel);
+        }
+    }
+
+    fn highlight_pixel(&mut self, x: usize, y: usize, color: DevicePixel) {
+        let offset = y * self.screen.bytes_per_row() + x * Screen::bytes_per_pixel();
+
+        self.highlight_saved.push((offset, self.screen.get_at_offset(offset)));
+        self.screen.set_at_offset(offset, color);
+    }
+}
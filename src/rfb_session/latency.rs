@@ -0,0 +1,95 @@
+use super::rfb_messages::{
+    ToServerMessage,
+    PointerEventArgs,
+    Point,
+};
+use super::TouchInput;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+// Input to screen latency probe. A pointer event is sent to a reserved coordinate outside any real screen, which a
+// HomeTouch server configured for it echoes by sending a 1x1 marker rectangle in MARKER_ENCODING (a Raw pixel under a
+// reserved encoding number, so ordinary screen content is never taken for the marker). The time from sending the
+// event to decoding the marker rectangle covers the network, the server and the decoding (not the display scan-out).
+// Servers without the echo never send the marker, so the probe times out as unsupported
+
+const PROBE_LOCATION: Point = Point { x: 0xffff, y: 0xffff };
+pub const MARKER_ENCODING: i32 = 0x48544c4d;        // "HTLM"
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub enum ProbeError {
+    NoSession,
+    Unsupported,        // No echo within PROBE_TIMEOUT
+}
+
+impl std::fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProbeError::NoSession => write!(f, "no active session"),
+            ProbeError::Unsupported => write!(f, "unsupported (no echo within {} ms)", PROBE_TIMEOUT.as_millis()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LatencyProbe {
+    sent_at: Arc<Mutex<Option<Instant>>>,
+    result: Arc<watch::Sender<Option<Duration>>>,
+}
+
+impl Default for LatencyProbe {
+    fn default() -> LatencyProbe {
+        LatencyProbe {
+            sent_at: Arc::new(Mutex::new(None)),
+            result: Arc::new(watch::channel(None).0),
+        }
+    }
+}
+
+impl LatencyProbe {
+    pub async fn measure(&self, touch_input: &TouchInput) -> Result<Duration, ProbeError> {
+        let mut result = self.result.subscribe();
+
+        result.borrow_and_update();
+        *self.sent_at.lock().unwrap() = Some(Instant::now());
+
+        if !touch_input.inject(ToServerMessage::PointerEvent(PointerEventArgs{button_mask: 0, location: PROBE_LOCATION, timestamp: None})).await {
+            *self.sent_at.lock().unwrap() = None;
+            return Err(ProbeError::NoSession);
+        }
+
+        // The probe moves the server pointer (at least on servers without the echo), put it back where the panel left it
+        touch_input.inject(ToServerMessage::PointerEvent(PointerEventArgs{button_mask: 0, location: touch_input.last_location(), timestamp: None})).await;
+
+        match tokio::time::timeout(PROBE_TIMEOUT, result.changed()).await {
+            Ok(Ok(_)) => (*result.borrow()).ok_or(ProbeError::Unsupported),
+            _ => {
+                *self.sent_at.lock().unwrap() = None;
+                Err(ProbeError::Unsupported)
+            }
+        }
+    }
+
+    // Decoder hook, called once a marker rectangle is decoded
+    pub fn marker_decoded(&self) {
+        if let Some(sent_at) = self.sent_at.lock().unwrap().take() {
+            self.result.send_replace(Some(sent_at.elapsed()));
+        }
+    }
+}
+
+// Periodic probe (--latency-probe-secs), logging the result. Quiet while there is no session
+pub async fn run_latency_probe(probe: LatencyProbe, touch_input: TouchInput, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        match probe.measure(&touch_input).await {
+            Ok(latency) => println!("Latency probe: {} ms", latency.as_millis()),
+            Err(ProbeError::NoSession) => {},
+            Err(e) => println!("Latency probe: {}", e),
+        }
+    }
+}
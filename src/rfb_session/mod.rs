@@ -1,16 +1,19 @@
 use std::any::Any;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio::net::tcp::{
     OwnedReadHalf,
     OwnedWriteHalf,
 };
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncWriteExt};
 
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::sync::Arc;
+use std::os::unix::io::AsRawFd;
 use tokio::sync::{
     Mutex,
+    watch,
     mpsc::{
         channel,
         Sender,
@@ -21,24 +24,46 @@ use tokio::sync::{
 
 mod rfb_messages;
 mod touch;
+mod control;
+mod latency;
+mod stats;
+mod cursor;
+mod highlight;
+mod drift;
+mod tile_geometry;
+mod pacing;
+mod handshake;
+mod handshake_error;
+mod verify;
+mod progress;
+mod custom_encoding;
+#[cfg(feature = "tight")]
+mod tight;
+
+pub use touch::{TouchInput, TouchOptions, TouchProtocol, INPUT_DEVICE_NAME};
+pub use control::serve_control_socket;
+pub use latency::{LatencyProbe, run_latency_probe};
+pub use custom_encoding::EncodingRegistry;
+pub use verify::{verify_server, ServerReport};
+pub use handshake_error::{HandshakeError, HandshakeErrorClassifier};
+
+pub use rfb_messages::{Point, PointerEventArgs, Rect, Size, ToServerMessage};
 
 use rfb_messages::{
-    ToServerMessage,
     RfbSecurityType,
     RfbEncodingType,
     FrameUpdateRequestArgs,
     FromServerCommands,
-    Point,
-    Rect,
-    Size,
 };
 
 mod decode;
 
-use super::screen::Screen;
+use super::screen::{ColorAdjustment, DevicePixel, Screen};
+use super::night::NightMode;
+use super::heartbeat::SessionHealth;
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PixelFormat {
     bits_per_pixel: u8,
     depth: u8,
@@ -71,6 +96,111 @@ impl PixelFormat {
     }
 }
 
+// Steps of the server facing protocol, run in order by FromServerThread::run_protocol. All but FrameData are part of
+// the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolPhase {
+    VersionExchange,
+    SecurityNegotiation,
+    SecurityResult,
+    ServerInit,
+    Init,
+    FrameData,
+}
+
+impl ProtocolPhase {
+    pub fn is_handshake(&self) -> bool {
+        *self != ProtocolPhase::FrameData
+    }
+}
+
+// Line of the screen showing the status text sent by the server (SetCurText)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CurTextPosition {
+    Top,
+    Bottom,
+}
+
+impl CurTextPosition {
+    // None for "off"
+    pub fn parse(value: &str) -> Result<Option<CurTextPosition>, String> {
+        match value {
+            "top" => Ok(Some(CurTextPosition::Top)),
+            "bottom" => Ok(Some(CurTextPosition::Bottom)),
+            "off" => Ok(None),
+            _ => Err(format!("Invalid server text position '{}' (top, bottom or off)", value)),
+        }
+    }
+}
+
+const MAX_CUR_TEXT_LENGTH: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    pub strict: bool,           // Validate the structure of the server stream and terminate the session on violations
+    pub lenient: bool,          // Skip known but unimplemented server messages instead of terminating the session
+    pub prefer_raw: bool,       // Advertise Raw before HexTile (faster end-to-end when the link is faster than HexTile decoding)
+    pub handshake_timeout: Duration,    // Deadline for each read until the session is initialized
+    pub read_timeout: Duration,         // Deadline for each read within a server message once frames are flowing
+    pub write_timeout: Duration,        // Deadline for writing each message to the server
+    pub dead_link_timeout: Option<Duration>,    // A connection that stops acknowledging (e.g. half-open after an AP reboot) is dropped after about this long
+    pub night_mode: Option<Arc<NightMode>>,
+    pub stats_overlay: bool,                        // Draw frame rate, bandwidth and decode time on the screen
+    pub slow_frame_threshold: Option<Duration>,     // Log timing breakdown for frames slower than this
+    pub pace_interval: Option<Duration>,            // Present decoded frames at this fixed interval (smoother animations)
+    pub jpeg_quality: Option<u8>,                   // Advertise Tight with this JPEG quality (0-9) for photo-like content
+    pub compression: Option<u8>,                    // Advertise Tight with this zlib compression level (0-9)
+    pub show_cursor: bool,                          // Ask the server for its cursor shape and position and draw it
+    pub touch_input: TouchInput,       // Delivers touches to the active session
+    pub health: SessionHealth,         // Time of the last frame, reported to the manager by the heartbeat
+    pub latency_probe: LatencyProbe,   // Told when the latency probe marker is decoded
+    pub latency_marker: bool,          // A latency probe is enabled, advertise the probe marker encoding
+    pub custom_encodings: EncodingRegistry,     // Custom encodings advertised to the server and their decoders
+    pub view_only: bool,               // Display only, input is never sent to the server
+    pub highlight_updates: bool,       // Outline the rectangles of each frame update until the next one (diagnostic)
+    pub frame_log: bool,               // Log the encoding and geometry of the rectangles of each frame update (diagnostic)
+    pub decode_buffer_cap: usize,      // Larger decode buffers (e.g. for a rare full screen Raw rectangle) are released after use
+    pub cur_text_position: Option<CurTextPosition>,     // Where the server status text is shown, None to not show it
+    pub idle_disconnect: Option<Duration>,     // Close the session when the panel was not touched for this long
+    pub drift_check_interval: Option<Duration>,    // Compare a full frame with the screen this often (see drift.rs)
+}
+
+// Negotiation results remembered per server address, so reconnecting to a server with the same pixel format
+// does not rebuild the pixel conversion table or the encoding list. The table folds in the color adjustment, so it
+// is rebuilt when that changes
+#[derive(Debug, Clone)]
+struct CachedNegotiation {
+    pixel_format: PixelFormat,
+    color_adjustment: ColorAdjustment,
+    same_pixel_format: bool,
+    pixel_lookup_table: Option<Arc<Vec<DevicePixel>>>,
+    encodings: Vec<i32>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NegotiationCache(Arc<std::sync::Mutex<HashMap<String, CachedNegotiation>>>);
+
+impl NegotiationCache {
+    fn get(&self, server_address: &str) -> Option<CachedNegotiation> {
+        self.0.lock().unwrap().get(server_address).cloned()
+    }
+
+    fn insert(&self, server_address: &str, negotiation: CachedNegotiation) {
+        self.0.lock().unwrap().insert(server_address.to_string(), negotiation);
+    }
+
+    // Called before connecting: if the server pixel format is already known, make sure its conversion table is built
+    // for the current color adjustment, so the session can use it as soon as ServerInit arrives
+    pub fn prepare(&self, server_address: &str, color_adjustment: &ColorAdjustment) {
+        if let Some(cached) = self.0.lock().unwrap().get_mut(server_address) {
+            if cached.color_adjustment != *color_adjustment {
+                cached.pixel_lookup_table = decode::pixel_lookup_table(cached.same_pixel_format, color_adjustment).map(Arc::new);
+                cached.color_adjustment = color_adjustment.clone();
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 struct ServerInfo {
@@ -80,34 +210,151 @@ struct ServerInfo {
     name: String,
 }
 
-pub async fn run(connection: TcpStream, screen: Arc<Mutex<Screen>>) -> Result<(), RfbSessionError> {
+pub async fn run(connection: TcpStream, connected_at: Instant, screen: Arc<Mutex<Screen>>, options: SessionOptions, negotiation_cache: NegotiationCache, server_address: String) -> Result<(), RfbSessionError> {
+    // Protocol messages (handshake, frame update requests) and pointer events are queued separately, so a burst of
+    // touch input never delays the request keeping the frames coming. Each queue keeps its own order
     let (output_sender, output_receiver): (Sender<ToServerMessage>, Receiver<ToServerMessage>) = channel(10);
+    let (pointer_sender, pointer_receiver): (Sender<ToServerMessage>, Receiver<ToServerMessage>) = channel(10);
+    // Pointer events go through the pacing task, which restores the spacing of touch events
+    let (paced_pointer_sender, paced_pointer_receiver): (Sender<ToServerMessage>, Receiver<ToServerMessage>) = channel(10);
+
+    if let Some(dead_link_timeout) = options.dead_link_timeout {
+        if let Err(e) = set_keepalive(&connection, dead_link_timeout) {
+            println!("Cannot set TCP keepalive: {}", e);
+        }
+    }
+
     let (input_stream, output_stream) = connection.into_split();
-    let (stop_touch_tx, stop_touch_rx) = oneshot::channel();
     let (stop_ping_tx, stop_ping_rx) = oneshot::channel();
-    let touch_output_sender = output_sender.clone();
+    let (pointer_enabled_tx, pointer_enabled_rx) = watch::channel(false);
     let ping_output_sender = output_sender.clone();
-
-    let from_server_thread = tokio::spawn(async move { from_server_thread(input_stream, output_sender, screen).await });
-    let to_server_thread = tokio::spawn(async move { to_server_thread(output_stream, output_receiver).await });
-    let touch_input_thread = tokio::spawn(async move { touch::run(stop_touch_rx, touch_output_sender).await });
+    // A view-only session never attaches to the input, so no pointer or key event can reach the server
+    let _touch_attachment = (!options.view_only).then(|| options.touch_input.attach(pointer_sender, pointer_enabled_rx));
+
+    let write_timeout = options.write_timeout;
+    let dead_link_timeout = options.dead_link_timeout;
+    let idle = idle_timeout(options.idle_disconnect, options.touch_input.subscribe_activity());
+    let mut from_server_thread = tokio::spawn(async move { from_server_thread(input_stream, connected_at, output_sender, screen, pointer_enabled_tx, options, negotiation_cache, server_address).await });
+    let mut to_server_thread = tokio::spawn(async move { to_server_thread(output_stream, output_receiver, paced_pointer_receiver, write_timeout, dead_link_timeout).await });
+    let pacing_thread = tokio::spawn(pacing::pace_pointer_events(pointer_receiver, paced_pointer_sender));
     let ping_server_thread = tokio::spawn(async move { ping_server_thread(stop_ping_rx, ping_output_sender).await });
-
-    to_server_thread.await?;
-    from_server_thread.await?;
-
-    _ = stop_touch_tx.send(true);
-    touch_input_thread.await?;
+    let _abort_on_drop = AbortOnDrop(vec![from_server_thread.abort_handle(), to_server_thread.abort_handle(), ping_server_thread.abort_handle(), pacing_thread.abort_handle()]);
+
+    // As soon as either side of the connection is done, tear down the other one. A wedged write (or read) must
+    // not keep the session, and with it the reconnect cycle, hanging
+    let session_result = tokio::select! {
+        result = &mut from_server_thread => {
+            to_server_thread.abort();
+            result
+        },
+        result = &mut to_server_thread => {
+            from_server_thread.abort();
+            result
+        },
+        _ = idle => {
+            from_server_thread.abort();
+            to_server_thread.abort();
+            Ok(Err(RfbSessionError(RfbSessionErrorKind::IdleDisconnect)))
+        },
+    };
 
     _ = stop_ping_tx.send(true);
     ping_server_thread.await?;
 
-    Ok(())
+    session_result?
+}
+
+// The best security type offered by the server that this client supports. There is no password option, so VNC
+// authentication is never selected and None is the only usable type
+fn select_security_type(offered: &[u8]) -> Option<RfbSecurityType> {
+    [RfbSecurityType::None].into_iter().find(|security_type| offered.contains(&(*security_type as u8)))
 }
 
-async fn to_server_thread(mut output_stream: OwnedWriteHalf, mut output_receiver: Receiver<ToServerMessage>) {
+// Keepalive probes detect a peer that went away while the connection is idle: the first probe after half the
+// timeout, then 3 probes spread over the other half
+fn set_keepalive(connection: &TcpStream, timeout: Duration) -> std::io::Result<()> {
+    let idle = (timeout / 2).max(Duration::from_secs(1));
+    let keepalive = socket2::TcpKeepalive::new()
+        .with_time(idle)
+        .with_interval((idle / 3).max(Duration::from_secs(1)))
+        .with_retries(3);
+
+    socket2::SockRef::from(connection).set_tcp_keepalive(&keepalive)
+}
+
+// Bytes written to the socket but not yet acknowledged by the peer
+fn unacknowledged_bytes(output_stream: &OwnedWriteHalf) -> Option<i32> {
+    let mut queued: libc::c_int = 0;
+
+    match unsafe { libc::ioctl(output_stream.as_ref().as_raw_fd(), libc::TIOCOUTQ as _, &mut queued) } {
+        0 => Some(queued),
+        _ => None,
+    }
+}
+
+// Completes once the panel was not touched for the idle time since the session started (never without an idle time)
+async fn idle_timeout(idle: Option<Duration>, mut activity: watch::Receiver<Instant>) {
+    let Some(idle) = idle else {
+        return std::future::pending().await;
+    };
+    let session_start = Instant::now();
+
     loop {
-        let m = output_receiver.recv().await.expect("output_receiver.recv");
+        let last_activity = (*activity.borrow_and_update()).max(session_start);
+
+        tokio::select! {
+            _ = tokio::time::sleep_until(tokio::time::Instant::from_std(last_activity + idle)) => return,
+            changed = activity.changed() => if changed.is_err() {
+                return std::future::pending().await;
+            },
+        }
+    }
+}
+
+// Dropping a running session (e.g. to switch to another server) must stop its tasks as well, the server reading
+// task holds the screen lock
+struct AbortOnDrop(Vec<tokio::task::AbortHandle>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.iter().for_each(|task| task.abort());
+    }
+}
+
+// Ends on Terminate, or with an error when writing fails, which tears down the whole session (see run). Protocol
+// messages take precedence over pointer events
+async fn to_server_thread(mut output_stream: OwnedWriteHalf, mut output_receiver: Receiver<ToServerMessage>, mut pointer_receiver: Receiver<ToServerMessage>,
+                          write_timeout: Duration, dead_link_timeout: Option<Duration>) -> Result<(), RfbSessionError> {
+    // Keepalive does not probe while written data is waiting for an acknowledgment, the kernel keeps retransmitting
+    // it for many minutes. So a send queue that does not drain for the dead link timeout ends the session
+    let mut drain_check = tokio::time::interval(Duration::from_secs(1));
+    let mut stalled: Option<(Instant, i32)> = None;
+
+    loop {
+        let m = tokio::select! {
+            biased;
+            m = output_receiver.recv() => match m {
+                Some(m) => m,
+                None => break,
+            },
+            Some(m) = pointer_receiver.recv() => m,
+            _ = drain_check.tick(), if dead_link_timeout.is_some() => {
+                stalled = match (unacknowledged_bytes(&output_stream), stalled) {
+                    (Some(queued), Some((since, previous))) if queued > 0 && queued >= previous => Some((since, queued)),
+                    (Some(queued), _) if queued > 0 => Some((Instant::now(), queued)),
+                    _ => None,
+                };
+
+                if let (Some((since, queued)), Some(dead_link_timeout)) = (stalled, dead_link_timeout) {
+                    if since.elapsed() >= dead_link_timeout {
+                        println!("{} bytes written to the server were not acknowledged for {:?}, connection is dead", queued, since.elapsed());
+                        return Err(RfbSessionError(RfbSessionErrorKind::DeadLink));
+                    }
+                }
+
+                continue;
+            },
+        };
 
         if let ToServerMessage::Terminate = m {
             break;
@@ -115,11 +362,21 @@ async fn to_server_thread(mut output_stream: OwnedWriteHalf, mut output_receiver
 
         let buffer = m.encode();
         
-        if let Err(e) = output_stream.write(&buffer[..]).await {
-            println!("Error {:?} while writing to server", e);
-            break;
+        // A server that stopped reading (e.g. behind a TCP black hole) fills the socket buffer and blocks the write
+        match tokio::time::timeout(write_timeout, output_stream.write_all(&buffer[..])).await {
+            Ok(Ok(_)) => {},
+            Ok(Err(e)) => {
+                println!("Error {:?} while writing to server", e);
+                return Err(e.into());
+            },
+            Err(_) => {
+                println!("Writing to server timed out");
+                return Err(RfbSessionError(RfbSessionErrorKind::WriteTimeout));
+            },
         }
     }
+
+    Ok(())
 }
 
 async fn ping_server_thread(stop_rx: oneshot::Receiver<bool>, output_sender: Sender<ToServerMessage>) {
@@ -134,63 +391,221 @@ async fn ping_server_thread(stop_rx: oneshot::Receiver<bool>, output_sender: Sen
     };
 }
 
-struct FromServerThread<'a> {
-    reader: &'a mut OwnedReadHalf,
+// Generic over the reader so the protocol code can run against any byte stream, not only a TCP socket
+struct FromServerThread<'a, R: AsyncRead + Unpin> {
+    reader: &'a mut R,
     sender: &'a Sender<ToServerMessage>,
     screen: &'a mut Screen,
     server_info: Option<ServerInfo>,
     same_pixel_format: bool,
+    pixel_lookup_table: Option<Arc<Vec<DevicePixel>>>,
+    negotiation_cache: NegotiationCache,
+    server_address: String,
+    pointer_enabled: watch::Sender<bool>,
+    options: SessionOptions,
+    phase: ProtocolPhase,
+    stats: stats::SessionStats,
+    read_time: Duration,        // Time spent waiting for server data (reset at the start of each frame update)
+    next_present: Instant,      // Frame pacing: when the next frame may be presented
+    present_pending: bool,      // Frame pacing: a decoded frame is waiting to be presented
+    ignored_pseudo_encodings: HashSet<i32>,     // Unknown pseudo-encodings already reported
+    cursor: Option<cursor::CursorShape>,        // Cursor image sent by the server (Cursor pseudo-encoding)
+    cursor_position: Point,
+    cursor_saved: Vec<(usize, DevicePixel)>,    // Screen pixels under the drawn cursor
+    updated_rects: Vec<Rect>,                   // Rectangles of the frame being decoded (--highlight-updates)
+    highlight_saved: Vec<(usize, DevicePixel)>, // Screen pixels under the drawn update highlights
+    decode_buffer: Vec<u8>,                     // Reused for the pixel data of Raw rectangles and HexTile raw tiles
+    cur_text: Option<String>,                   // Latest status text sent by the server (SetCurText)
+    server_version: String,                     // Protocol version announced by the server
+    security_types: Vec<u8>,                    // Security types offered by the server
+    first_frame_progress: Option<progress::FrameProgress>,
+    next_drift_check: Option<Instant>,
+    drift_check_pending: bool,                  // The next frame is the full one requested by the drift check
+    drift_buffer: Vec<u8>,                      // The drift check frame is decoded into one buffer, the screen it replaces is kept in the other
+    #[cfg(feature = "tight")]
+    tight: tight::TightState,
 }
 
-async fn from_server_thread(mut input_stream: OwnedReadHalf, output_sender: Sender<ToServerMessage>, screen: Arc<Mutex<Screen>>) {
+async fn from_server_thread(mut input_stream: OwnedReadHalf, connected_at: Instant, output_sender: Sender<ToServerMessage>, screen: Arc<Mutex<Screen>>, pointer_enabled: watch::Sender<bool>, options: SessionOptions,
+                            negotiation_cache: NegotiationCache, server_address: String) -> Result<(), RfbSessionError> {
     let mut screen = screen.as_ref().lock().await;
-    let mut fst = FromServerThread::new(&mut input_stream, &output_sender, &mut screen);
+    let mut fst = FromServerThread::new(&mut input_stream, &output_sender, &mut screen, pointer_enabled, options);
 
-    if let Err(e) = fst.initialize_protocol().await {
-        println!("Protocol initialization failed: {:?}", e);
-    }
+    fst.negotiation_cache = negotiation_cache;
+    fst.server_address = server_address;
+    fst.stats.connected_at = connected_at;
 
-    if let Err(e) = fst.refresh_screen().await {
-        println!("Session terminated {:?}", e);
-    }
+    let result = fst.run_protocol(None).await;
 
-    output_sender.send(ToServerMessage::Terminate).await.unwrap();
+    // The writer may already be gone (write error or timeout), its error is reported by the session
+    let _ = output_sender.send(ToServerMessage::Terminate).await;
+    result
 }
 
-impl FromServerThread<'_> {
+impl<R: AsyncRead + Unpin> FromServerThread<'_, R> {
 
-    fn new<'a>(reader: &'a mut OwnedReadHalf, sender: &'a Sender<ToServerMessage>, screen: &'a mut Screen) -> FromServerThread<'a> {
+    fn new<'a>(reader: &'a mut R, sender: &'a Sender<ToServerMessage>, screen: &'a mut Screen, pointer_enabled: watch::Sender<bool>, options: SessionOptions) -> FromServerThread<'a, R> {
         FromServerThread {
             reader,
             sender,
             screen,
             server_info: None,
             same_pixel_format: false,
+            pixel_lookup_table: None,
+            negotiation_cache: NegotiationCache::default(),
+            server_address: String::new(),
+            pointer_enabled,
+            options,
+            phase: ProtocolPhase::VersionExchange,
+            stats: stats::SessionStats::new(),
+            read_time: Duration::ZERO,
+            next_present: Instant::now(),
+            present_pending: false,
+            ignored_pseudo_encodings: HashSet::new(),
+            cursor: None,
+            cursor_position: Point{x: 0, y: 0},
+            cursor_saved: Vec::new(),
+            updated_rects: Vec::new(),
+            highlight_saved: Vec::new(),
+            decode_buffer: Vec::new(),
+            cur_text: None,
+            server_version: String::new(),
+            security_types: Vec::new(),
+            first_frame_progress: None,
+            next_drift_check: None,
+            drift_check_pending: false,
+            drift_buffer: Vec::new(),
+            #[cfg(feature = "tight")]
+            tight: tight::TightState::default(),
         }
     }
 
-    async fn initialize_protocol(&mut self) -> Result<(), RfbSessionError> {
-        let mut protocol_version: [u8; 12] = [0; 12];
+    // Drive the session through the protocol steps, each step returns the one to continue with. With `stop_before`
+    // the protocol stops once that step is reached (e.g. to check a server without entering the frame loop)
+    async fn run_protocol(&mut self, stop_before: Option<ProtocolPhase>) -> Result<(), RfbSessionError> {
+        loop {
+            if Some(self.phase) == stop_before {
+                return Ok(());
+            }
+
+            let next_phase = match self.phase {
+                ProtocolPhase::VersionExchange => self.version_exchange().await,
+                ProtocolPhase::SecurityNegotiation => self.security_negotiation().await,
+                ProtocolPhase::SecurityResult => self.security_result().await,
+                ProtocolPhase::ServerInit => self.server_init().await,
+                ProtocolPhase::Init => self.init().await,
+                ProtocolPhase::FrameData => return self.refresh_screen().await.inspect_err(|e| println!("Session terminated {:?} ({}, {})", e, self.stats.flush_summary(), self.stats.decode_memory_summary())),
+            };
 
-        let count = self.read(&mut protocol_version).await?;
-        if count != 12 {
-            return Err(RfbSessionError(RfbSessionErrorKind::ServerProtocolVersion))
+            self.phase = next_phase.inspect_err(|e| eprintln!("Protocol initialization failed: {:?}", e))?;
         }
+    }
 
+    async fn version_exchange(&mut self) -> Result<ProtocolPhase, RfbSessionError> {
+        self.server_version = handshake::read_protocol_version(self).await?;
         self.sender.send(ToServerMessage::ProtocolVersion).await?;
+        Ok(ProtocolPhase::SecurityNegotiation)
+    }
+
+    async fn security_negotiation(&mut self) -> Result<ProtocolPhase, RfbSessionError> {
+        self.security_types = handshake::read_security_types(self).await?;
 
-        let _ = self.get_server_supported_security_options().await?;
-        self.sender.send(ToServerMessage::Security(RfbSecurityType::None)).await?;
+        // Selecting a type the server did not offer desyncs the handshake
+        let security_type = select_security_type(&self.security_types)
+            .ok_or_else(|| RfbSessionError(RfbSessionErrorKind::NoSupportedSecurity(self.security_types.clone())))?;
 
-        self.get_security_result().await?;
+        self.sender.send(ToServerMessage::Security(security_type)).await?;
+
+        Ok(ProtocolPhase::SecurityResult)
+    }
+
+    async fn security_result(&mut self) -> Result<ProtocolPhase, RfbSessionError> {
+        handshake::read_security_result(self).await?;
+        Ok(ProtocolPhase::ServerInit)
+    }
 
+    async fn server_init(&mut self) -> Result<ProtocolPhase, RfbSessionError> {
         self.sender.send(ToServerMessage::ClientInit(true)).await?;
-        self.server_info = Some(self.get_server_info().await?);
-        self.same_pixel_format = self.is_same_pixel_format();
+        self.server_info = Some(handshake::read_server_init(self).await?);
 
-        self.sender.send(ToServerMessage::SetEncoding(vec![RfbEncodingType::HexTile, RfbEncodingType::Raw])).await?;
+        Ok(ProtocolPhase::Init)
+    }
 
-        Ok(())
+    async fn init(&mut self) -> Result<ProtocolPhase, RfbSessionError> {
+        // If the remote screen is smaller than the panel, the area around it is never painted by the server
+        let frame_size = self.server_frame_size()?;
+        if (frame_size.width as usize) < self.screen.xres() || (frame_size.height as usize) < self.screen.yres() {
+            self.screen.clear();
+            self.screen.update();
+        }
+
+        let encodings = match self.negotiation_cache.get(&self.server_address) {
+            Some(cached) if cached.pixel_format == *self.get_server_pixel_format()? && cached.color_adjustment == self.screen.color_adjustment => {
+                self.same_pixel_format = cached.same_pixel_format;
+                self.pixel_lookup_table = cached.pixel_lookup_table;
+                cached.encodings
+            },
+            _ => {
+                self.same_pixel_format = self.is_same_pixel_format();
+                self.pixel_lookup_table = self.build_pixel_lookup_table().map(Arc::new);
+                let encodings = self.advertised_encodings();
+
+                self.negotiation_cache.insert(&self.server_address, CachedNegotiation {
+                    pixel_format: self.get_server_pixel_format()?.clone(),
+                    color_adjustment: self.screen.color_adjustment.clone(),
+                    same_pixel_format: self.same_pixel_format,
+                    pixel_lookup_table: self.pixel_lookup_table.clone(),
+                    encodings: encodings.clone(),
+                });
+
+                encodings
+            }
+        };
+
+        self.sender.send(ToServerMessage::SetEncoding(encodings)).await?;
+
+        Ok(ProtocolPhase::FrameData)
+    }
+
+    // The encodings sent with SetEncoding, in order of preference, built from the enabled features. Pseudo-encodings
+    // (negative numbers) announce what else the client understands (cursor updates, desktop name changes, Tight levels)
+    fn advertised_encodings(&self) -> Vec<i32> {
+        // On a fast wired LAN, decoding HexTile on the Pi costs more than the bandwidth it saves, so let the
+        // server pick Raw. On Wi-Fi or slower links HexTile is the better default
+        let mut encodings = if self.options.prefer_raw {
+            vec![RfbEncodingType::Raw, RfbEncodingType::HexTile]
+        } else {
+            vec![RfbEncodingType::HexTile, RfbEncodingType::Raw]
+        };
+        let mut pseudo_encodings = Vec::new();
+
+        // Tight is preferred when enabled, JPEG only pays off for photo-like content (e.g. camera snapshots)
+        #[cfg(feature = "tight")]
+        if self.options.jpeg_quality.is_some() || self.options.compression.is_some() {
+            encodings.insert(0, RfbEncodingType::Tight);
+
+            if let Some(jpeg_quality) = self.options.jpeg_quality {
+                pseudo_encodings.push(RfbEncodingType::jpeg_quality(jpeg_quality));
+            }
+
+            if let Some(compression) = self.options.compression {
+                println!("Requesting compression level {}", compression);
+                pseudo_encodings.push(RfbEncodingType::compress_level(compression));
+            }
+        }
+
+        if self.options.show_cursor {
+            pseudo_encodings.extend_from_slice(&[RfbEncodingType::Cursor, RfbEncodingType::PointerPos]);
+        }
+
+        pseudo_encodings.push(RfbEncodingType::DesktopName);
+
+        // Custom encodings are preferred over the standard ones. The latency probe marker is only advertised when a
+        // probe is enabled (--latency-probe-secs or --control-socket)
+        self.options.custom_encodings.encoding_numbers()
+            .chain(Some(latency::MARKER_ENCODING).filter(|_| self.options.latency_marker))
+            .chain(encodings.iter().chain(pseudo_encodings.iter()).map(|encoding| *encoding as i32))
+            .collect()
     }
 
     async fn refresh_screen(&mut self) -> Result<(), RfbSessionError> {
@@ -208,20 +623,34 @@ impl FromServerThread<'_> {
         )).await?;
 
         loop {
-            let mut command_buffer: [u8; 2] = [0; 2];
-
-            self.read(&mut command_buffer[..]).await?;
-            let command = <u16>::from_be_bytes(command_buffer);
+            let command = self.read_command().await?;
 
             match FromServerCommands::new(command)? {
                
                 FromServerCommands::FrameUpdate => {
+                    let drift_check = std::mem::take(&mut self.drift_check_pending);
+
+                    if drift_check {
+                        self.begin_drift_frame();
+                    }
+
                     self.frame_update().await?;
 
-                    // Send incremental frame refresh command to get the next frame update
-                    
+                    if drift_check {
+                        self.finish_drift_check();
+                    }
+
+                    // Frame updates are flowing, so taps can now be delivered to this server. Anything
+                    // touched before this point (e.g. while the splash screen was shown) was dropped
+                    self.pointer_enabled.send_replace(true);
+
+                    // Send incremental frame refresh command to get the next frame update (or a full one if the
+                    // screen was blanked while the display was off)
+
+                    let incremental = !self.wait_while_display_off().await && !self.start_drift_check();
+
                     self.sender.send(ToServerMessage::FrameUpdateRequest(
-                        FrameUpdateRequestArgs { incremental: true,
+                        FrameUpdateRequestArgs { incremental,
                             rect: Rect {
                                 location: Point{x: 0, y: 0},
                                 size: Size{
@@ -232,74 +661,122 @@ impl FromServerThread<'_> {
                         }
                     )).await?;
                 }
+
+                FromServerCommands::SetCurText => {
+                    self.set_cur_text().await?;
+
+                    if self.options.cur_text_position.is_some() {
+                        self.draw_cur_text();
+                        self.present();
+                    }
+                },
+
+                // A colour map is meaningless with a true colour pixel format, so the stream is out of step (e.g. the
+                // preceding frame update was decoded with the wrong length)
+                FromServerCommands::SetColourMapEntries if self.options.strict && self.get_server_pixel_format().is_ok_and(|pf| pf.true_color) =>
+                    return Err(RfbSessionError(RfbSessionErrorKind::ProtocolViolation(String::from("Colour map entries with a true colour pixel format")))),
+
+                command if self.options.lenient => self.skip_message(command).await?,
+                command => return Err(RfbSessionError(RfbSessionErrorKind::InvalidServerCommand(command as u16))),
             }
         }
     }
 
-    async fn get_server_supported_security_options(&mut self) -> Result<Vec<u8>, RfbSessionError> {
-        let mut buffer: [u8; 1]= [0; 1];
-
-        self.read(&mut buffer[..]).await?;
-        let count = buffer[0];
+    // Wait for the next server message. If a paced frame is waiting to be presented, present it when its time
+    // comes even if no more data arrives
+    async fn read_command(&mut self) -> Result<u8, RfbSessionError> {
+        let mut command_buffer: [u8; 1] = [0; 1];
 
-        if count == 0 {
-            let error_message = self.get_string_from_server().await?;
+        if self.present_pending {
+            let deadline = tokio::time::Instant::from_std(self.next_present);
 
-            return Err(RfbSessionError(RfbSessionErrorKind::ServerError(error_message)));
+            match tokio::time::timeout_at(deadline, self.read(&mut command_buffer[..])).await {
+                Ok(result) => {
+                    result?;
+                    return Ok(command_buffer[0]);
+                },
+                Err(_) => self.present(),
+            }
         }
 
-        let mut security_options = vec![0; count as usize];
-        self.read(security_options.as_mut_slice()).await?;
-
-        Ok(security_options)
+        self.read(&mut command_buffer[..]).await?;
+        Ok(command_buffer[0])
     }
 
-    async fn get_security_result(&mut self) -> Result<(), RfbSessionError> {
-        let mut buffer: [u8; 4] = [0; 4];
+    // Without a backlight control, night mode blanks the screen and stops requesting frame updates (saving
+    // bandwidth) until the display is turned on again. Returns true if the screen was blanked
+    async fn wait_while_display_off(&mut self) -> bool {
+        let night_mode = match self.options.night_mode {
+            Some(ref night_mode) if !night_mode.has_backlight() && !night_mode.is_display_on() => night_mode.clone(),
+            _ => return false,
+        };
 
-        self.read(&mut buffer[..]).await?;
-        let result = u32::from_be_bytes(buffer);
+        self.screen.image.fill(0);
+        self.screen.update();
 
-        if result != 0 {
-            let error_message = self.get_string_from_server().await?;
+        let mut display_on = night_mode.subscribe();
+        let _ = display_on.wait_for(|on| *on).await;
 
-            return Err(RfbSessionError(RfbSessionErrorKind::ServerError(error_message)));
+        true
+    }
+
+    // Skip server messages that are known but not implemented (only in lenient mode):
+    //   SetColourMapEntries - padding, first color (u16), color count (u16) followed by 6 bytes per color
+    //   Bell - no payload
+    //   ServerCutText - 3 padding bytes, text length (u32) followed by the text
+    async fn skip_message(&mut self, command: FromServerCommands) -> Result<(), RfbSessionError> {
+        let payload_length = match command {
+            FromServerCommands::SetColourMapEntries => {
+                let mut header: [u8; 5] = [0; 5];
+
+                self.read_with_timeout(&mut header[..]).await?;
+                u16::from_be_bytes([header[3], header[4]]) as usize * 6
+            },
+            FromServerCommands::ServerCutText => {
+                let mut header: [u8; 7] = [0; 7];
+
+                self.read_with_timeout(&mut header[..]).await?;
+                u32::from_be_bytes([header[3], header[4], header[5], header[6]]) as usize
+            },
+            FromServerCommands::Bell | FromServerCommands::FrameUpdate | FromServerCommands::SetCurText => 0,
+        };
+
+        println!("Skipping unsupported server message {:?}", command);
+
+        let mut buffer: [u8; 256] = [0; 256];
+        let mut remaining = payload_length;
+
+        while remaining > 0 {
+            let count = remaining.min(buffer.len());
+
+            self.read_with_timeout(&mut buffer[..count]).await?;
+            remaining -= count;
         }
-        
+
         Ok(())
     }
 
-    async fn get_server_info(&mut self) -> Result<ServerInfo, RfbSessionError> {
-        let mut buffer: [u8; 2+2+16] = [0; 20];
+    // SetCurText: 3 padding bytes, text length (u32) and the UTF-8 text
+    async fn set_cur_text(&mut self) -> Result<(), RfbSessionError> {
+        let mut header: [u8; 7] = [0; 7];
 
-        self.read(&mut buffer[..]).await?;
+        self.read_with_timeout(&mut header[..]).await?;
 
-        let width = u16::from_be_bytes(<[u8; 2]>::try_from(&buffer[0..2]).unwrap());
-        let height = u16::from_be_bytes(<[u8; 2]>::try_from(&buffer[2..4]).unwrap());
-        let pixel_format = PixelFormat::decode(&buffer[4..20]);
-        let name = self.get_string_from_server().await?;
+        let length = u32::from_be_bytes([header[3], header[4], header[5], header[6]]) as usize;
 
-        Ok(ServerInfo{
-            frame_buffer_width: width,
-            frame_buffer_height: height,
-            pixel_format,
-            name
-        })
-    }
-
-    async fn get_string_from_server(&mut self) -> Result<String, RfbSessionError> {
-        let mut count_buffer: [u8; 4] = [0; 4];
+        if length > MAX_CUR_TEXT_LENGTH {
+            return Err(RfbSessionError(RfbSessionErrorKind::ProtocolViolation(format!("SetCurText of {} bytes is too long", length))));
+        }
 
-        self.read(&mut count_buffer).await?;
-        let count = i32::from_be_bytes(count_buffer);
+        let mut text_bytes = vec![0; length];
 
-        assert!(count < 1024);
-        let mut message_bytes = vec![0; count as usize];
+        self.read_with_timeout(text_bytes.as_mut_slice()).await?;
 
-        self.read(message_bytes.as_mut_slice()).await?;
-        let message = String::from_utf8(message_bytes).unwrap();
+        let text = String::from_utf8(text_bytes).map_err(|_| RfbSessionError(RfbSessionErrorKind::ProtocolViolation("SetCurText text is not UTF-8".to_string())))?;
 
-        Ok(message)
+        println!("Server text: '{}'", text);
+        self.cur_text = Some(text);
+        Ok(())
     }
 }
 
@@ -311,15 +788,27 @@ pub enum RfbSessionErrorKind {
     SendError(tokio::sync::mpsc::error::SendError<ToServerMessage>),
     ServerProtocolVersion,
     ServerError(String),
+    NoSupportedSecurity(Vec<u8>),   // Security types offered by the server, none of them usable
     InvalidServerCommand(u16),
     InvalidEncoding(i32),
+    ProtocolViolation(String),
     SessionClosedByServer,
+    Timeout { phase: ProtocolPhase },
+    WriteTimeout,
+    DeadLink,
+    IdleDisconnect,
     JoinError,
 }
 
 #[derive(Debug)]
 pub struct RfbSessionError(RfbSessionErrorKind);
 
+impl RfbSessionError {
+    pub fn kind(&self) -> &RfbSessionErrorKind {
+        &self.0
+    }
+}
+
 impl std::error::Error for RfbSessionError {
     fn description(&self) -> &str {
         match &self.0 {
@@ -328,9 +817,15 @@ impl std::error::Error for RfbSessionError {
             RfbSessionErrorKind::SendError(_) => "SendError",
             RfbSessionErrorKind::OtherError(_) => "Another error",
             RfbSessionErrorKind::ServerError(_) => "Server error",
+            RfbSessionErrorKind::NoSupportedSecurity(_) => "No supported security type",
             RfbSessionErrorKind::InvalidServerCommand(_) => "Invalid server command",
             RfbSessionErrorKind::InvalidEncoding(_) => "Invalid encoding",
+            RfbSessionErrorKind::ProtocolViolation(_) => "Protocol violation",
             RfbSessionErrorKind::SessionClosedByServer => "Session closed by server",
+            RfbSessionErrorKind::Timeout { .. } => "Timeout",
+            RfbSessionErrorKind::WriteTimeout => "Write timeout",
+            RfbSessionErrorKind::DeadLink => "Dead link",
+            RfbSessionErrorKind::IdleDisconnect => "Idle disconnect",
             RfbSessionErrorKind::JoinError => "Join error",
         }
     }
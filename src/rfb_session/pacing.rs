@@ -0,0 +1,164 @@
+use std::time::Duration;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::Instant;
+
+use super::rfb_messages::{PointerEventArgs, ToServerMessage};
+
+// Touch events reach the session in bursts (e.g. while the channel was backed up), but the server tells a swipe from a
+// slow drag by the spacing of the points. Events carrying the kernel event time are forwarded with their original
+// spacing, up to MAX_SPACING (so a pause never delays the following events by much). Moves closer than MERGE_WINDOW
+// to the following move are dropped in its favor
+
+const MAX_SPACING: Duration = Duration::from_millis(50);
+const MERGE_WINDOW: Duration = Duration::from_millis(5);
+
+pub async fn pace_pointer_events(mut input: Receiver<ToServerMessage>, output: Sender<ToServerMessage>) {
+    let mut last_sent: Option<(Duration, Instant)> = None;     // Event time and send time of the last event
+    let mut next = input.recv().await;
+
+    while let Some(mut message) = next.take() {
+        loop {
+            match input.try_recv() {
+                Ok(following) if can_merge(&message, &following) => message = following,
+                Ok(following) => {
+                    next = Some(following);
+                    break;
+                },
+                Err(_) => break,
+            }
+        }
+
+        if let Some(timestamp) = event_time(&message) {
+            if let Some((last_timestamp, last_sent_at)) = last_sent {
+                tokio::time::sleep_until(last_sent_at + timestamp.saturating_sub(last_timestamp).min(MAX_SPACING)).await;
+            }
+
+            last_sent = Some((timestamp, Instant::now()));
+        }
+
+        if output.send(message).await.is_err() {
+            return;
+        }
+
+        if next.is_none() {
+            next = input.recv().await;
+        }
+    }
+}
+
+fn event_time(message: &ToServerMessage) -> Option<Duration> {
+    match message {
+        ToServerMessage::PointerEvent(PointerEventArgs{timestamp, ..}) => *timestamp,
+        _ => None,
+    }
+}
+
+// Only events with the same buttons down are merged, so no press or release is lost
+fn can_merge(message: &ToServerMessage, following: &ToServerMessage) -> bool {
+    match (message, following) {
+        (ToServerMessage::PointerEvent(PointerEventArgs{button_mask, timestamp: Some(timestamp), ..}),
+         ToServerMessage::PointerEvent(PointerEventArgs{button_mask: following_button_mask, timestamp: Some(following_timestamp), ..})) =>
+            button_mask == following_button_mask && following_timestamp.saturating_sub(*timestamp) < MERGE_WINDOW,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::rfb_messages::Point;
+    use tokio::sync::mpsc::channel;
+
+    fn pointer_event(button_mask: u8, x: u16, millis: u64) -> ToServerMessage {
+        ToServerMessage::PointerEvent(PointerEventArgs { button_mask, location: Point { x, y: 100 }, timestamp: Some(Duration::from_millis(millis)) })
+    }
+
+    // Feed a burst of events and return the x coordinate of each event forwarded with the time it was sent
+    async fn pace(events: Vec<ToServerMessage>) -> Vec<(u16, Duration)> {
+        let (input_sender, input) = channel(events.len() + 1);
+        let (output, mut output_receiver) = channel(events.len() + 1);
+        let start = Instant::now();
+
+        for event in events {
+            input_sender.send(event).await.unwrap();
+        }
+        drop(input_sender);
+
+        tokio::spawn(pace_pointer_events(input, output));
+
+        let mut sent = Vec::new();
+        while let Some(message) = output_receiver.recv().await {
+            match message {
+                ToServerMessage::PointerEvent(PointerEventArgs{location, ..}) => sent.push((location.x, start.elapsed())),
+                other => panic!("Unexpected message {:?}", other),
+            }
+        }
+
+        sent
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn swipe_keeps_its_spacing() {
+        // A recorded swipe: press, moves every 8 ms, release
+        let swipe = vec![
+            pointer_event(1, 10, 1000),
+            pointer_event(1, 30, 1008),
+            pointer_event(1, 60, 1016),
+            pointer_event(1, 100, 1024),
+            pointer_event(0, 100, 1032),
+        ];
+
+        assert_eq!(pace(swipe).await, vec![
+            (10, Duration::from_millis(0)),
+            (30, Duration::from_millis(8)),
+            (60, Duration::from_millis(16)),
+            (100, Duration::from_millis(24)),
+            (100, Duration::from_millis(32)),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn close_moves_are_merged() {
+        let events = vec![
+            pointer_event(1, 10, 0),
+            pointer_event(1, 20, 10),
+            pointer_event(1, 25, 12),
+            pointer_event(1, 30, 30),
+        ];
+
+        assert_eq!(pace(events).await, vec![
+            (10, Duration::from_millis(0)),
+            (25, Duration::from_millis(12)),
+            (30, Duration::from_millis(30)),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pauses_are_capped() {
+        let events = vec![
+            pointer_event(1, 10, 0),
+            pointer_event(0, 10, 2000),
+        ];
+
+        assert_eq!(pace(events).await, vec![
+            (10, Duration::from_millis(0)),
+            (10, MAX_SPACING),
+        ]);
+    }
+
+    #[test]
+    fn press_and_release_are_never_merged() {
+        assert!(can_merge(&pointer_event(1, 10, 0), &pointer_event(1, 11, 1)));
+        assert!(!can_merge(&pointer_event(0, 10, 0), &pointer_event(1, 10, 1)));
+        assert!(!can_merge(&pointer_event(1, 10, 0), &pointer_event(0, 10, 1)));
+        assert!(!can_merge(&pointer_event(1, 10, 0), &pointer_event(1, 11, 5)));
+    }
+
+    #[test]
+    fn events_without_time_are_not_merged() {
+        let untimed = ToServerMessage::PointerEvent(PointerEventArgs { button_mask: 1, location: Point { x: 0, y: 0 }, timestamp: None });
+
+        assert!(!can_merge(&untimed, &pointer_event(1, 10, 0)));
+        assert!(!can_merge(&pointer_event(1, 10, 0), &untimed));
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant};
+use tokio::io::AsyncRead;
+use crate::screen::DevicePixel;
+
+const BAR_HEIGHT: usize = 4;
+
+// On a fast link the first frame is complete before the bar would be useful
+const SHOW_AFTER: Duration = Duration::from_millis(300);
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+// Progress of the first (full) frame after connect, which may take several seconds on a slow link
+#[derive(Debug)]
+pub struct FrameProgress {
+    start: Instant,
+    last_shown: Option<Instant>,
+    covered: u64,
+    total: u64,
+}
+
+impl<R: AsyncRead + Unpin> super::FromServerThread<'_, R> {
+
+    pub fn start_progress(&mut self) {
+        if let (None, Ok(frame_size)) = (self.stats.first_frame_time, self.server_frame_size()) {
+            self.first_frame_progress = Some(FrameProgress {
+                start: Instant::now(),
+                last_shown: None,
+                covered: 0,
+                total: (frame_size.width as u64 * frame_size.height as u64).max(1),
+            });
+        }
+    }
+
+    // Another part (in pixels) of the first frame was decoded
+    pub fn advance_progress(&mut self, area: u64) {
+        let fraction = match self.first_frame_progress {
+            Some(ref mut progress) => {
+                progress.covered += area;
+
+                if progress.start.elapsed() < SHOW_AFTER || progress.last_shown.is_some_and(|last_shown| last_shown.elapsed() < REFRESH_INTERVAL) {
+                    return;
+                }
+
+                progress.last_shown = Some(Instant::now());
+                (progress.covered as f64 / progress.total as f64).min(1.0)
+            },
+            None => return,
+        };
+
+        self.show_progress(fraction);
+    }
+
+    pub fn end_progress(&mut self) {
+        self.first_frame_progress = None;
+    }
+
+    // The bar is drawn at the bottom of the screen and displayed, then the pixels under it are restored so the
+    // decoded frame is not affected
+    fn show_progress(&mut self, fraction: f64) {
+        let bytes_per_row = self.screen.bytes_per_row();
+        let width = self.screen.xres();
+        let bar_top = self.screen.yres().saturating_sub(BAR_HEIGHT);
+        let saved = self.screen.image[bar_top * bytes_per_row..].to_vec();
+        let done_width = (width as f64 * fraction) as usize;
+
+        self.screen.fill_rect(0, bar_top, done_width, BAR_HEIGHT, DevicePixel::from_rgb(255, 255, 255));
+        self.screen.fill_rect(done_width, bar_top, width - done_width, BAR_HEIGHT, DevicePixel::from_rgb(64, 64, 64));
+        self.screen.update();
+
+        self.screen.image[bar_top * bytes_per_row..].copy_from_slice(&saved);
+    }
+}
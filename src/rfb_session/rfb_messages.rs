@@ -3,44 +3,79 @@ use crate::rfb_session::{
     RfbSessionError,
     RfbSessionErrorKind,
 };
+use std::time::Duration;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct Point {
     pub x: u16,
     pub y: u16,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Size {
     pub width: u16,
     pub height: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Rect {
     pub location: Point,
     pub size: Size,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct FrameUpdateRequestArgs {
     pub incremental: bool,
     pub rect: Rect,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct PointerEventArgs {
     pub button_mask: u8,
     pub location: Point,
+    pub timestamp: Option<Duration>,    // Kernel time of the input event, the spacing of touch events is preserved when sending them
+}
+
+#[derive(Debug, PartialEq)]
+pub struct KeyEventArgs {
+    pub down: bool,
+    pub key: u32,           // X11 keysym
 }
 
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(feature = "tight"), allow(dead_code))]
 pub enum RfbEncodingType {
     Raw = 0,
     HexTile = 5,
+    Tight = 7,
+    JpegQuality0 = -32,     // Pseudo-encodings: Tight JPEG quality level (0 lowest, 9 highest)
+    JpegQuality1 = -31,
+    JpegQuality2 = -30,
+    JpegQuality3 = -29,
+    JpegQuality4 = -28,
+    JpegQuality5 = -27,
+    JpegQuality6 = -26,
+    JpegQuality7 = -25,
+    JpegQuality8 = -24,
+    JpegQuality9 = -23,
+    CompressLevel0 = -256,  // Pseudo-encodings: zlib compression level for Tight (0 fastest, 9 smallest)
+    CompressLevel1 = -255,
+    CompressLevel2 = -254,
+    CompressLevel3 = -253,
+    CompressLevel4 = -252,
+    CompressLevel5 = -251,
+    CompressLevel6 = -250,
+    CompressLevel7 = -249,
+    CompressLevel8 = -248,
+    CompressLevel9 = -247,
+    DesktopSize = -223,     // Pseudo-encoding: the server framebuffer was resized to the rectangle size
+    LastRect = -224,        // Pseudo-encoding: no more rectangles in this update (the count may be larger)
+    PointerPos = -232,      // Pseudo-encoding: the rectangle location is the new cursor position
+    Cursor = -239,          // Pseudo-encoding: cursor image and transparency mask, the location is the hotspot
+    DesktopName = -307,     // Pseudo-encoding: the session name changed, followed by the new name
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[allow(dead_code)]
 pub enum RfbSecurityType {
     Invalid = 0,
@@ -48,17 +83,23 @@ pub enum RfbSecurityType {
     VncAuthentication = 2,
 }
 
+#[derive(Clone, Copy, Debug)]
 pub enum FromServerCommands {
     FrameUpdate = 0,
+    SetColourMapEntries = 1,
+    Bell = 2,
+    ServerCutText = 3,
+    SetCurText = 6,         // HomeTouch: short status text from the server, framed like ClientCutText
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ToServerMessage {
     ProtocolVersion,
     Security(RfbSecurityType),
     ClientInit(bool),
-    SetEncoding(Vec<RfbEncodingType>),
+    SetEncoding(Vec<i32>),             // Encoding numbers, so custom encodings can be advertised too
     FrameUpdateRequest(FrameUpdateRequestArgs),
+    KeyEvent(KeyEventArgs),
     PointerEvent(PointerEventArgs),
     SetCurText(String),
     Terminate,
@@ -66,6 +107,13 @@ pub enum ToServerMessage {
 
 use ToServerMessage::*;
 
+// Client to server message types (first byte of the messages sent once the handshake is done)
+const SET_ENCODINGS_MESSAGE: u8 = 2;
+const FRAME_UPDATE_REQUEST_MESSAGE: u8 = 3;
+const KEY_EVENT_MESSAGE: u8 = 4;
+const POINTER_EVENT_MESSAGE: u8 = 5;
+const CLIENT_CUT_TEXT_MESSAGE: u8 = 6;
+
 impl ToServerMessage {
     pub fn encode(&self) -> Vec<u8> {
         match self {
@@ -73,11 +121,11 @@ impl ToServerMessage {
             Security(security_type) => vec![*security_type as u8],
             ClientInit(shared) => vec![if *shared { 1 } else { 0} ],
             SetEncoding(encodings) => {
-                let mut result = vec![2, 0];
+                let mut result = vec![SET_ENCODINGS_MESSAGE, 0];
                 result.extend_from_slice(&(encodings.len() as u16).to_be_bytes());
 
                 for encoding in encodings.iter() {
-                    result.extend_from_slice(&(*encoding as i32).to_be_bytes());
+                    result.extend_from_slice(&encoding.to_be_bytes());
                 }
                 result
             },
@@ -88,49 +136,278 @@ impl ToServerMessage {
                     size: Size{width, height},
                 }
             }) => {
-                let mut result = vec![3, if *incremental { 1 } else { 0 }];
+                let mut result = vec![FRAME_UPDATE_REQUEST_MESSAGE, if *incremental { 1 } else { 0 }];
                 result.extend_from_slice(&x.to_be_bytes());
                 result.extend_from_slice(&y.to_be_bytes());
                 result.extend_from_slice(&width.to_be_bytes());
                 result.extend_from_slice(&height.to_be_bytes());
                 result
             },
+            KeyEvent(KeyEventArgs{down, key}) => {
+                let mut result = vec![KEY_EVENT_MESSAGE, if *down { 1 } else { 0 }, 0, 0];
+                result.extend_from_slice(&key.to_be_bytes());
+                result
+            },
             PointerEvent(PointerEventArgs{
                 button_mask,
-                location: Point{x, y}
+                location: Point{x, y},
+                ..
             }) => {
-                let mut result = vec![5, *button_mask];
+                let mut result = vec![POINTER_EVENT_MESSAGE, *button_mask];
                 result.extend_from_slice(&x.to_be_bytes());
                 result.extend_from_slice(&y.to_be_bytes());
                 result
             },
+            // Framed as a standard RFB ClientCutText message: type 6, 3 padding bytes, u32 length and the text.
+            // The HomeTouch server treats it as "current text" and the client sends an empty one as a keepalive
             SetCurText(text) => {
                 let text_bytes = text.as_bytes();
-                let mut result = vec![6, 0, 0, 0];
-                result.extend_from_slice(&text_bytes.len().to_be_bytes());
+                let mut result = vec![CLIENT_CUT_TEXT_MESSAGE, 0, 0, 0];
+                result.extend_from_slice(&(text_bytes.len() as u32).to_be_bytes());
                 result.extend_from_slice(text_bytes);
                 result
             },
             Terminate => panic!("Cannot encode terminate message")
         }
     }
+
+    // Inverse of encode for the messages sent after the handshake, returns the message and the number of bytes it
+    // occupies. The handshake messages (ProtocolVersion, Security and ClientInit) carry no type and can only be told
+    // apart by their position in the stream, so they are not decoded here
+    #[allow(dead_code)]
+    pub fn decode(buffer: &[u8]) -> Result<(ToServerMessage, usize), RfbSessionError> {
+        let truncated = || RfbSessionError(RfbSessionErrorKind::ProtocolViolation(format!("Truncated client message ({} bytes)", buffer.len())));
+        let get_u16 = |offset: usize| buffer.get(offset..offset + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]])).ok_or_else(truncated);
+        let get_u32 = |offset: usize| buffer.get(offset..offset + 4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).ok_or_else(truncated);
+
+        match *buffer.first().ok_or_else(truncated)? {
+            SET_ENCODINGS_MESSAGE => {
+                let count = get_u16(2)? as usize;
+                let mut encodings = Vec::with_capacity(count);
+
+                for index in 0..count {
+                    encodings.push(get_u32(4 + index * 4)? as i32);
+                }
+
+                Ok((SetEncoding(encodings), 4 + count * 4))
+            },
+            FRAME_UPDATE_REQUEST_MESSAGE => {
+                let incremental = *buffer.get(1).ok_or_else(truncated)? != 0;
+                let rect = Rect {
+                    location: Point{x: get_u16(2)?, y: get_u16(4)?},
+                    size: Size{width: get_u16(6)?, height: get_u16(8)?},
+                };
+
+                Ok((FrameUpdateRequest(FrameUpdateRequestArgs{incremental, rect}), 10))
+            },
+            KEY_EVENT_MESSAGE => {
+                let down = *buffer.get(1).ok_or_else(truncated)? != 0;
+
+                Ok((KeyEvent(KeyEventArgs{down, key: get_u32(4)?}), 8))
+            },
+            POINTER_EVENT_MESSAGE => {
+                let button_mask = *buffer.get(1).ok_or_else(truncated)?;
+                let location = Point{x: get_u16(2)?, y: get_u16(4)?};
+
+                Ok((PointerEvent(PointerEventArgs{button_mask, location, timestamp: None}), 6))
+            },
+            CLIENT_CUT_TEXT_MESSAGE => {
+                let length = get_u32(4)? as usize;
+                let text_bytes = buffer.get(8..8 + length).ok_or_else(truncated)?;
+                let text = String::from_utf8(text_bytes.to_vec()).map_err(|_| RfbSessionError(RfbSessionErrorKind::ProtocolViolation("Client cut text is not UTF-8".to_string())))?;
+
+                Ok((SetCurText(text), 8 + length))
+            },
+            message_type => Err(RfbSessionError(RfbSessionErrorKind::InvalidServerCommand(message_type as u16))),
+        }
+    }
 }
 
 impl FromServerCommands {
-    pub fn new(command: u16) -> Result<FromServerCommands, RfbSessionError> {
+    pub fn new(command: u8) -> Result<FromServerCommands, RfbSessionError> {
         match command {
             0 => Ok(FromServerCommands::FrameUpdate),
-            _ => Err(RfbSessionError(RfbSessionErrorKind::InvalidServerCommand(command))),
+            1 => Ok(FromServerCommands::SetColourMapEntries),
+            2 => Ok(FromServerCommands::Bell),
+            3 => Ok(FromServerCommands::ServerCutText),
+            6 => Ok(FromServerCommands::SetCurText),
+            _ => Err(RfbSessionError(RfbSessionErrorKind::InvalidServerCommand(command as u16))),
         }
     }
 }
 
 impl RfbEncodingType {
+    #[cfg(feature = "tight")]
+    pub fn jpeg_quality(level: u8) -> RfbEncodingType {
+        match level {
+            0 => RfbEncodingType::JpegQuality0,
+            1 => RfbEncodingType::JpegQuality1,
+            2 => RfbEncodingType::JpegQuality2,
+            3 => RfbEncodingType::JpegQuality3,
+            4 => RfbEncodingType::JpegQuality4,
+            5 => RfbEncodingType::JpegQuality5,
+            6 => RfbEncodingType::JpegQuality6,
+            7 => RfbEncodingType::JpegQuality7,
+            8 => RfbEncodingType::JpegQuality8,
+            _ => RfbEncodingType::JpegQuality9,
+        }
+    }
+
+    #[cfg(feature = "tight")]
+    pub fn compress_level(level: u8) -> RfbEncodingType {
+        match level {
+            0 => RfbEncodingType::CompressLevel0,
+            1 => RfbEncodingType::CompressLevel1,
+            2 => RfbEncodingType::CompressLevel2,
+            3 => RfbEncodingType::CompressLevel3,
+            4 => RfbEncodingType::CompressLevel4,
+            5 => RfbEncodingType::CompressLevel5,
+            6 => RfbEncodingType::CompressLevel6,
+            7 => RfbEncodingType::CompressLevel7,
+            8 => RfbEncodingType::CompressLevel8,
+            _ => RfbEncodingType::CompressLevel9,
+        }
+    }
+
+    // Encoding of a rectangle carrying pixels
     pub fn new(encoding: i32) -> Result<RfbEncodingType, RfbSessionError> {
         match encoding {
             0 => Ok(RfbEncodingType::Raw),
             5 => Ok(RfbEncodingType::HexTile),
+            #[cfg(feature = "tight")]
+            7 => Ok(RfbEncodingType::Tight),
             _ => Err(RfbSessionError(RfbSessionErrorKind::InvalidEncoding(encoding)))
         }
     }
-}
\ No newline at end of file
+
+    // Pseudo-encoding (negative) of a rectangle the decoder understands. Settings that are only advertised (e.g.
+    // JPEG quality) never come as a rectangle
+    pub fn pseudo(encoding: i32) -> Option<RfbEncodingType> {
+        match encoding {
+            -223 => Some(RfbEncodingType::DesktopSize),
+            -224 => Some(RfbEncodingType::LastRect),
+            -232 => Some(RfbEncodingType::PointerPos),
+            -239 => Some(RfbEncodingType::Cursor),
+            -307 => Some(RfbEncodingType::DesktopName),
+            _ => None,
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pseudo_encodings_are_sent_as_signed_big_endian() {
+        let encodings = vec![RfbEncodingType::HexTile as i32, RfbEncodingType::Cursor as i32, RfbEncodingType::DesktopName as i32, RfbEncodingType::CompressLevel0 as i32];
+
+        assert_eq!(SetEncoding(encodings).encode(), vec![
+            SET_ENCODINGS_MESSAGE, 0, 0, 4,
+            0x00, 0x00, 0x00, 0x05,
+            0xff, 0xff, 0xff, 0x11,     // -239
+            0xff, 0xff, 0xfe, 0xcd,     // -307
+            0xff, 0xff, 0xff, 0x00,     // -256
+        ]);
+    }
+
+    #[test]
+    fn pseudo_encoding_values() {
+        assert_eq!(RfbEncodingType::DesktopSize as i32, -223);
+        assert_eq!(RfbEncodingType::LastRect as i32, -224);
+        assert_eq!(RfbEncodingType::PointerPos as i32, -232);
+        assert_eq!(RfbEncodingType::Cursor as i32, -239);
+        assert_eq!(RfbEncodingType::DesktopName as i32, -307);
+        assert_eq!(RfbEncodingType::JpegQuality0 as i32, -32);
+        assert_eq!(RfbEncodingType::JpegQuality9 as i32, -23);
+        assert_eq!(RfbEncodingType::CompressLevel9 as i32, -247);
+    }
+
+    #[test]
+    fn rect_pseudo_encodings_are_recognized() {
+        for encoding in [RfbEncodingType::DesktopSize, RfbEncodingType::LastRect, RfbEncodingType::PointerPos, RfbEncodingType::Cursor, RfbEncodingType::DesktopName] {
+            assert_eq!(RfbEncodingType::pseudo(encoding as i32).map(|pseudo| pseudo as i32), Some(encoding as i32));
+        }
+
+        // Only advertised, never sent as a rectangle
+        assert!(RfbEncodingType::pseudo(RfbEncodingType::JpegQuality5 as i32).is_none());
+        assert!(RfbEncodingType::pseudo(RfbEncodingType::Raw as i32).is_none());
+    }
+
+    #[test]
+    fn pseudo_encodings_are_not_pixel_encodings() {
+        assert!(RfbEncodingType::new(RfbEncodingType::Cursor as i32).is_err());
+        assert!(RfbEncodingType::new(RfbEncodingType::DesktopSize as i32).is_err());
+        assert!(RfbEncodingType::new(-1).is_err());
+        assert!(matches!(RfbEncodingType::new(0), Ok(RfbEncodingType::Raw)));
+        assert!(matches!(RfbEncodingType::new(5), Ok(RfbEncodingType::HexTile)));
+    }
+
+    fn round_trip(message: ToServerMessage) {
+        let encoded = message.encode();
+        let (decoded, length) = ToServerMessage::decode(&encoded).unwrap();
+
+        assert_eq!(decoded, message);
+        assert_eq!(length, encoded.len(), "{:?}", message);
+
+        // Trailing bytes belong to the next message
+        let mut followed = encoded.clone();
+        followed.extend_from_slice(&[POINTER_EVENT_MESSAGE, 0, 0, 0, 0, 0]);
+        assert_eq!(ToServerMessage::decode(&followed).unwrap(), (message, encoded.len()));
+
+        // Any truncation is an error, never a panic or a shorter message
+        for length in 0..encoded.len() {
+            assert!(ToServerMessage::decode(&encoded[..length]).is_err(), "{:?} truncated to {}", decoded, length);
+        }
+    }
+
+    fn pointer_event(button_mask: u8, x: u16, y: u16) -> ToServerMessage {
+        PointerEvent(PointerEventArgs{button_mask, location: Point{x, y}, timestamp: None})
+    }
+
+    #[test]
+    fn set_encoding_round_trip() {
+        round_trip(SetEncoding(vec![]));
+        round_trip(SetEncoding(vec![RfbEncodingType::Tight as i32, RfbEncodingType::Cursor as i32, i32::MIN, i32::MAX]));
+        round_trip(SetEncoding((-300..300).collect()));
+    }
+
+    #[test]
+    fn frame_update_request_round_trip() {
+        for incremental in [false, true] {
+            round_trip(FrameUpdateRequest(FrameUpdateRequestArgs{incremental, rect: Rect{location: Point{x: 0, y: 0}, size: Size{width: 800, height: 480}}}));
+            round_trip(FrameUpdateRequest(FrameUpdateRequestArgs{incremental, rect: Rect{location: Point{x: u16::MAX, y: u16::MAX}, size: Size{width: u16::MAX, height: u16::MAX}}}));
+        }
+    }
+
+    #[test]
+    fn key_event_round_trip() {
+        round_trip(KeyEvent(KeyEventArgs{down: true, key: 0xff0d}));
+        round_trip(KeyEvent(KeyEventArgs{down: false, key: 0}));
+        round_trip(KeyEvent(KeyEventArgs{down: true, key: u32::MAX}));
+    }
+
+    #[test]
+    fn pointer_event_round_trip() {
+        round_trip(pointer_event(0, 0, 0));
+        round_trip(pointer_event(1, 400, 240));
+        round_trip(pointer_event(0xff, u16::MAX, u16::MAX));
+    }
+
+    #[test]
+    fn cur_text_round_trip() {
+        round_trip(SetCurText(String::new()));
+        round_trip(SetCurText("Living room 21.5°C".to_string()));
+        round_trip(SetCurText("x".repeat(70000)));
+    }
+
+    #[test]
+    fn unknown_message_type_is_rejected() {
+        assert!(ToServerMessage::decode(&[99, 0, 0, 0]).is_err());
+        assert!(ToServerMessage::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn cur_text_must_be_utf8() {
+        assert!(ToServerMessage::decode(&[CLIENT_CUT_TEXT_MESSAGE, 0, 0, 0, 0, 0, 0, 2, 0xc3, 0x28]).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const ROLLING_WINDOW: Duration = Duration::from_secs(5);
+
+// Time spent on one frame update: waiting for server data, converting pixels and writing to the framebuffer
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FrameTiming {
+    pub read: Duration,
+    pub convert: Duration,
+    pub flush: Duration,
+}
+
+impl FrameTiming {
+    pub fn total(&self) -> Duration {
+        self.read + self.convert + self.flush
+    }
+}
+
+#[derive(Debug)]
+struct FrameSample {
+    time: Instant,
+    bytes: u64,
+    timing: FrameTiming,
+}
+
+#[derive(Debug)]
+pub struct SessionStats {
+    pub connected_at: Instant,
+    pub first_frame_time: Option<Duration>,     // Time from connect to the first complete frame
+    pub bytes_received: u64,
+    pub decode_allocations: u64,        // Times the reusable decode buffer had to grow
+    pub decode_buffer_high_water: usize,    // Largest decode buffer needed
+    pub decode_buffer_shrinks: u64,     // Times the decode buffer was released for exceeding the cap
+    pub frames_decoded: u64,
+    pub flushes: u64,                   // Frames written to the framebuffer, fewer than decoded when coalesced
+    recent_frames: VecDeque<FrameSample>,
+}
+
+impl SessionStats {
+    pub fn new() -> SessionStats {
+        SessionStats {
+            connected_at: Instant::now(),
+            first_frame_time: None,
+            bytes_received: 0,
+            decode_allocations: 0,
+            decode_buffer_high_water: 0,
+            decode_buffer_shrinks: 0,
+            frames_decoded: 0,
+            flushes: 0,
+            recent_frames: VecDeque::new(),
+        }
+    }
+
+    pub fn add_bytes_received(&mut self, count: usize) {
+        self.bytes_received += count as u64;
+    }
+
+    pub fn frame_completed(&mut self, bytes: u64, timing: FrameTiming) {
+        let now = Instant::now();
+
+        self.frames_decoded += 1;
+        self.recent_frames.push_back(FrameSample { time: now, bytes, timing });
+
+        while self.recent_frames.front().is_some_and(|sample| now.duration_since(sample.time) > ROLLING_WINDOW) {
+            self.recent_frames.pop_front();
+        }
+    }
+
+    // Returns true when the size is a new high water mark
+    pub fn decode_buffer_grown(&mut self, size: usize) -> bool {
+        self.decode_allocations += 1;
+
+        if size > self.decode_buffer_high_water {
+            self.decode_buffer_high_water = size;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn decode_memory_summary(&self) -> String {
+        format!("decode buffer high water {} KB, {} allocations, {} shrinks", self.decode_buffer_high_water / 1024, self.decode_allocations, self.decode_buffer_shrinks)
+    }
+
+    pub fn flush_summary(&self) -> String {
+        format!("{} frames decoded, {} flushed", self.frames_decoded, self.flushes)
+    }
+
+    // Frames per second over the rolling window
+    pub fn fps(&self) -> f64 {
+        self.recent_frames.len() as f64 / ROLLING_WINDOW.as_secs_f64()
+    }
+
+    // Kilobytes per second received over the rolling window
+    pub fn kbps(&self) -> f64 {
+        self.recent_frames.iter().map(|sample| sample.bytes).sum::<u64>() as f64 / 1024.0 / ROLLING_WINDOW.as_secs_f64()
+    }
+
+    // Average kilobytes per frame over the rolling window (shows the effect of the compression level)
+    pub fn average_frame_kb(&self) -> f64 {
+        match self.recent_frames.len() {
+            0 => 0.0,
+            count => self.recent_frames.iter().map(|sample| sample.bytes).sum::<u64>() as f64 / 1024.0 / count as f64,
+        }
+    }
+
+    // Average read + convert time per frame over the rolling window
+    pub fn average_decode_time(&self) -> Duration {
+        match self.recent_frames.len() {
+            0 => Duration::ZERO,
+            count => self.recent_frames.iter().map(|sample| sample.timing.read + sample.timing.convert).sum::<Duration>() / count as u32,
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use tokio::io::AsyncRead;
+use flate2::{Decompress, FlushDecompress};
+use super::{
+    RfbSessionError,
+    RfbSessionErrorKind,
+};
+use super::rfb_messages::{Rect, Size};
+use super::decode::MAX_RAW_RECT_SIZE;
+use super::tile_geometry;
+use crate::screen::{DevicePixel, Screen};
+
+const STREAM_COUNT: usize = 4;
+const MIN_TO_COMPRESS: usize = 12;     // Data shorter than this is sent without zlib
+const COMPRESSED_CHUNK_SIZE: usize = 64 * 1024;     // Compressed data is read from the socket in chunks of this size
+
+// Compression control byte (high nibble)
+const FILL_COMPRESSION: u8 = 0x08;
+const JPEG_COMPRESSION: u8 = 0x09;
+const MAX_BASIC_COMPRESSION: u8 = 0x07;
+const EXPLICIT_FILTER: u8 = 0x04;
+
+const COPY_FILTER: u8 = 0;
+const PALETTE_FILTER: u8 = 1;
+const GRADIENT_FILTER: u8 = 2;
+
+// The four zlib streams of a Tight session, they keep their state from one rectangle to the next
+pub struct TightState {
+    streams: Vec<Decompress>,
+    compressed_chunk: Vec<u8>,
+}
+
+impl Default for TightState {
+    fn default() -> TightState {
+        TightState {
+            streams: (0..STREAM_COUNT).map(|_| Decompress::new(true)).collect(),
+            compressed_chunk: Vec::new(),
+        }
+    }
+}
+
+fn tight_error(reason: String) -> RfbSessionError {
+    RfbSessionError(RfbSessionErrorKind::ProtocolViolation(reason))
+}
+
+impl<R: AsyncRead + Unpin> super::FromServerThread<'_, R> {
+
+    pub async fn decode_tight_rect(&mut self, rect: &Rect) -> Result<(), RfbSessionError> {
+        let control = self.read_u8().await?;
+
+        for stream_id in 0..STREAM_COUNT {
+            if control & (1 << stream_id) != 0 {
+                self.tight.streams[stream_id].reset(true);
+            }
+        }
+
+        // As with Raw, only the part of the rectangle on the screen is drawn
+        let visible = tile_geometry::clamp_to_screen(rect, self.screen.xres(), self.screen.yres())
+            .unwrap_or(Rect { location: rect.location, size: Size { width: 0, height: 0 } });
+
+        match control >> 4 {
+            FILL_COMPRESSION => {
+                let pixel = self.read_tight_pixel().await?;
+
+                self.screen.fill_rect(visible.location.x as usize, visible.location.y as usize, visible.size.width as usize, visible.size.height as usize, pixel);
+                Ok(())
+            },
+            JPEG_COMPRESSION => self.decode_tight_jpeg(rect, &visible).await,
+            compression if compression <= MAX_BASIC_COMPRESSION => {
+                let filter = if compression & EXPLICIT_FILTER != 0 { self.read_u8().await? } else { COPY_FILTER };
+
+                self.decode_tight_basic(rect, &visible, (compression & 0x03) as usize, filter).await
+            },
+            compression => Err(tight_error(format!("Invalid Tight compression {:#x}", compression))),
+        }
+    }
+
+    // Basic compression: the pixels are optionally filtered and zlib compressed
+    async fn decode_tight_basic(&mut self, rect: &Rect, visible: &Rect, stream_id: usize, filter: u8) -> Result<(), RfbSessionError> {
+        let width = rect.size.width as usize;
+        let height = rect.size.height as usize;
+        let pixel_size = self.tight_pixel_size()?;
+
+        match filter {
+            COPY_FILTER => {
+                let data = self.read_tight_data(stream_id, width * height * pixel_size).await?;
+
+                for (index, tight_pixel) in data.chunks(pixel_size).enumerate() {
+                    let pixel = self.tight_to_device_pixel(tight_pixel);
+
+                    self.put_pixel(visible, index % width, index / width, pixel);
+                }
+
+                self.return_decode_buffer(data);
+            },
+            PALETTE_FILTER => {
+                let color_count = self.read_u8().await? as usize + 1;
+                let mut palette_bytes = vec![0; color_count * pixel_size];
+
+                self.read_with_timeout(palette_bytes.as_mut_slice()).await?;
+                let palette: Vec<DevicePixel> = palette_bytes.chunks(pixel_size).map(|tight_pixel| self.tight_to_device_pixel(tight_pixel)).collect();
+
+                if color_count == 2 {
+                    // One bit per pixel, each row padded to a byte
+                    let bytes_per_row = width.div_ceil(8);
+                    let data = self.read_tight_data(stream_id, bytes_per_row * height).await?;
+
+                    for y in 0..height {
+                        for x in 0..width {
+                            let bit = (data[y * bytes_per_row + x / 8] >> (7 - x % 8)) & 1;
+
+                            self.put_pixel(visible, x, y, palette[bit as usize]);
+                        }
+                    }
+
+                    self.return_decode_buffer(data);
+                } else {
+                    let data = self.read_tight_data(stream_id, width * height).await?;
+
+                    for (index, color_index) in data.iter().enumerate() {
+                        let pixel = *palette.get(*color_index as usize).ok_or_else(|| tight_error(format!("Tight palette index {} out of {} colors", color_index, color_count)))?;
+
+                        self.put_pixel(visible, index % width, index / width, pixel);
+                    }
+
+                    self.return_decode_buffer(data);
+                }
+            },
+            GRADIENT_FILTER => {
+                if pixel_size != 3 {
+                    return Err(tight_error("Tight gradient filter is only supported for 24 bit color".to_string()));
+                }
+
+                let data = self.read_tight_data(stream_id, width * height * 3).await?;
+                let mut previous_row = vec![[0u8; 3]; width];
+
+                // Each component is predicted from its left, upper and upper left neighbours, the data is the difference
+                for y in 0..height {
+                    let mut row = vec![[0u8; 3]; width];
+
+                    for x in 0..width {
+                        for component in 0..3 {
+                            let left = if x > 0 { row[x - 1][component] as i32 } else { 0 };
+                            let up = previous_row[x][component] as i32;
+                            let up_left = if x > 0 { previous_row[x - 1][component] as i32 } else { 0 };
+                            let predicted = (left + up - up_left).clamp(0, 255) as u8;
+
+                            row[x][component] = predicted.wrapping_add(data[(y * width + x) * 3 + component]);
+                        }
+
+                        let pixel = self.tight_to_device_pixel(&row[x]);
+                        self.put_pixel(visible, x, y, pixel);
+                    }
+
+                    previous_row = row;
+                }
+
+                self.return_decode_buffer(data);
+            },
+            filter => return Err(tight_error(format!("Invalid Tight filter {}", filter))),
+        }
+
+        Ok(())
+    }
+
+    // JPEG compression: a JPEG image of the rectangle, converted to device pixels through the color adjustment.
+    // The compressed data (at most 4 MB, see read_compact_length) is read into the decode buffer, and the image size
+    // is checked against the rectangle before the image is decoded
+    async fn decode_tight_jpeg(&mut self, rect: &Rect, visible: &Rect) -> Result<(), RfbSessionError> {
+        let length = self.read_compact_length().await?;
+        let mut jpeg_data = self.take_decode_buffer(length);
+
+        self.read_with_timeout(jpeg_data.as_mut_slice()).await?;
+
+        let mut decoder = jpeg_decoder::Decoder::new(&jpeg_data[..]);
+        decoder.read_info().map_err(|e| tight_error(format!("Invalid Tight JPEG header: {}", e)))?;
+        let info = decoder.info().ok_or_else(|| tight_error("Tight JPEG without image info".to_string()))?;
+
+        if info.width != rect.size.width || info.height != rect.size.height {
+            return Err(tight_error(format!("Tight JPEG is {}x{}, rectangle is {:?}", info.width, info.height, rect.size)));
+        }
+
+        let image = decoder.decode().map_err(|e| tight_error(format!("Invalid Tight JPEG data: {}", e)))?;
+        self.return_decode_buffer(jpeg_data);
+
+        let bytes_per_pixel = match info.pixel_format {
+            jpeg_decoder::PixelFormat::RGB24 => 3,
+            jpeg_decoder::PixelFormat::L8 => 1,
+            pixel_format => return Err(tight_error(format!("Unsupported Tight JPEG pixel format {:?}", pixel_format))),
+        };
+
+        let width = info.width as usize;
+
+        for (index, jpeg_pixel) in image.chunks(bytes_per_pixel).enumerate() {
+            let (r, g, b) = if bytes_per_pixel == 3 { (jpeg_pixel[0], jpeg_pixel[1], jpeg_pixel[2]) } else { (jpeg_pixel[0], jpeg_pixel[0], jpeg_pixel[0]) };
+            let pixel = self.screen.color_adjustment.to_device_pixel(r, g, b);
+
+            self.put_pixel(visible, index % width, index / width, pixel);
+        }
+
+        Ok(())
+    }
+
+    // Data shorter than MIN_TO_COMPRESS bytes is sent as is, longer data is compressed on the given zlib stream. The
+    // compressed data is read and inflated a chunk at a time, the result is returned in the decode buffer
+    async fn read_tight_data(&mut self, stream_id: usize, size: usize) -> Result<Vec<u8>, RfbSessionError> {
+        if size > MAX_RAW_RECT_SIZE {
+            return Err(tight_error(format!("Tight rectangle data of {} bytes is too large", size)));
+        }
+
+        let mut data = self.take_decode_buffer(size);
+
+        if size < MIN_TO_COMPRESS {
+            self.read_with_timeout(data.as_mut_slice()).await?;
+            return Ok(data);
+        }
+
+        let mut remaining = self.read_compact_length().await?;
+        let mut chunk = std::mem::take(&mut self.tight.compressed_chunk);
+        let mut produced = 0;
+
+        while remaining > 0 {
+            chunk.resize(remaining.min(COMPRESSED_CHUNK_SIZE), 0);
+            self.read_with_timeout(chunk.as_mut_slice()).await?;
+            remaining -= chunk.len();
+
+            inflate_chunk(&mut self.tight.streams[stream_id], &chunk, &mut data, &mut produced)
+                .map_err(|reason| tight_error(format!("Tight zlib stream {}: {}", stream_id, reason)))?;
+        }
+
+        self.tight.compressed_chunk = chunk;
+
+        if produced < size {
+            return Err(tight_error(format!("Tight zlib stream {} produced {} of {} bytes", stream_id, produced, size)));
+        }
+
+        Ok(data)
+    }
+
+    // Length of 1 to 3 bytes, 7 bits per byte (least significant first) with the high bit set if more bytes follow
+    async fn read_compact_length(&mut self) -> Result<usize, RfbSessionError> {
+        let mut length = 0;
+
+        for byte_index in 0..3 {
+            let byte = self.read_u8().await? as usize;
+
+            if byte_index == 2 {
+                length |= byte << 14;
+                break;
+            }
+
+            length |= (byte & 0x7f) << (7 * byte_index);
+
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        Ok(length)
+    }
+
+    // 24 bit true color server pixels are sent as 3 bytes (red, green, blue), others in the server pixel format
+    fn tight_pixel_size(&self) -> Result<usize, RfbSessionError> {
+        let pf = self.get_server_pixel_format()?;
+
+        if pf.bits_per_pixel == 32 && pf.depth == 24 && pf.red_max == 255 && pf.green_max == 255 && pf.blue_max == 255 {
+            Ok(3)
+        } else {
+            self.bytes_per_server_pixel()
+        }
+    }
+
+    async fn read_tight_pixel(&mut self) -> Result<DevicePixel, RfbSessionError> {
+        let mut tight_pixel = vec![0; self.tight_pixel_size()?];
+
+        self.read_with_timeout(tight_pixel.as_mut_slice()).await?;
+        Ok(self.tight_to_device_pixel(&tight_pixel))
+    }
+
+    fn tight_to_device_pixel(&self, tight_pixel: &[u8]) -> DevicePixel {
+        if tight_pixel.len() == 3 {
+            self.screen.color_adjustment.to_device_pixel(tight_pixel[0], tight_pixel[1], tight_pixel[2])
+        } else {
+            self.to_device_pixel(tight_pixel)
+        }
+    }
+
+    // Pixels outside the visible part of the rectangle are dropped
+    fn put_pixel(&mut self, visible: &Rect, x: usize, y: usize, pixel: DevicePixel) {
+        if x >= visible.size.width as usize || y >= visible.size.height as usize {
+            return;
+        }
+
+        let offset = (visible.location.y as usize + y) * self.screen.bytes_per_row() + (visible.location.x as usize + x) * Screen::bytes_per_pixel();
+
+        self.screen.set_at_offset(offset, pixel);
+    }
+}
+
+// Inflate all of `input` into `data` after the `produced` bytes already there. The server ends each rectangle's data
+// with a sync flush, which has to be consumed too so the stream stays in step for the next rectangle
+fn inflate_chunk(stream: &mut Decompress, input: &[u8], data: &mut [u8], produced: &mut usize) -> Result<(), String> {
+    let mut consumed = 0;
+    let mut overflow = [0u8; 64];
+
+    while consumed < input.len() {
+        let in_before = stream.total_in();
+        let out_before = stream.total_out();
+        let data_full = *produced >= data.len();
+        let output = if data_full { &mut overflow[..] } else { &mut data[*produced..] };
+
+        stream.decompress(&input[consumed..], output, FlushDecompress::Sync).map_err(|e| e.to_string())?;
+
+        let consumed_now = (stream.total_in() - in_before) as usize;
+        let produced_now = (stream.total_out() - out_before) as usize;
+
+        if data_full && produced_now > 0 {
+            return Err(format!("more than the expected {} bytes", data.len()));
+        }
+
+        if consumed_now == 0 && produced_now == 0 {
+            return Err(format!("stalled after {} of {} bytes", produced, data.len()));
+        }
+
+        consumed += consumed_now;
+        *produced += produced_now;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compress, Compression, FlushCompress};
+
+    // Compressed the way a Tight server does it: one zlib stream for the session, each rectangle ends with a sync flush
+    fn compress_rect(stream: &mut Compress, data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::with_capacity(data.len() * 2 + 64);
+
+        stream.compress_vec(data, &mut compressed, FlushCompress::Sync).unwrap();
+        compressed
+    }
+
+    fn inflate_in_chunks(stream: &mut Decompress, compressed: &[u8], chunk_size: usize, size: usize) -> Result<Vec<u8>, String> {
+        let mut data = vec![0; size];
+        let mut produced = 0;
+
+        for chunk in compressed.chunks(chunk_size) {
+            inflate_chunk(stream, chunk, &mut data, &mut produced)?;
+        }
+
+        if produced < size {
+            return Err(format!("produced {} of {} bytes", produced, size));
+        }
+
+        Ok(data)
+    }
+
+    fn rect_pixels(seed: u8, size: usize) -> Vec<u8> {
+        (0..size).map(|index| (index as u8).wrapping_mul(seed) ^ (index / 97) as u8).collect()
+    }
+
+    #[test]
+    fn rectangle_split_over_chunks() {
+        let pixels = rect_pixels(7, 40 * 30 * 3);
+        let compressed = compress_rect(&mut Compress::new(Compression::default(), true), &pixels);
+
+        for chunk_size in [1, 5, 64, compressed.len()] {
+            let data = inflate_in_chunks(&mut Decompress::new(true), &compressed, chunk_size, pixels.len()).unwrap();
+
+            assert_eq!(data, pixels, "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn stream_continues_over_rectangles() {
+        let mut compress = Compress::new(Compression::default(), true);
+        let mut decompress = Decompress::new(true);
+
+        for seed in [3, 11, 3] {
+            let pixels = rect_pixels(seed, 16 * 16 * 3);
+            let compressed = compress_rect(&mut compress, &pixels);
+
+            assert_eq!(inflate_in_chunks(&mut decompress, &compressed, 10, pixels.len()).unwrap(), pixels);
+        }
+    }
+
+    #[test]
+    fn more_data_than_the_rectangle() {
+        let pixels = rect_pixels(5, 1000);
+        let compressed = compress_rect(&mut Compress::new(Compression::default(), true), &pixels);
+
+        assert!(inflate_in_chunks(&mut Decompress::new(true), &compressed, 64, 500).is_err());
+    }
+
+    #[test]
+    fn less_data_than_the_rectangle() {
+        let pixels = rect_pixels(5, 1000);
+        let compressed = compress_rect(&mut Compress::new(Compression::default(), true), &pixels);
+
+        assert!(inflate_in_chunks(&mut Decompress::new(true), &compressed, 64, 2000).is_err());
+    }
+
+    #[test]
+    fn corrupt_data() {
+        assert!(inflate_in_chunks(&mut Decompress::new(true), &[0xff; 32], 8, 100).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use super::rfb_messages::{Point, Rect, Size};
+
+// HexTile geometry kept free of the decoder, so the bounds math can be checked on its own. All sums are done in u32,
+// a hostile rectangle or subrect must not wrap around u16
+
+pub const TILE_SIZE: u16 = 16;
+
+// Tiles of a HexTile rectangle, left to right and top to bottom. Tiles are 16x16 except in the last column and row
+// of a rectangle whose size is not a multiple of 16
+pub fn tiles(rect: &Rect) -> impl Iterator<Item = Rect> {
+    let rect = *rect;
+    let h_tile_count = rect.size.width.div_ceil(TILE_SIZE);
+    let v_tile_count = rect.size.height.div_ceil(TILE_SIZE);
+
+    (0..v_tile_count).flat_map(move |v_tile| (0..h_tile_count).map(move |h_tile| {
+        let x_offset = h_tile * TILE_SIZE;
+        let y_offset = v_tile * TILE_SIZE;
+
+        Rect {
+            location: Point { x: rect.location.x.saturating_add(x_offset), y: rect.location.y.saturating_add(y_offset) },
+            size: Size { width: TILE_SIZE.min(rect.size.width - x_offset), height: TILE_SIZE.min(rect.size.height - y_offset) },
+        }
+    }))
+}
+
+// The part of a subrect (relative to its tile) inside the tile, None if nothing of it is left
+pub fn clamp_subrect(tile_size: Size, subrect: &Rect) -> Option<Rect> {
+    clamp(subrect, tile_size.width as u32, tile_size.height as u32)
+}
+
+// The part of a rectangle inside the screen, None if it is entirely outside
+pub fn clamp_to_screen(rect: &Rect, screen_width: usize, screen_height: usize) -> Option<Rect> {
+    clamp(rect, screen_width.min(u16::MAX as usize) as u32, screen_height.min(u16::MAX as usize) as u32)
+}
+
+// Rectangle (relative to its tile) translated to screen coordinates
+pub fn tile_to_screen(tile_rect: &Rect, subrect: &Rect) -> Rect {
+    Rect {
+        location: Point {
+            x: tile_rect.location.x.saturating_add(subrect.location.x),
+            y: tile_rect.location.y.saturating_add(subrect.location.y),
+        },
+        size: subrect.size,
+    }
+}
+
+fn clamp(rect: &Rect, width: u32, height: u32) -> Option<Rect> {
+    let (x, y) = (rect.location.x as u32, rect.location.y as u32);
+    let right = (x + rect.size.width as u32).min(width);
+    let bottom = (y + rect.size.height as u32).min(height);
+
+    if x >= right || y >= bottom {
+        return None;
+    }
+
+    Some(Rect {
+        location: rect.location,
+        size: Size { width: (right - x) as u16, height: (bottom - y) as u16 },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: u16, y: u16, width: u16, height: u16) -> Rect {
+        Rect { location: Point { x, y }, size: Size { width, height } }
+    }
+
+    #[test]
+    fn tiles_cover_the_rectangle() {
+        let tiles: Vec<Rect> = tiles(&rect(10, 20, 40, 20)).collect();
+
+        assert_eq!(tiles, vec![
+            rect(10, 20, 16, 16), rect(26, 20, 16, 16), rect(42, 20, 8, 16),
+            rect(10, 36, 16, 4), rect(26, 36, 16, 4), rect(42, 36, 8, 4),
+        ]);
+    }
+
+    #[test]
+    fn empty_rectangle_has_no_tiles() {
+        assert_eq!(tiles(&rect(0, 0, 0, 16)).count(), 0);
+        assert_eq!(tiles(&rect(0, 0, 16, 0)).count(), 0);
+    }
+
+    #[test]
+    fn tiles_of_a_huge_rectangle_do_not_overflow() {
+        let last = tiles(&rect(u16::MAX, u16::MAX, u16::MAX, u16::MAX)).last().unwrap();
+
+        assert_eq!(last, rect(u16::MAX, u16::MAX, 15, 15));
+        assert_eq!(tiles(&rect(0, 0, u16::MAX, 16)).count(), 4096);
+    }
+
+    #[test]
+    fn subrect_is_clamped_to_its_tile() {
+        let tile = Size { width: 16, height: 8 };
+
+        assert_eq!(clamp_subrect(tile, &rect(2, 3, 4, 4)), Some(rect(2, 3, 4, 4)));
+        assert_eq!(clamp_subrect(tile, &rect(12, 6, 10, 10)), Some(rect(12, 6, 4, 2)));
+        assert_eq!(clamp_subrect(tile, &rect(16, 0, 1, 1)), None);
+        assert_eq!(clamp_subrect(tile, &rect(0, 0, 0, 4)), None);
+        assert_eq!(clamp_subrect(tile, &rect(u16::MAX, u16::MAX, u16::MAX, u16::MAX)), None);
+    }
+
+    #[test]
+    fn rectangle_is_clamped_to_the_screen() {
+        assert_eq!(clamp_to_screen(&rect(790, 470, 20, 20), 800, 480), Some(rect(790, 470, 10, 10)));
+        assert_eq!(clamp_to_screen(&rect(800, 0, 20, 20), 800, 480), None);
+        assert_eq!(clamp_to_screen(&rect(0, 0, u16::MAX, u16::MAX), usize::MAX, usize::MAX), Some(rect(0, 0, u16::MAX, u16::MAX)));
+    }
+
+    #[test]
+    fn subrect_is_translated_to_screen() {
+        assert_eq!(tile_to_screen(&rect(32, 48, 16, 16), &rect(3, 5, 2, 2)), rect(35, 53, 2, 2));
+        assert_eq!(tile_to_screen(&rect(u16::MAX - 1, 0, 16, 16), &rect(4, 0, 1, 1)).location, Point { x: u16::MAX, y: 0 });
+    }
+}
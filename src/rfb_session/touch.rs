@@ -3,13 +3,15 @@ use super::rfb_messages::{
     ToServerMessage,
     PointerEventArgs,
     Point,
+    Rect,
 };
 
 use tokio::sync::mpsc::Sender;
-use tokio::sync::oneshot;
+use tokio::sync::watch;
 
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::fs::{
+    File,
     OpenOptions
 };
 use tokio_fd::AsyncFd;
@@ -22,6 +24,9 @@ use super::{
 };
 
 use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::night::NightMode;
 
 #[repr(C)]
 #[derive(Debug)]
@@ -43,56 +48,535 @@ impl InputEvent {
             value: i32::from_ne_bytes(buffer[12..16].try_into().unwrap()),
         }
     }
+
+    fn time(&self) -> Duration {
+        Duration::new(self.seconds as u64, 0) + Duration::from_micros(self.micro_seconds as u64)
+    }
+}
+
+// Which axes carry the touch location: multitouch ABS_MT_POSITION_X/Y or single-touch ABS_X/ABS_Y (e.g. resistive
+// panels). Auto selects by the axes the device advertises
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TouchProtocol {
+    #[default]
+    Auto,
+    MultiTouch,
+    SingleTouch,
+}
+
+impl TouchProtocol {
+    pub fn parse(value: &str) -> Result<TouchProtocol, String> {
+        match value {
+            "auto" => Ok(TouchProtocol::Auto),
+            "mt" => Ok(TouchProtocol::MultiTouch),
+            "st" => Ok(TouchProtocol::SingleTouch),
+            _ => Err(format!("Invalid touch protocol '{}' (must be mt, st or auto)", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TouchOptions {
+    // For devices reporting pressure but no reliable BTN_TOUCH: pressure above the threshold is a touch
+    pub pressure_threshold: Option<i32>,
+    pub verbose: bool,          // Log raw input events and the pointer events sent to the server
+    pub protocol: TouchProtocol,
+    pub moves: bool,                    // Send the moves of a touch, not only its press and release
+    pub tap_delay: Option<Duration>,    // Minimum time between the press and release of a tap (for servers that debounce clicks)
+    pub touch_device: bool,             // False on displays without touch hardware, nothing is opened or retried
+    pub button_device: Option<String>,  // Input device with physical buttons (e.g. gpio-keys)
+    pub button_map: Vec<(u16, u8)>,     // Key code of a physical button and the pointer button mask bit it sends
+}
+
+// Where touches are delivered: the currently active session, if any (one per region with --split)
+#[derive(Debug)]
+struct TouchTarget {
+    sender: Sender<ToServerMessage>,
+    pointer_enabled: watch::Receiver<bool>,
+    region: Option<Rect>,
+}
+
+// The input subsystem lives for the whole application, independently of the RFB sessions. It keeps (re)opening
+// the touch device, so a touchscreen that shows up late or is reconnected starts working without a new session
+#[derive(Debug, Clone)]
+pub struct TouchInput {
+    target: Arc<Mutex<Vec<TouchTarget>>>,
+    last_location: Arc<Mutex<Point>>,       // Physical button events are sent where the panel was last touched
+    activity: Arc<watch::Sender<Instant>>,  // When the panel was last touched (or a button pressed), with or without a session
+    region: Option<Rect>,                   // Part of the panel delivered to the sessions attached by this view, all of it when None
 }
 
-pub async fn run(stop: oneshot::Receiver<bool>, output_sender: Sender<ToServerMessage>) {
-    let _ = handle_input(stop, output_sender).await;
+impl Default for TouchInput {
+    fn default() -> Self {
+        TouchInput {
+            target: Default::default(),
+            last_location: Default::default(),
+            activity: Arc::new(watch::channel(Instant::now()).0),
+            region: None,
+        }
+    }
+}
+
+// Touches are delivered to the session until this is dropped
+pub struct TouchAttachment {
+    target: Arc<Mutex<Vec<TouchTarget>>>,
+    region: Option<Rect>,
+}
+
+impl Drop for TouchAttachment {
+    fn drop(&mut self) {
+        self.target.lock().unwrap().retain(|target| target.region != self.region);
+    }
+}
+
+impl TouchInput {
+    pub fn start(night_mode: Option<Arc<NightMode>>, options: TouchOptions) -> TouchInput {
+        let touch_input = TouchInput::default();
+
+        if let Some(button_device) = options.button_device.clone() {
+            let touch_input = touch_input.clone();
+            let night_mode = night_mode.clone();
+            let options = options.clone();
+
+            tokio::spawn(async move { handle_buttons(touch_input, &button_device, night_mode, options).await });
+        }
+
+        if options.touch_device {
+            let input = touch_input.clone();
+            tokio::spawn(async move { handle_input(input, night_mode, options).await });
+        }
+
+        touch_input
+    }
+
+    // View of the input for a session showing one region of a split panel: the sessions it attaches only get the
+    // touches starting inside the region, in the region coordinates
+    pub fn for_region(&self, region: Rect) -> TouchInput {
+        TouchInput { region: Some(region), ..self.clone() }
+    }
+
+    // Where the panel was last touched
+    pub fn last_location(&self) -> Point {
+        *self.last_location.lock().unwrap()
+    }
+
+    // Deliver a message from another input source (e.g. the control socket) to the active session. Returns false
+    // if no session is accepting input
+    pub async fn inject(&self, mut message: ToServerMessage) -> bool {
+        let route_at = match message {
+            ToServerMessage::PointerEvent(PointerEventArgs{location, ..}) => location,
+            _ => Point::default(),
+        };
+
+        match session_sender(&self.target, route_at, route_at) {
+            Some((sender, location)) => {
+                if let ToServerMessage::PointerEvent(ref mut pointer_event) = message {
+                    pointer_event.location = location;
+                }
+
+                sender.send(message).await.is_ok()
+            },
+            None => false,
+        }
+    }
+
+    pub fn attach(&self, sender: Sender<ToServerMessage>, pointer_enabled: watch::Receiver<bool>) -> TouchAttachment {
+        let mut targets = self.target.lock().unwrap();
+
+        targets.retain(|target| target.region != self.region);
+        targets.push(TouchTarget { sender, pointer_enabled, region: self.region });
+        TouchAttachment { target: self.target.clone(), region: self.region }
+    }
+
+    // Changes whenever the user touches the panel or presses a button, also while no session is active
+    pub fn subscribe_activity(&self) -> watch::Receiver<Instant> {
+        self.activity.subscribe()
+    }
+
+    // Also called for input from the control socket, so scripted use keeps the session from idling out
+    pub fn touched(&self) {
+        self.activity.send_replace(Instant::now());
+    }
 }
 
 const EVENTS_BUFFER_SIZE: usize = 64 * mem::size_of::<InputEvent>();
+const EV_SYN:u16 = 0;
 const EV_ABS:u16 = 3;
 const EV_KEY:u16 = 1;
 
+const CODE_SYN_REPORT:u16 = 0;
+
 const CODE_ABS_X:u16 = 0;
 const CODE_ABS_Y:u16 = 1;
+const CODE_ABS_PRESSURE:u16 = 24;
+const CODE_ABS_MT_PRESSURE:u16 = 58;
 const CODE_ABS_MT_POSITION_X:u16 = 53;
 const CODE_ABS_MT_POSITION_Y:u16 = 54;
 const CODE_BTN_TOUCH:u16 = 330;
 
-#[allow(unused_variables)]
-async fn handle_input(stop_rx: oneshot::Receiver<bool>, output_sender: Sender<ToServerMessage>) -> Result<(), RfbSessionError> {
-    //let input_device = "/dev/input/by-path/platform-soc:firmware:touchscreen-event";
-    let input_device_name = "/dev/input/event0";
-    let events_input_file = OpenOptions::new().read(true).open(input_device_name).await.unwrap();
-    let mut events_input = AsyncFd::try_from(events_input_file.as_raw_fd())?;
+pub const INPUT_DEVICE_NAME: &str = "/dev/input/event0";
+const DEVICE_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+// Reads whole input events. A read may end in the middle of an event, its bytes are kept and completed by the
+// next read, so the following events stay aligned
+struct EventReader<R: AsyncRead + Unpin> {
+    input: R,
+    pending: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> EventReader<R> {
+    fn new(input: R) -> EventReader<R> {
+        EventReader { input, pending: Vec::new() }
+    }
+
+    async fn read_events(&mut self) -> Result<Vec<InputEvent>, RfbSessionError> {
+        let mut input_buffer: [u8; EVENTS_BUFFER_SIZE] = [0; EVENTS_BUFFER_SIZE];
+
+        let bytes_read = self.input.read(&mut input_buffer[..]).await?;
+        if bytes_read == 0 {
+            return Err(RfbSessionError(RfbSessionErrorKind::SessionClosedByServer));
+        }
+
+        self.pending.extend_from_slice(&input_buffer[..bytes_read]);
+
+        let complete_bytes = self.pending.len() / mem::size_of::<InputEvent>() * mem::size_of::<InputEvent>();
+        let events = self.pending[..complete_bytes].chunks(mem::size_of::<InputEvent>()).map(InputEvent::from_buffer).collect();
+
+        self.pending.drain(..complete_bytes);
+        Ok(events)
+    }
+}
+
+// Open an input device, waiting for it to show up (devices may enumerate late during boot)
+async fn open_input_device(device_name: &str, kind: &str) -> File {
+    let mut device_missing_reported = false;
+
+    loop {
+        match OpenOptions::new().read(true).open(device_name).await {
+            Ok(file) => {
+                println!("{} device {} opened", kind, device_name);
+                return file;
+            },
+            Err(e) => {
+                if !device_missing_reported {
+                    println!("Cannot open {} device {}: {} - will keep trying", kind.to_lowercase(), device_name, e);
+                    device_missing_reported = true;
+                }
+                tokio::time::sleep(DEVICE_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+// EVIOCGBIT(EV_ABS): bitmap of the absolute axes the device reports
+fn absolute_axes(events_input_file: &File) -> Option<[u8; 8]> {
+    let mut axes = [0u8; 8];
+    let request = (2 << 30) | ((axes.len() as u32) << 16) | ((b'E' as u32) << 8) | (0x20 + EV_ABS as u32);
+
+    match unsafe { libc::ioctl(events_input_file.as_raw_fd(), request as _, axes.as_mut_ptr()) } {
+        result if result < 0 => None,
+        _ => Some(axes),
+    }
+}
+
+// Resolve the auto protocol: multitouch if the device advertises ABS_MT_POSITION_X, otherwise single-touch if it
+// advertises ABS_X. Multitouch if the axes cannot be queried
+fn select_protocol(events_input_file: &File, protocol: TouchProtocol) -> TouchProtocol {
+    if protocol != TouchProtocol::Auto {
+        return protocol;
+    }
+
+    let has_axis = |axes: &[u8; 8], code: u16| axes[code as usize / 8] & (1 << (code % 8)) != 0;
+
+    let selected = match absolute_axes(events_input_file) {
+        Some(axes) if has_axis(&axes, CODE_ABS_MT_POSITION_X) => TouchProtocol::MultiTouch,
+        Some(axes) if has_axis(&axes, CODE_ABS_X) => TouchProtocol::SingleTouch,
+        _ => TouchProtocol::MultiTouch,
+    };
+
+    println!("Touch protocol: {:?}", selected);
+    selected
+}
+
+// Sender of the session owning the panel at `route_at` if input should be delivered to it now, and `location`
+// in that session's coordinates. A location outside the region (e.g. a drag that left it) is moved to its edge
+fn session_sender(target: &Mutex<Vec<TouchTarget>>, route_at: Point, location: Point) -> Option<(Sender<ToServerMessage>, Point)> {
+    let targets = target.lock().unwrap();
+    let target = targets.iter().find(|target| match target.region {
+        Some(region) => region_contains(&region, route_at),
+        None => true,
+    })?;
+
+    if !*target.pointer_enabled.borrow() {
+        return None;
+    }
+
+    let location = match target.region {
+        Some(region) => Point {
+            x: location.x.clamp(region.location.x, region.location.x + region.size.width.saturating_sub(1)) - region.location.x,
+            y: location.y.clamp(region.location.y, region.location.y + region.size.height.saturating_sub(1)) - region.location.y,
+        },
+        None => location,
+    };
+
+    Some((target.sender.clone(), location))
+}
+
+fn region_contains(region: &Rect, point: Point) -> bool {
+    (point.x as u32) >= region.location.x as u32 && (point.x as u32) < region.location.x as u32 + region.size.width as u32 &&
+        (point.y as u32) >= region.location.y as u32 && (point.y as u32) < region.location.y as u32 + region.size.height as u32
+}
+
+async fn handle_input(touch_input: TouchInput, night_mode: Option<Arc<NightMode>>, options: TouchOptions) {
+    loop {
+        let events_input_file = open_input_device(INPUT_DEVICE_NAME, "Touch").await;
+
+        if let Err(e) = read_device(&events_input_file, &touch_input, &night_mode, &options).await {
+            println!("Touch device {} failed: {:?}", INPUT_DEVICE_NAME, e);
+        }
+
+        tokio::time::sleep(DEVICE_RETRY_INTERVAL).await;
+    }
+}
+
+// Physical buttons (e.g. back/forward wired to GPIO) send pointer events with their mapped button mask bits. The
+// extended bits (0x20, 0x40...) are not supported by every server
+async fn handle_buttons(touch_input: TouchInput, button_device: &str, night_mode: Option<Arc<NightMode>>, options: TouchOptions) {
+    loop {
+        let events_input_file = open_input_device(button_device, "Button").await;
+
+        if let Err(e) = read_buttons(&events_input_file, &touch_input, &night_mode, &options).await {
+            println!("Button device {} failed: {:?}", button_device, e);
+        }
+
+        tokio::time::sleep(DEVICE_RETRY_INTERVAL).await;
+    }
+}
+
+async fn read_buttons(events_input_file: &File, touch_input: &TouchInput, night_mode: &Option<Arc<NightMode>>, options: &TouchOptions) -> Result<(), RfbSessionError> {
+    let mut events_input = EventReader::new(AsyncFd::try_from(events_input_file.as_raw_fd())?);
+
+    loop {
+        for the_event in events_input.read_events().await? {
+            // Key press (1) and release (0), auto repeat (2) is ignored
+            if the_event.event_type != EV_KEY || the_event.value > 1 {
+                continue;
+            }
+
+            let button_bit = match options.button_map.iter().find(|(code, _)| *code == the_event.code) {
+                Some((_, button_bit)) => *button_bit,
+                None => continue,
+            };
+
+            if the_event.value == 1 {
+                touch_input.touched();
+            }
+
+            // A button pressed while the display is off only wakes it
+            if the_event.value == 1 && night_mode.as_ref().is_some_and(|night_mode| night_mode.touched()) {
+                continue;
+            }
+
+            let last_location = *touch_input.last_location.lock().unwrap();
+
+            if let Some((sender, location)) = session_sender(&touch_input.target, last_location, last_location) {
+                let button_mask = if the_event.value == 1 { button_bit } else { 0 };
+
+                if options.verbose {
+                    println!("Button {}: pointer event mask {:#x} at ({}, {})", the_event.code, button_mask, location.x, location.y);
+                }
+
+                let _ = sender.send(ToServerMessage::PointerEvent(PointerEventArgs{button_mask, location, timestamp: Some(the_event.time())})).await;
+            }
+        }
+    }
+}
+
+async fn read_device(events_input_file: &File, touch_input: &TouchInput, night_mode: &Option<Arc<NightMode>>, options: &TouchOptions) -> Result<(), RfbSessionError> {
+    let mut events_input = EventReader::new(AsyncFd::try_from(events_input_file.as_raw_fd())?);
     let mut x:u16 = 0;
     let mut y:u16 = 0;
+    let mut touching = false;
+    let mut pressure_reported = false;  // Once the device reported pressure, BTN_TOUCH is ignored
+    let mut swallow_touch = false;      // The current touch woke the display, so it is not delivered to the server
+    let multi_touch = select_protocol(events_input_file, options.protocol) == TouchProtocol::MultiTouch;
+    let mut press_sent_at: Option<Instant> = None;
+    let mut sent_location = Point{x, y};    // Where the last pointer event was sent, moves are sent once per report
+    let mut touch_origin = Point{x, y};     // Where the current touch started, its events go to the session shown there
+
+    loop {
+        for the_event in events_input.read_events().await? {
+            let mut pressed: Option<bool> = None;
+
+            if options.verbose {
+                println!("Input event: type {} code {} value {}", the_event.event_type, the_event.code, the_event.value);
+            }
+
+            match the_event {
+                InputEvent{event_type: EV_ABS, code: CODE_ABS_MT_POSITION_X, value, ..} if multi_touch => x = value as u16,
+                InputEvent{event_type: EV_ABS, code: CODE_ABS_MT_POSITION_Y, value, ..} if multi_touch => y = value as u16,
+                InputEvent{event_type: EV_ABS, code: CODE_ABS_X, value, ..} if !multi_touch => x = value as u16,
+                InputEvent{event_type: EV_ABS, code: CODE_ABS_Y, value, ..} if !multi_touch => y = value as u16,
+                InputEvent{event_type: EV_ABS, code: CODE_ABS_MT_PRESSURE | CODE_ABS_PRESSURE, value, ..} => {
+                    if let Some(threshold) = options.pressure_threshold {
+                        pressure_reported = true;
+                        pressed = Some(value > threshold);
+                    }
+                },
+                InputEvent{event_type: EV_KEY, code: CODE_BTN_TOUCH, value: 1, ..} if !pressure_reported => pressed = Some(true),
+                InputEvent{event_type: EV_KEY, code: CODE_BTN_TOUCH, value: 0, ..} if !pressure_reported => pressed = Some(false),
+                _ => ()
+            }
 
-    let result =tokio::select! {
-        _ = stop_rx => Err(RfbSessionError(RfbSessionErrorKind::SessionClosedByServer)),
-        _ = async {
-            loop {
-                let mut input_buffer: [u8; EVENTS_BUFFER_SIZE] = [0; EVENTS_BUFFER_SIZE];
-
-                let bytes_read = events_input.read(&mut input_buffer[..]).await.unwrap();
-                let events_count = bytes_read / mem::size_of::<InputEvent>();
-                
-                for event_index in 0..events_count {
-                    let the_event = InputEvent::from_buffer(&input_buffer[event_index*mem::size_of::<InputEvent>()..]);
-
-                    match the_event {
-                        InputEvent{event_type: EV_ABS, code: CODE_ABS_MT_POSITION_X, value, ..} => x = value as u16,
-                        InputEvent{event_type: EV_ABS, code: CODE_ABS_MT_POSITION_Y, value, ..} => y = value as u16,
-                        InputEvent{event_type: EV_KEY, code: CODE_BTN_TOUCH, value: 1, ..} => 
-                            output_sender.send(ToServerMessage::PointerEvent(PointerEventArgs{button_mask:1, location: Point{x, y}})).await.unwrap(),
-                        InputEvent{event_type: EV_KEY, code: CODE_BTN_TOUCH, value: 0, ..} => 
-                            output_sender.send(ToServerMessage::PointerEvent(PointerEventArgs{button_mask:0, location: Point{x, y}})).await.unwrap(),
-                        _ => ()
+            // With --touch-moves, each input report at a new location while touching is sent as a move (e.g. the points
+            // of a swipe)
+            if options.moves && the_event.event_type == EV_SYN && the_event.code == CODE_SYN_REPORT && touching && !swallow_touch && (sent_location.x, sent_location.y) != (x, y) {
+                if let Some((sender, location)) = session_sender(&touch_input.target, touch_origin, Point{x, y}) {
+                    if options.verbose {
+                        println!("Pointer event: move to ({}, {})", x, y);
                     }
+
+                    sent_location = Point{x, y};
+                    *touch_input.last_location.lock().unwrap() = sent_location;
+                    let _ = sender.send(ToServerMessage::PointerEvent(PointerEventArgs{button_mask: 1, location, timestamp: Some(the_event.time())})).await;
                 }
             }
-        } => Err(RfbSessionError(RfbSessionErrorKind::SessionClosedByServer))
-    };
-    
-    result
+
+            if let Some(pressed) = pressed.filter(|pressed| *pressed != touching) {
+                touching = pressed;
+
+                if pressed {
+                    touch_input.touched();
+                    swallow_touch = night_mode.as_ref().is_some_and(|night_mode| night_mode.touched());
+                    touch_origin = Point{x, y};
+                }
+
+                *touch_input.last_location.lock().unwrap() = Point{x, y};
+
+                // Touches while no session is active (or before its frames are flowing) are dropped
+                let sender = if swallow_touch { None } else { session_sender(&touch_input.target, touch_origin, Point{x, y}) };
+
+                if let Some((sender, location)) = sender {
+                    let button_mask = if pressed { 1 } else { 0 };
+
+                    // A release following the press (almost) instantly is held back, so the server sees a click
+                    if let (false, Some(tap_delay), Some(press_sent_at)) = (pressed, options.tap_delay, press_sent_at) {
+                        if let Some(remaining) = tap_delay.checked_sub(press_sent_at.elapsed()) {
+                            tokio::time::sleep(remaining).await;
+                        }
+                    }
+
+                    press_sent_at = if pressed { Some(Instant::now()) } else { None };
+
+                    if options.verbose {
+                        println!("Pointer event: mask {} at ({}, {})", button_mask, x, y);
+                    }
+
+                    sent_location = Point{x, y};
+                    let _ = sender.send(ToServerMessage::PointerEvent(PointerEventArgs{button_mask, location, timestamp: Some(the_event.time())})).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    // Input device returning the given chunks, one per read, then end of file
+    struct ChunkedReader(VecDeque<Vec<u8>>);
+
+    impl AsyncRead for ChunkedReader {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            if let Some(chunk) = self.0.pop_front() {
+                buf.put_slice(&chunk);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn event_bytes(event_type: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&12i32.to_ne_bytes());
+        bytes.extend_from_slice(&345i32.to_ne_bytes());
+        bytes.extend_from_slice(&event_type.to_ne_bytes());
+        bytes.extend_from_slice(&code.to_ne_bytes());
+        bytes.extend_from_slice(&value.to_ne_bytes());
+        assert_eq!(bytes.len(), mem::size_of::<InputEvent>());
+        bytes
+    }
+
+    fn summary(events: &[InputEvent]) -> Vec<(u16, u16, i32)> {
+        events.iter().map(|event| (event.event_type, event.code, event.value)).collect()
+    }
+
+    #[tokio::test]
+    async fn event_split_across_reads_is_reassembled() {
+        let x = event_bytes(EV_ABS, CODE_ABS_MT_POSITION_X, 400);
+        let y = event_bytes(EV_ABS, CODE_ABS_MT_POSITION_Y, 240);
+        let syn = event_bytes(EV_SYN, CODE_SYN_REPORT, 0);
+
+        let mut reader = EventReader::new(ChunkedReader(VecDeque::from(vec![
+            x[..10].to_vec(),
+            [&x[10..], &y[..3]].concat(),
+            [&y[3..], &syn[..]].concat(),
+        ])));
+
+        assert!(reader.read_events().await.unwrap().is_empty());
+        assert_eq!(summary(&reader.read_events().await.unwrap()), vec![(EV_ABS, CODE_ABS_MT_POSITION_X, 400)]);
+
+        let events = reader.read_events().await.unwrap();
+        assert_eq!(summary(&events), vec![(EV_ABS, CODE_ABS_MT_POSITION_Y, 240), (EV_SYN, CODE_SYN_REPORT, 0)]);
+        assert_eq!(events[0].time(), Duration::from_secs(12) + Duration::from_micros(345));
+        assert!(reader.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn event_read_byte_by_byte() {
+        let press = event_bytes(EV_KEY, CODE_BTN_TOUCH, 1);
+        let mut reader = EventReader::new(ChunkedReader(press.iter().map(|byte| vec![*byte]).collect()));
+
+        for _ in 1..press.len() {
+            assert!(reader.read_events().await.unwrap().is_empty());
+        }
+
+        assert_eq!(summary(&reader.read_events().await.unwrap()), vec![(EV_KEY, CODE_BTN_TOUCH, 1)]);
+    }
+
+    #[tokio::test]
+    async fn end_of_input_is_an_error() {
+        let mut reader = EventReader::new(ChunkedReader(VecDeque::new()));
+
+        assert!(reader.read_events().await.is_err());
+    }
+
+    fn pointer_event(button_mask: u8, x: u16) -> ToServerMessage {
+        ToServerMessage::PointerEvent(PointerEventArgs { button_mask, location: Point { x, y: 100 }, timestamp: None })
+    }
+
+    #[tokio::test]
+    async fn events_before_the_handshake_never_reach_the_session() {
+        let touch_input = TouchInput::default();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(10);
+        let (pointer_enabled, pointer_enabled_receiver) = watch::channel(false);
+        let _attachment = touch_input.attach(sender, pointer_enabled_receiver);
+
+        assert!(!touch_input.inject(pointer_event(1, 10)).await);
+        assert!(!touch_input.inject(pointer_event(0, 10)).await);
+        assert!(receiver.try_recv().is_err());
+
+        pointer_enabled.send_replace(true);
+
+        assert!(touch_input.inject(pointer_event(1, 20)).await);
+        assert!(touch_input.inject(pointer_event(0, 20)).await);
+        assert_eq!(receiver.try_recv().unwrap(), pointer_event(1, 20));
+        assert_eq!(receiver.try_recv().unwrap(), pointer_event(0, 20));
+        assert!(receiver.try_recv().is_err());
+    }
 }
@@ -0,0 +1,181 @@
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc::channel, watch};
+
+use crate::screen::Screen;
+use super::rfb_messages::ToServerMessage;
+use super::{FromServerThread, PixelFormat, ProtocolPhase, ServerInfo, SessionOptions, to_server_thread};
+
+// This client speaks RFB 3.8 and always selects security type None
+const CLIENT_PROTOCOL_VERSION: &str = "RFB 003.008";
+
+// What a server offers up to ServerInit (--verify-server), and why this client could not work with it
+#[derive(Debug)]
+pub struct ServerReport {
+    server_address: String,
+    protocol_version: String,
+    security_types: Vec<u8>,
+    server_info: Option<ServerInfo>,
+    panel_size: (usize, usize),
+    pub problems: Vec<String>,
+}
+
+impl ServerReport {
+    // Report for a server that could not be connected to
+    pub fn unreachable(server_address: &str, error: String) -> ServerReport {
+        ServerReport {
+            server_address: server_address.to_string(),
+            protocol_version: String::new(),
+            security_types: Vec::new(),
+            server_info: None,
+            panel_size: (0, 0),
+            problems: vec![format!("cannot connect: {}", error)],
+        }
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    // The handshake got as far as ServerInit (the server name, size and pixel format are known)
+    pub fn reached_server_init(&self) -> bool {
+        self.server_info.is_some()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("Server: {}", self.server_address)];
+
+        if !self.protocol_version.is_empty() {
+            lines.push(format!("Protocol: {}", self.protocol_version));
+        }
+
+        if !self.security_types.is_empty() {
+            lines.push(format!("Security types: {}", self.security_types.iter().map(|security_type| security_type_name(*security_type)).collect::<Vec<_>>().join(", ")));
+        }
+
+        if let Some(ref server_info) = self.server_info {
+            lines.push(format!("Framebuffer: {}x{} (panel {}x{})", server_info.frame_buffer_width, server_info.frame_buffer_height, self.panel_size.0, self.panel_size.1));
+            lines.push(format!("Pixel format: {}", describe_pixel_format(&server_info.pixel_format)));
+            lines.push(format!("Desktop name: {}", server_info.name));
+        }
+
+        if self.is_compatible() {
+            lines.push(String::from("Compatible"));
+        } else {
+            lines.extend(self.problems.iter().map(|problem| format!("Not compatible: {}", problem)));
+        }
+
+        lines
+    }
+
+    // One line JSON object (--probe). Fields the handshake did not get to are null. RFB servers do not announce
+    // their encodings, so only the security types are listed
+    pub fn to_json(&self) -> String {
+        let mut fields = vec![
+            format!("\"server\":{}", json_string(&self.server_address)),
+            format!("\"protocol_version\":{}", if self.protocol_version.is_empty() { String::from("null") } else { json_string(&self.protocol_version) }),
+            format!("\"security_types\":[{}]", self.security_types.iter().map(|security_type| format!("{{\"type\":{},\"name\":{}}}", security_type, json_string(&security_type_name(*security_type)))).collect::<Vec<_>>().join(",")),
+        ];
+
+        match self.server_info {
+            Some(ref server_info) => {
+                let pf = &server_info.pixel_format;
+
+                fields.push(format!("\"name\":{}", json_string(&server_info.name)));
+                fields.push(format!("\"width\":{},\"height\":{}", server_info.frame_buffer_width, server_info.frame_buffer_height));
+                fields.push(format!("\"pixel_format\":{{\"bits_per_pixel\":{},\"depth\":{},\"big_endian\":{},\"true_color\":{},\"red_max\":{},\"green_max\":{},\"blue_max\":{},\"red_shift\":{},\"green_shift\":{},\"blue_shift\":{}}}",
+                    pf.bits_per_pixel, pf.depth, pf.big_endian, pf.true_color, pf.red_max, pf.green_max, pf.blue_max, pf.red_shift, pf.green_shift, pf.blue_shift));
+            },
+            None => fields.push(String::from("\"name\":null,\"width\":null,\"height\":null,\"pixel_format\":null")),
+        }
+
+        fields.push(format!("\"compatible\":{}", self.is_compatible()));
+        fields.push(format!("\"problems\":[{}]", self.problems.iter().map(|problem| json_string(problem)).collect::<Vec<_>>().join(",")));
+
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+
+    json.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+    json
+}
+
+// Run the handshake through ServerInit without entering the frame loop, then disconnect
+pub async fn verify_server(connection: TcpStream, screen: &mut Screen, options: SessionOptions, server_address: &str) -> ServerReport {
+    let (output_sender, output_receiver) = channel(10);
+    let (_, pointer_receiver) = channel(1);
+    let (pointer_enabled, _) = watch::channel(false);
+    let (mut input_stream, output_stream) = connection.into_split();
+    let to_server = tokio::spawn(to_server_thread(output_stream, output_receiver, pointer_receiver, options.write_timeout, None));
+    let panel_size = (screen.xres(), screen.yres());
+
+    let (mut report, same_pixel_format) = {
+        let mut fst = FromServerThread::new(&mut input_stream, &output_sender, screen, pointer_enabled, options);
+        let result = fst.run_protocol(Some(ProtocolPhase::Init)).await;
+        let same_pixel_format = fst.server_info.is_some() && fst.is_same_pixel_format();
+
+        (ServerReport {
+            server_address: server_address.to_string(),
+            protocol_version: std::mem::take(&mut fst.server_version),
+            security_types: std::mem::take(&mut fst.security_types),
+            server_info: fst.server_info.take(),
+            panel_size,
+            problems: result.err().map(|e| format!("handshake failed: {}", e)).into_iter().collect(),
+        }, same_pixel_format)
+    };
+
+    // Closing the write side ends the connection cleanly
+    let _ = output_sender.send(ToServerMessage::Terminate).await;
+    let _ = to_server.await;
+
+    if !report.protocol_version.is_empty() && report.protocol_version.as_str() < CLIENT_PROTOCOL_VERSION {
+        report.problems.push(format!("server speaks {}, this client needs {}", report.protocol_version, CLIENT_PROTOCOL_VERSION));
+    }
+
+    if let Some(ref server_info) = report.server_info {
+        if !same_pixel_format && !is_supported_pixel_format(&server_info.pixel_format) {
+            report.problems.push(format!("pixel format is not supported ({})", describe_pixel_format(&server_info.pixel_format)));
+        }
+    }
+
+    report
+}
+
+// Besides the device pixel format, FromServerThread::to_device_pixel converts 32 bit true color
+fn is_supported_pixel_format(pixel_format: &PixelFormat) -> bool {
+    pixel_format.true_color && pixel_format.bits_per_pixel == 32
+}
+
+fn describe_pixel_format(pixel_format: &PixelFormat) -> String {
+    format!("{} bpp, depth {}, {}, {} endian, red {}<<{} green {}<<{} blue {}<<{}",
+        pixel_format.bits_per_pixel, pixel_format.depth,
+        if pixel_format.true_color { "true color" } else { "color map" },
+        if pixel_format.big_endian { "big" } else { "little" },
+        pixel_format.red_max, pixel_format.red_shift,
+        pixel_format.green_max, pixel_format.green_shift,
+        pixel_format.blue_max, pixel_format.blue_shift)
+}
+
+fn security_type_name(security_type: u8) -> String {
+    match security_type {
+        1 => String::from("None"),
+        2 => String::from("VNC authentication"),
+        _ => format!("type {}", security_type),
+    }
+}
@@ -0,0 +1,114 @@
+
+// Scale an RGB565 (little endian) image to a different size using a box filter: each destination pixel is the
+// average of the source pixels it covers (when enlarging, this degenerates to nearest neighbor)
+#[allow(clippy::too_many_arguments)]
+pub fn scale_rgb565(source: &[u8], source_width: usize, source_height: usize, source_stride: usize,
+                    destination: &mut [u8], destination_width: usize, destination_height: usize, destination_stride: usize) {
+    if source_width == 0 || source_height == 0 {
+        return;
+    }
+
+    for dy in 0..destination_height {
+        let sy0 = dy * source_height / destination_height;
+        let sy1 = ((dy + 1) * source_height / destination_height).max(sy0 + 1);
+
+        for dx in 0..destination_width {
+            let sx0 = dx * source_width / destination_width;
+            let sx1 = ((dx + 1) * source_width / destination_width).max(sx0 + 1);
+            let (mut r, mut g, mut b, mut count) = (0u32, 0u32, 0u32, 0u32);
+
+            for sy in sy0..sy1 {
+                for sx in sx0..sx1 {
+                    let offset = sy * source_stride + sx * 2;
+                    let pixel = source[offset] as u32 | (source[offset + 1] as u32) << 8;
+
+                    r += pixel >> 11;
+                    g += (pixel >> 5) & 0x3f;
+                    b += pixel & 0x1f;
+                    count += 1;
+                }
+            }
+
+            let pixel = ((r / count) << 11) | ((g / count) << 5) | (b / count);
+            let offset = dy * destination_stride + dx * 2;
+
+            destination[offset] = (pixel & 0xff) as u8;
+            destination[offset + 1] = (pixel >> 8) as u8;
+        }
+    }
+}
+
+// Size of a width x height image scaled to fit xres x yres, preserving the aspect ratio. The result is at least 1x1
+// and never larger than the screen, so the image can be centered on it. Integer arithmetic, so the limiting side is
+// exactly the screen size (a floating point scale could make it a pixel short)
+pub fn fit_size(width: usize, height: usize, xres: usize, yres: usize) -> (usize, usize) {
+    let (width, height, xres, yres) = (width.max(1) as u64, height.max(1) as u64, xres as u64, yres as u64);
+
+    if width * yres >= height * xres {
+        (xres as usize, (height * xres / width).clamp(1, yres) as usize)
+    } else {
+        ((width * yres / height).clamp(1, xres) as usize, yres as usize)
+    }
+}
+
+// Resize an RGB (3 bytes per pixel, packed rows) image with bilinear interpolation
+pub fn resize_rgb24_bilinear(source: &[u8], source_width: usize, source_height: usize, destination_width: usize, destination_height: usize) -> Vec<u8> {
+    let mut destination = vec![0; destination_width * destination_height * 3];
+
+    // Source coordinate of a destination pixel center, clamped to the image
+    let source_position = |destination_index: usize, destination_size: usize, source_size: usize| {
+        let position = ((destination_index as f64 + 0.5) * source_size as f64 / destination_size as f64 - 0.5).max(0.0);
+        let index = (position as usize).min(source_size - 1);
+
+        (index, (index + 1).min(source_size - 1), position - index as f64)
+    };
+
+    for dy in 0..destination_height {
+        let (y0, y1, fy) = source_position(dy, destination_height, source_height);
+
+        for dx in 0..destination_width {
+            let (x0, x1, fx) = source_position(dx, destination_width, source_width);
+
+            for channel in 0..3 {
+                let value = |x: usize, y: usize| source[(y * source_width + x) * 3 + channel] as f64;
+                let top = value(x0, y0) * (1.0 - fx) + value(x1, y0) * fx;
+                let bottom = value(x0, y1) * (1.0 - fx) + value(x1, y1) * fx;
+
+                destination[(dy * destination_width + dx) * 3 + channel] = (top * (1.0 - fy) + bottom * fy).round() as u8;
+            }
+        }
+    }
+
+    destination
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_larger_than_the_screen_is_reduced() {
+        assert_eq!(fit_size(1600, 960, 800, 480), (800, 480));
+        assert_eq!(fit_size(1920, 1080, 800, 480), (800, 450));
+        assert_eq!(fit_size(1024, 768, 320, 240), (320, 240));
+    }
+
+    #[test]
+    fn smaller_image_is_enlarged() {
+        assert_eq!(fit_size(400, 240, 800, 480), (800, 480));
+        assert_eq!(fit_size(100, 100, 800, 480), (480, 480));
+    }
+
+    #[test]
+    fn very_wide_image_fits_the_width() {
+        assert_eq!(fit_size(4000, 100, 800, 480), (800, 20));
+        assert_eq!(fit_size(100000, 1, 800, 480), (800, 1));
+    }
+
+    #[test]
+    fn very_tall_image_fits_the_height() {
+        assert_eq!(fit_size(100, 4000, 800, 480), (12, 480));
+        assert_eq!(fit_size(1, 100000, 800, 480), (1, 480));
+        assert_eq!(fit_size(3, 1000, 800, 479), (1, 479));
+    }
+}
@@ -1,10 +1,195 @@
 
-use framebuffer::{self, Framebuffer, FramebufferError, KdMode};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use framebuffer::{self, Framebuffer, FramebufferError, KdMode, VarScreeninfo};
 use png::Decoder;
+use crate::{font, scale};
 
 pub struct Screen {
     pub fb: Framebuffer,
+    device: String,
     pub image: Vec<u8>,
+    pub color_adjustment: ColorAdjustment,
+    pub background_color: (u8, u8, u8),
+    mirror: Option<Mirror>,
+    snapshots: Option<SnapshotPublisher>,
+    splash_cache: Vec<SplashImage>,     // Most recently used last
+    pixel_format: FramebufferPixelFormat,
+    bytes_per_row: usize,               // Of the image, the framebuffer row length may differ (see set_pixel_format)
+    output: Vec<u8>,                    // Image converted to the framebuffer pixel format, unless it is RGB565
+    region: Option<ScreenRegion>,       // Part of the framebuffer this screen draws into, all of it when None
+}
+
+// Part of the framebuffer shown by one panel when it is split between several (--split)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScreenRegion {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// How --split divides the framebuffer between two panels: vertical puts them side by side, horizontal one above the
+// other
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitLayout {
+    Vertical,
+    Horizontal,
+}
+
+impl SplitLayout {
+    pub fn parse(value: &str) -> Result<SplitLayout, String> {
+        match value {
+            "vertical" => Ok(SplitLayout::Vertical),
+            "horizontal" => Ok(SplitLayout::Horizontal),
+            _ => Err(format!("Invalid split '{}' (vertical or horizontal)", value)),
+        }
+    }
+
+    // The two halves of a framebuffer of this size, left (or top) first
+    pub fn regions(&self, width: usize, height: usize) -> [ScreenRegion; 2] {
+        match self {
+            SplitLayout::Vertical => [
+                ScreenRegion { x: 0, y: 0, width: width / 2, height },
+                ScreenRegion { x: width / 2, y: 0, width: width - width / 2, height },
+            ],
+            SplitLayout::Horizontal => [
+                ScreenRegion { x: 0, y: 0, width, height: height / 2 },
+                ScreenRegion { x: 0, y: height / 2, width, height: height - height / 2 },
+            ],
+        }
+    }
+}
+
+// Pixel format of the framebuffer. The screen image is always RGB565, for other formats it is converted when the
+// framebuffer is updated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FramebufferPixelFormat {
+    Rgb565,
+    Bgr565,             // Red and blue swapped (red in the low bits)
+    Grayscale8,         // 8 bit luminance, for grayscale and e-ink panels
+}
+
+impl FramebufferPixelFormat {
+    // None for auto (detected from the framebuffer)
+    pub fn parse(value: &str) -> Result<Option<FramebufferPixelFormat>, String> {
+        match value {
+            "auto" => Ok(None),
+            "rgb565" => Ok(Some(FramebufferPixelFormat::Rgb565)),
+            "bgr565" => Ok(Some(FramebufferPixelFormat::Bgr565)),
+            "grayscale8" => Ok(Some(FramebufferPixelFormat::Grayscale8)),
+            _ => Err(format!("Invalid pixel format '{}' (auto, rgb565, bgr565 or grayscale8)", value)),
+        }
+    }
+
+    // From the bit depth and the channel offsets reported by the driver
+    fn detect(screen_info: &VarScreeninfo) -> Option<FramebufferPixelFormat> {
+        match screen_info.bits_per_pixel {
+            16 if screen_info.red.offset == 0 && screen_info.blue.offset == 11 => Some(FramebufferPixelFormat::Bgr565),
+            16 => Some(FramebufferPixelFormat::Rgb565),
+            8 => Some(FramebufferPixelFormat::Grayscale8),
+            _ => None,
+        }
+    }
+
+    fn bits_per_pixel(&self) -> u32 {
+        match self {
+            FramebufferPixelFormat::Rgb565 | FramebufferPixelFormat::Bgr565 => 16,
+            FramebufferPixelFormat::Grayscale8 => 8,
+        }
+    }
+}
+
+// A secondary (usually small SPI) display showing a scaled down copy of the main screen
+struct Mirror {
+    screen: Box<Screen>,
+    interval: Duration,
+    last_update: Option<Instant>,
+}
+
+// Copy of the screen contents, published for the VNC mirror server (it cannot lock the screen while a session runs)
+#[derive(Debug)]
+pub struct Snapshot {
+    pub width: usize,
+    pub height: usize,
+    pub bytes_per_row: usize,
+    pub image: Vec<u8>,
+}
+
+impl Snapshot {
+    // 8 bits per channel, the low bits of each RGB565 channel are filled from its high bits
+    pub fn to_rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.width * self.height * 3);
+
+        for row in self.image.chunks(self.bytes_per_row).take(self.height) {
+            for pixel in row[..self.width * Screen::bytes_per_pixel()].chunks(2) {
+                let value = pixel[0] as u16 | (pixel[1] as u16) << 8;
+                let (r, g, b) = ((value >> 11) as u8, ((value >> 5) & 0x3f) as u8, (value & 0x1f) as u8);
+
+                rgb.extend_from_slice(&[(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2)]);
+            }
+        }
+
+        rgb
+    }
+
+    pub fn encode_png(&self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut png_image = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png_image, self.width as u32, self.height as u32);
+
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&self.to_rgb())?;
+
+        Ok(png_image)
+    }
+}
+
+// Test support for comparing the screen against golden images: a stable content hash (FNV-1a of the visible pixels,
+// ignoring the row padding) and a per pixel comparison with a tolerance
+#[cfg(test)]
+impl Snapshot {
+    pub fn content_hash(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        self.image.chunks(self.bytes_per_row).take(self.height)
+            .flat_map(|row| row[..self.width * Screen::bytes_per_pixel()].iter())
+            .fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME))
+    }
+
+    // Number of pixels with a channel differing by more than `tolerance` from a golden RGB image of the same size
+    pub fn differing_pixels(&self, golden_rgb: &[u8], tolerance: u8) -> usize {
+        let rgb = self.to_rgb();
+
+        if rgb.len() != golden_rgb.len() {
+            return self.width * self.height;
+        }
+
+        rgb.chunks(3).zip(golden_rgb.chunks(3))
+            .filter(|(pixel, golden)| pixel.iter().zip(golden.iter()).any(|(value, expected)| value.abs_diff(*expected) > tolerance))
+            .count()
+    }
+}
+
+// A splash image converted to device pixels, fitted to the panel resolution. The color adjustment is set before the
+// first splash is shown and does not change afterwards
+struct SplashImage {
+    resource: &'static [u8],
+    resolution: (usize, usize),
+    color_adjustment: ColorAdjustment,      // Applied to the pixels, a splash cached before the adjustment changed is stale
+    width: usize,
+    height: usize,
+    pixels: Vec<DevicePixel>,
+}
+
+const MAX_CACHED_SPLASHES: usize = 4;
+
+struct SnapshotPublisher {
+    sender: watch::Sender<Arc<Snapshot>>,
+    interval: Duration,
+    last_update: Option<Instant>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -12,7 +197,7 @@ pub struct DevicePixel(u16);
 
 impl DevicePixel {
     pub fn from_rgb(r: u8, g: u8, b:u8) -> DevicePixel {
-        DevicePixel(((r as u16 >> 3) << 11) | (g as u16 >> 2) << 5 | (b as u16 >> 3))
+        DevicePixel((scale_channel(r, 31) << 11) | (scale_channel(g, 63) << 5) | scale_channel(b, 31))
     }
 
     pub fn from_value(v: u16) -> DevicePixel {
@@ -20,13 +205,169 @@ impl DevicePixel {
     }
 }
 
+// Per panel color correction (gamma and per channel gain) folded into lookup tables, so applying it
+// does not cost more than a table lookup per channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorAdjustment {
+    red: [u8; 256],
+    green: [u8; 256],
+    blue: [u8; 256],
+    identity: bool,
+}
+
+impl ColorAdjustment {
+    // gamma > 1 brightens mid tones, gamma < 1 darkens them. Gains are [red, green, blue] multipliers
+    pub fn new(gamma: f64, gains: [f64; 3]) -> ColorAdjustment {
+        let build_table = |gain: f64| {
+            let mut table = [0u8; 256];
+
+            for (value, entry) in table.iter_mut().enumerate() {
+                let corrected = (value as f64 / 255.0).powf(1.0 / gamma) * gain * 255.0;
+                *entry = corrected.round().clamp(0.0, 255.0) as u8;
+            }
+            table
+        };
+
+        ColorAdjustment {
+            red: build_table(gains[0]),
+            green: build_table(gains[1]),
+            blue: build_table(gains[2]),
+            identity: gamma == 1.0 && gains == [1.0, 1.0, 1.0],
+        }
+    }
+
+    pub fn identity() -> ColorAdjustment {
+        ColorAdjustment::new(1.0, [1.0, 1.0, 1.0])
+    }
+
+    // Color temperature is either "neutral", "warm", "cool" or explicit "red,green,blue" gains (e.g. "1.0,0.95,0.8")
+    pub fn parse_color_temperature(value: &str) -> Result<[f64; 3], String> {
+        match value {
+            "neutral" => Ok([1.0, 1.0, 1.0]),
+            "warm" => Ok([1.0, 0.92, 0.78]),
+            "cool" => Ok([0.85, 0.93, 1.0]),
+            _ => {
+                let gains = value.split(',').map(|gain| gain.trim().parse::<f64>()).collect::<Result<Vec<_>, _>>();
+
+                match gains {
+                    Ok(gains) if gains.len() == 3 && gains.iter().all(|gain| *gain >= 0.0 && gain.is_finite()) => Ok([gains[0], gains[1], gains[2]]),
+                    _ => Err(format!("Invalid color temperature '{}' (expected neutral, warm, cool or red,green,blue gains)", value)),
+                }
+            }
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.identity
+    }
+
+    pub fn to_device_pixel(&self, r: u8, g: u8, b: u8) -> DevicePixel {
+        DevicePixel::from_rgb(self.red[r as usize], self.green[g as usize], self.blue[b as usize])
+    }
+}
+
+impl Default for ColorAdjustment {
+    fn default() -> Self {
+        ColorAdjustment::identity()
+    }
+}
+
+// ITU-R BT.601 luma of an RGB565 pixel
+fn luminance(value: u16) -> u8 {
+    let r = (value >> 11) as u32 * 255 / 31;
+    let g = ((value >> 5) & 0x3f) as u32 * 255 / 63;
+    let b = (value & 0x1f) as u32 * 255 / 31;
+
+    ((77 * r + 150 * g + 29 * b) >> 8) as u8
+}
+
+// Scale an 8 bit channel value to 0..=max with rounding (truncating the low bits darkens the image)
+fn scale_channel(value: u8, max: u16) -> u16 {
+    (value as u16 * max + 127) / 255
+}
+
 impl Screen {
     pub fn new() -> Result<Screen, FramebufferError> {
-        let fb = Framebuffer::new("/dev/fb0")?;
+        Self::open("/dev/fb0")
+    }
+
+    pub fn open(device: &str) -> Result<Screen, FramebufferError> {
+        let fb = Framebuffer::new(device)?;
+        let device = device.to_string();
         let image_size = fb.fix_screen_info.line_length * fb.var_screen_info.yres;
         let image = vec![0; image_size as usize];
+        let bytes_per_row = fb.fix_screen_info.line_length as usize;
 
-        Ok(Screen {fb, image, })
+        Ok(Screen {fb, device, image, color_adjustment: ColorAdjustment::identity(), background_color: (0, 0, 0), mirror: None, snapshots: None, splash_cache: Vec::new(),
+            pixel_format: FramebufferPixelFormat::Rgb565, bytes_per_row, output: Vec::new(), region: None, })
+    }
+
+    // Screen drawing into a region of this screen's framebuffer (--split), with the same pixel format and colors.
+    // Its image covers only the region and is copied into the framebuffer by update, so the screens of the regions
+    // are independent of each other (each session locks only its own screen)
+    pub fn open_region(&self, region: ScreenRegion) -> Result<Screen, FramebufferError> {
+        let mut screen = Self::open(&self.device)?;
+        let region = ScreenRegion {
+            width: region.width.min(screen.xres().saturating_sub(region.x)),
+            height: region.height.min(screen.yres().saturating_sub(region.y)),
+            ..region
+        };
+
+        screen.region = Some(region);
+        screen.bytes_per_row = region.width * Self::bytes_per_pixel();
+        screen.image = vec![0; screen.bytes_per_row * region.height];
+        screen.pixel_format = self.pixel_format;
+        screen.output = if self.pixel_format == FramebufferPixelFormat::Rgb565 { Vec::new() } else { vec![0; screen.output_bytes_per_row() * region.height] };
+        screen.color_adjustment = self.color_adjustment.clone();
+        screen.background_color = self.background_color;
+        Ok(screen)
+    }
+
+    // Must be set before anything is drawn, None detects the format. Drawing and decoding stay in RGB565 (in an image
+    // with rows of exactly the screen width), the conversion is done by update
+    pub fn set_pixel_format(&mut self, pixel_format: Option<FramebufferPixelFormat>) -> Result<(), String> {
+        let bits_per_pixel = self.fb.var_screen_info.bits_per_pixel;
+        let pixel_format = match pixel_format.or_else(|| FramebufferPixelFormat::detect(&self.fb.var_screen_info)) {
+            Some(pixel_format) => pixel_format,
+            None => return Err(format!("Unsupported framebuffer with {} bits per pixel", bits_per_pixel)),
+        };
+
+        if bits_per_pixel != pixel_format.bits_per_pixel() {
+            return Err(format!("Pixel format {:?} needs a {} bits per pixel framebuffer, it is {} bits per pixel", pixel_format, pixel_format.bits_per_pixel(), bits_per_pixel));
+        }
+
+        if pixel_format != FramebufferPixelFormat::Rgb565 {
+            self.bytes_per_row = self.xres() * Self::bytes_per_pixel();
+            self.image = vec![0; self.bytes_per_row * self.yres()];
+            self.output = vec![0; self.output_bytes_per_row() * self.yres()];
+        }
+
+        self.pixel_format = pixel_format;
+        Ok(())
+    }
+
+    // Mirror the screen content to another framebuffer, refreshing it at most `fps` times per second. Only 16 bits
+    // per pixel mirror displays are supported
+    pub fn attach_mirror(&mut self, device: &str, fps: f64) -> Result<(), String> {
+        let mut screen = Screen::open(device).map_err(|e| format!("Cannot open mirror framebuffer {}: {:?}", device, e))?;
+
+        if screen.fb.var_screen_info.bits_per_pixel != 16 {
+            return Err(format!("Mirror framebuffer {} is not 16 bits per pixel", device));
+        }
+
+        screen.set_pixel_format(None)?;
+
+        if fps <= 0.0 {
+            return Err(format!("Invalid mirror refresh rate {}", fps));
+        }
+
+        self.mirror = Some(Mirror {
+            screen: Box::new(screen),
+            interval: Duration::from_secs_f64(1.0 / fps),
+            last_update: None,
+        });
+
+        Ok(())
     }
 
     pub fn set_console_to_graphic_mode() -> Result<(), FramebufferError> {
@@ -39,12 +380,23 @@ impl Screen {
         Ok(())
     }
 
+    // SPI panels (fbtft drivers, identified as fb_<controller>) take tens of milliseconds per full flush
+    pub fn is_slow_display(&self) -> bool {
+        self.fb.fix_screen_info.id.starts_with(b"fb_")
+    }
+
     pub fn xres(&self) -> usize {
-        self.fb.var_screen_info.xres as usize
+        match self.region {
+            Some(region) => region.width,
+            None => self.fb.var_screen_info.xres as usize,
+        }
     }
 
     pub fn yres(&self) -> usize {
-        self.fb.var_screen_info.yres as usize
+        match self.region {
+            Some(region) => region.height,
+            None => self.fb.var_screen_info.yres as usize,
+        }
     }
 
     pub fn bytes_per_pixel() -> usize {
@@ -52,7 +404,11 @@ impl Screen {
     }
 
     pub fn bytes_per_row(&self) -> usize {
-        self.fb.fix_screen_info.line_length as usize
+        self.bytes_per_row
+    }
+
+    pub fn get_at_offset(&self, offset: usize) -> DevicePixel {
+        DevicePixel(self.image[offset] as u16 | (self.image[offset + 1] as u16) << 8)
     }
 
     pub fn set_at_offset(&mut self, offset: usize, value: DevicePixel) {
@@ -60,42 +416,359 @@ impl Screen {
         self.image[offset + 1] = (value.0 >> 8) as u8;
     }
     
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, pixel: DevicePixel) {
+        for row in y..y + height {
+            self.fill_row(row * self.bytes_per_row() + x * Self::bytes_per_pixel(), width, pixel);
+        }
+    }
+
+    // Write a run of `count` identical pixels starting at `offset`: the first pixel is set and then copied over the
+    // rest of the span, doubling the copied length each time
+    pub fn fill_row(&mut self, offset: usize, count: usize, pixel: DevicePixel) {
+        if count == 0 {
+            return;
+        }
+
+        let span = &mut self.image[offset..offset + count * Self::bytes_per_pixel()];
+        let mut filled = Self::bytes_per_pixel();
+
+        span[0] = (pixel.0 & 0xff) as u8;
+        span[1] = (pixel.0 >> 8) as u8;
+
+        while filled < span.len() {
+            let length = filled.min(span.len() - filled);
+
+            span.copy_within(0..length, filled);
+            filled += length;
+        }
+    }
+
+    // Draw text using the built in font, each font pixel is drawn as a scale x scale square. Text beyond the
+    // screen edges is clipped
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str, scale: usize, foreground: DevicePixel, background: Option<DevicePixel>) {
+        if let Some(background) = background {
+            let width = font::text_width(text, scale).min(self.xres().saturating_sub(x));
+            let height = font::text_height(scale).min(self.yres().saturating_sub(y));
+
+            self.fill_rect(x, y, width, height, background);
+        }
+
+        for (index, c) in text.chars().enumerate() {
+            let glyph_x = x + index * (font::GLYPH_WIDTH + font::GLYPH_SPACING) * scale;
+
+            for (row, bits) in font::glyph(c).iter().enumerate() {
+                for column in 0..font::GLYPH_WIDTH {
+                    if bits & (0x10 >> column) != 0 {
+                        let pixel_x = glyph_x + column * scale;
+                        let pixel_y = y + row * scale;
+                        let width = scale.min(self.xres().saturating_sub(pixel_x));
+                        let height = scale.min(self.yres().saturating_sub(pixel_y));
+
+                        self.fill_rect(pixel_x, pixel_y, width, height, foreground);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        let (r, g, b) = self.background_color;
+        let background = self.color_adjustment.to_device_pixel(r, g, b);
+
+        self.fill_rect(0, 0, self.xres(), self.yres(), background);
+    }
+
+    // Publish a copy of the screen at most `fps` times per second (when it is updated). Later subscribers share the
+    // snapshots (and the rate) of the first one
+    pub fn publish_snapshots(&mut self, fps: f64) -> watch::Receiver<Arc<Snapshot>> {
+        if let Some(ref publisher) = self.snapshots {
+            return publisher.sender.subscribe();
+        }
+
+        let (sender, receiver) = watch::channel(Arc::new(self.snapshot()));
+
+        self.snapshots = Some(SnapshotPublisher {
+            sender,
+            interval: Duration::from_secs_f64(1.0 / fps),
+            last_update: None,
+        });
+
+        receiver
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot { width: self.xres(), height: self.yres(), bytes_per_row: self.bytes_per_row(), image: self.image.clone() }
+    }
+
     pub fn update(&mut self) {
-        self.fb.write_frame(&self.image);
+        self.write_framebuffer();
+        self.update_mirror();
+        self.update_snapshot();
     }
 
-    pub fn display_png_resource(&mut self, png_image: &'static [u8]) {
-        let decoder = Decoder::new(png_image);
-        let mut decoded_image_reader = decoder.read_info().expect("Error decoding image");
-        let info = decoded_image_reader.info();
-        let width = decoded_image_reader.info().width;
-        let height = decoded_image_reader.info().height;
-        
-        self.image.fill(0);         // Fill with black
-        let mut offset = (self.yres() - (height as usize)) / 2 * self.bytes_per_row() +
-            (self.xres() - (width as usize)) / 2 * Self::bytes_per_pixel();
-
-        for _ in 0..info.height {
-            match decoded_image_reader.next_row().expect("PNG image decoding error") {
-                Some(row_buffer) => {
-                    let mut png_row_offset = 0;
-                    let mut row_offset = offset;
-                    let row_data = row_buffer.data();
-
-                    for _ in 0..width {
-                        let pixel = DevicePixel::from_rgb(row_data[png_row_offset], row_data[png_row_offset+1], row_data[png_row_offset+2]);
-                        png_row_offset += 3;
-
-                        self.set_at_offset(row_offset, pixel);
-                        row_offset += Self::bytes_per_pixel();
+    // Row length of the converted output: the framebuffer row, or just the region of a region screen
+    fn output_bytes_per_row(&self) -> usize {
+        match self.region {
+            Some(region) => region.width * self.fb.var_screen_info.bits_per_pixel as usize / 8,
+            None => self.fb.fix_screen_info.line_length as usize,
+        }
+    }
+
+    fn write_framebuffer(&mut self) {
+        let output_bytes_per_row = self.output_bytes_per_row();
+        let output_rows = self.image.chunks(self.bytes_per_row).zip(self.output.chunks_mut(output_bytes_per_row));
+
+        match self.pixel_format {
+            FramebufferPixelFormat::Rgb565 => {
+                if self.region.is_none() {
+                    self.fb.write_frame(&self.image);
+                    return;
+                }
+            },
+            FramebufferPixelFormat::Bgr565 => {
+                for (row, output_row) in output_rows {
+                    for (pixel, output_pixel) in row.chunks(2).zip(output_row.chunks_mut(2)) {
+                        let value = pixel[0] as u16 | (pixel[1] as u16) << 8;
+                        let swapped = (value & 0x07e0) | (value >> 11) | ((value & 0x1f) << 11);
+
+                        output_pixel.copy_from_slice(&swapped.to_le_bytes());
+                    }
+                }
+            },
+            FramebufferPixelFormat::Grayscale8 => {
+                for (row, output_row) in output_rows {
+                    for (pixel, output_pixel) in row.chunks(2).zip(output_row.iter_mut()) {
+                        *output_pixel = luminance(pixel[0] as u16 | (pixel[1] as u16) << 8);
                     }
                 }
-                None => panic!("Missing PNG row")
+            },
+        }
+
+        let (output, output_bytes_per_row) = match self.pixel_format {
+            FramebufferPixelFormat::Rgb565 => (&self.image, self.bytes_per_row),
+            _ => (&self.output, output_bytes_per_row),
+        };
+
+        match self.region {
+            // Only the rows of the region are written, the rest of the framebuffer belongs to the other regions
+            Some(region) => {
+                let frame_bytes_per_row = self.fb.fix_screen_info.line_length as usize;
+                let frame_bytes_per_pixel = self.fb.var_screen_info.bits_per_pixel as usize / 8;
+                let row_length = region.width * frame_bytes_per_pixel;
+
+                for (row, output_row) in output.chunks(output_bytes_per_row).enumerate() {
+                    let offset = (region.y + row) * frame_bytes_per_row + region.x * frame_bytes_per_pixel;
+
+                    self.fb.frame[offset..offset + row_length].copy_from_slice(&output_row[..row_length]);
+                }
+            },
+            None => self.fb.write_frame(output),
+        }
+    }
+
+    fn update_snapshot(&mut self) {
+        let snapshot = match self.snapshots {
+            Some(ref publisher) if publisher.last_update.is_some_and(|last_update| last_update.elapsed() < publisher.interval) => return,
+            Some(_) => self.snapshot(),
+            None => return,
+        };
+
+        if let Some(ref mut publisher) = self.snapshots {
+            publisher.last_update = Some(Instant::now());
+            publisher.sender.send_replace(Arc::new(snapshot));
+        }
+    }
+
+    fn update_mirror(&mut self) {
+        let (width, height, stride) = (self.xres(), self.yres(), self.bytes_per_row());
+
+        if let Some(ref mut mirror) = self.mirror {
+            if mirror.last_update.is_some_and(|last_update| last_update.elapsed() < mirror.interval) {
+                return;
             }
 
-            offset += self.bytes_per_row();
+            mirror.last_update = Some(Instant::now());
+
+            let mirror_screen = &mut mirror.screen;
+            let (mirror_width, mirror_height, mirror_stride) = (mirror_screen.xres(), mirror_screen.yres(), mirror_screen.bytes_per_row());
+
+            scale::scale_rgb565(&self.image, width, height, stride, &mut mirror_screen.image, mirror_width, mirror_height, mirror_stride);
+            mirror_screen.write_framebuffer();
         }
+    }
 
+    // Splash images are scaled to fit the panel (preserving the aspect ratio) and centered. The converted image is
+    // cached, so switching back and forth between the splash screens does not decode and scale the PNG again
+    pub fn display_png_resource(&mut self, png_image: &'static [u8]) {
+        self.draw_png_resource(png_image);
         self.update();
     }
+
+    // Draw the splash image without updating the display, so more can be drawn on top of it first
+    pub fn draw_png_resource(&mut self, png_image: &'static [u8]) {
+        let resolution = (self.xres(), self.yres());
+        let is_cached = |splash: &SplashImage| std::ptr::eq(splash.resource, png_image) && splash.resolution == resolution && splash.color_adjustment == self.color_adjustment;
+        let index = match self.splash_cache.iter().position(is_cached) {
+            Some(index) => index,
+            None => {
+                let splash = self.decode_splash(png_image);
+
+                if self.splash_cache.len() >= MAX_CACHED_SPLASHES {
+                    self.splash_cache.remove(0);
+                }
+
+                self.splash_cache.push(splash);
+                self.splash_cache.len() - 1
+            }
+        };
+
+        // Most recently used last
+        let splash = self.splash_cache.remove(index);
+
+        self.clear();
+
+        let mut offset = (self.yres() - splash.height) / 2 * self.bytes_per_row() + (self.xres() - splash.width) / 2 * Self::bytes_per_pixel();
+
+        for row in splash.pixels.chunks(splash.width) {
+            for (column, pixel) in row.iter().enumerate() {
+                self.set_at_offset(offset + column * Self::bytes_per_pixel(), *pixel);
+            }
+
+            offset += self.bytes_per_row();
+        }
+
+        self.splash_cache.push(splash);
+    }
+
+    fn decode_splash(&self, png_image: &'static [u8]) -> SplashImage {
+        let decoder = Decoder::new(png_image);
+        let mut decoded_image_reader = decoder.read_info().expect("Error decoding image");
+        let width = decoded_image_reader.info().width as usize;
+        let height = decoded_image_reader.info().height as usize;
+        let mut rgb = Vec::with_capacity(width * height * 3);
+
+        while let Some(row_buffer) = decoded_image_reader.next_row().expect("PNG image decoding error") {
+            let row_data = row_buffer.data();
+            let bytes_per_pixel = row_data.len() / width;     // RGB or RGBA
+
+            for pixel in row_data.chunks(bytes_per_pixel).take(width) {
+                rgb.extend_from_slice(&pixel[..3]);
+            }
+        }
+
+        if rgb.len() != width * height * 3 {
+            panic!("Missing PNG row");
+        }
+
+        let (fit_width, fit_height) = scale::fit_size(width, height, self.xres(), self.yres());
+
+        if (fit_width, fit_height) != (width, height) {
+            rgb = scale::resize_rgb24_bilinear(&rgb, width, height, fit_width, fit_height);
+        }
+
+        SplashImage {
+            resource: png_image,
+            resolution: (self.xres(), self.yres()),
+            color_adjustment: self.color_adjustment.clone(),
+            width: fit_width,
+            height: fit_height,
+            pixels: rgb.chunks(3).map(|pixel| self.color_adjustment.to_device_pixel(pixel[0], pixel[1], pixel[2])).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgb_is_rounded_to_565() {
+        assert_eq!(DevicePixel::from_rgb(0, 0, 0).0, 0x0000);
+        assert_eq!(DevicePixel::from_rgb(255, 255, 255).0, 0xffff);
+        assert_eq!(DevicePixel::from_rgb(255, 0, 0).0, 0xf800);
+        assert_eq!(DevicePixel::from_rgb(0, 255, 0).0, 0x07e0);
+        assert_eq!(DevicePixel::from_rgb(0, 0, 255).0, 0x001f);
+        assert_eq!(DevicePixel::from_rgb(128, 128, 128).0, 0x8410);
+
+        // Truncating would give 0 for each channel
+        assert_eq!(DevicePixel::from_rgb(5, 3, 5).0, 0x0821);
+    }
+
+    const GOLDEN_PATTERN: &[u8] = include_bytes!("../tests/fixtures/snapshot_pattern.png");
+
+    // 8x4 RGB565 pattern, rows longer than the visible pixels are padded like a framebuffer line
+    fn pattern_snapshot(bytes_per_row: usize) -> Snapshot {
+        let (width, height) = (8, 4);
+        let mut image = vec![0xee; bytes_per_row * height];
+
+        for y in 0..height {
+            for x in 0..width {
+                let value = (((x * 4) << 11) | ((y * 21) << 5) | ((x + y) * 2)) as u16;
+                let offset = y * bytes_per_row + x * Screen::bytes_per_pixel();
+
+                image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        Snapshot { width, height, bytes_per_row, image }
+    }
+
+    fn decode_png(png_image: &[u8]) -> Vec<u8> {
+        let mut reader = Decoder::new(png_image).read_info().unwrap();
+        let mut rgb = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut rgb).unwrap();
+
+        rgb.truncate(info.buffer_size());
+        rgb
+    }
+
+    // On a mismatch the actual image is written next to the other test output, so it can be inspected or
+    // adopted as the new golden image
+    fn assert_matches_golden(snapshot: &Snapshot, name: &str, golden_png: &[u8], tolerance: u8) {
+        let differing_pixels = snapshot.differing_pixels(&decode_png(golden_png), tolerance);
+
+        if differing_pixels != 0 {
+            let path = std::env::temp_dir().join(format!("{}.actual.png", name));
+
+            std::fs::write(&path, snapshot.encode_png().unwrap()).unwrap();
+            panic!("{} pixels differ from golden image {}, actual image written to {}", differing_pixels, name, path.display());
+        }
+    }
+
+    #[test]
+    fn snapshot_matches_golden_image() {
+        assert_matches_golden(&pattern_snapshot(16), "snapshot_pattern", GOLDEN_PATTERN, 0);
+        assert_matches_golden(&pattern_snapshot(20), "snapshot_pattern", GOLDEN_PATTERN, 0);
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let snapshot = pattern_snapshot(20);
+
+        assert_eq!(decode_png(&snapshot.encode_png().unwrap()), snapshot.to_rgb());
+    }
+
+    #[test]
+    fn content_hash_is_stable_and_ignores_padding() {
+        assert_eq!(pattern_snapshot(16).content_hash(), 0xc9e74aeb2c0a7435);
+        assert_eq!(pattern_snapshot(20).content_hash(), 0xc9e74aeb2c0a7435);
+
+        let mut changed = pattern_snapshot(16);
+        changed.image[10] ^= 1;
+        assert_ne!(changed.content_hash(), 0xc9e74aeb2c0a7435);
+    }
+
+    #[test]
+    fn differences_within_tolerance_are_ignored() {
+        let snapshot = pattern_snapshot(16);
+        let mut golden = snapshot.to_rgb();
+
+        golden[0] = golden[0].wrapping_add(3);
+        golden[3 * 9 + 2] = golden[3 * 9 + 2].wrapping_sub(1);
+
+        assert_eq!(snapshot.differing_pixels(&golden, 0), 2);
+        assert_eq!(snapshot.differing_pixels(&golden, 2), 1);
+        assert_eq!(snapshot.differing_pixels(&golden, 3), 0);
+        assert_eq!(snapshot.differing_pixels(&golden[3..], 255), 8 * 4);
+    }
 }
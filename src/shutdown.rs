@@ -0,0 +1,24 @@
+use tokio::sync::watch;
+
+// Process wide shutdown signal, raised by the ctrl-c/SIGTERM handler so that waits anywhere in the state
+// machine can be abandoned promptly
+#[derive(Debug, Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+pub fn channel() -> (watch::Sender<bool>, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+
+    (sender, Shutdown(receiver))
+}
+
+impl Shutdown {
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    // Completes when shutdown is requested
+    pub async fn requested(&self) {
+        let mut receiver = self.0.clone();
+        let _ = receiver.wait_for(|requested| *requested).await;
+    }
+}
@@ -0,0 +1,147 @@
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use crate::locator;
+use crate::query;
+use crate::shutdown;
+
+// Load test of the servers manager: simulated panels discover the manager, query it for a server and connect to
+// the assigned server, without any framebuffer or input device
+
+// Panels running at the same time, and the delay between starting panels (unless --stampede)
+const MAX_CONCURRENT_PANELS: usize = 8;
+const STARTUP_STAGGER: Duration = Duration::from_millis(100);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Resolutions reported by the simulated panels, in turn
+const PANEL_RESOLUTIONS: [(usize, usize); 4] = [(800, 480), (1024, 600), (1280, 800), (480, 320)];
+
+pub enum ManagerSource {
+    Domain(String, Option<Ipv4Addr>),   // Located with mDNS (on the given interface)
+    Address(String),
+}
+
+#[derive(Debug, Default)]
+struct PanelResult {
+    discovery: Option<Result<Duration, String>>,
+    query: Option<Result<Duration, String>>,
+    connect: Option<Result<Duration, String>>,
+}
+
+pub async fn run(panel_count: u32, base_name: &str, manager_source: ManagerSource, stampede: bool) {
+    let manager_source = Arc::new(manager_source);
+    let concurrency = Arc::new(Semaphore::new(if stampede { panel_count.max(1) as usize } else { MAX_CONCURRENT_PANELS }));
+    let mut panels = Vec::new();
+
+    println!("Simulating {} panels{}", panel_count, if stampede { " (stampede)" } else { "" });
+
+    for index in 0..panel_count {
+        let name = format!("{}-sim{:03}", base_name, index);
+        let (width, height) = PANEL_RESOLUTIONS[index as usize % PANEL_RESOLUTIONS.len()];
+        let manager_source = manager_source.clone();
+        let concurrency = concurrency.clone();
+
+        panels.push(tokio::spawn(async move {
+            let _permit = concurrency.acquire().await.expect("Simulation semaphore closed");
+
+            simulate_panel(&name, width, height, &manager_source).await
+        }));
+
+        if !stampede {
+            tokio::time::sleep(STARTUP_STAGGER).await;
+        }
+    }
+
+    let mut results = Vec::new();
+
+    for panel in panels {
+        match panel.await {
+            Ok(result) => results.push(result),
+            Err(e) => println!("Simulated panel failed: {}", e),
+        }
+    }
+
+    println!("Simulation of {} panels:", panel_count);
+    report("Discovery", results.iter().filter_map(|result| result.discovery.as_ref()));
+    report("Query", results.iter().filter_map(|result| result.query.as_ref()));
+    report("Connect", results.iter().filter_map(|result| result.connect.as_ref()));
+}
+
+async fn simulate_panel(name: &str, width: usize, height: usize, manager_source: &ManagerSource) -> PanelResult {
+    let mut result = PanelResult::default();
+
+    let manager = match manager_source {
+        ManagerSource::Address(manager) => manager.clone(),
+        ManagerSource::Domain(domain_name, interface) => {
+            let start = Instant::now();
+
+            match locator::locate_ht_manager(domain_name, *interface).await {
+                Ok(Some(manager)) => {
+                    result.discovery = Some(Ok(start.elapsed()));
+                    manager
+                },
+                Ok(None) => {
+                    result.discovery = Some(Err(format!("{}: no manager for domain {}", name, domain_name)));
+                    return result;
+                },
+                Err(e) => {
+                    result.discovery = Some(Err(format!("{}: {}", name, e)));
+                    return result;
+                },
+            }
+        },
+    };
+
+    // The simulation is never cancelled, it just runs to the end
+    let (_shutdown_sender, shutdown) = shutdown::channel();
+    let query_bytes = query::prepare_panel_query(name, width, height, &[]);
+    let start = Instant::now();
+
+    let server_address = match query::query_for_hometouch_server(&manager, &query_bytes, &shutdown).await {
+        Ok(server_address) => {
+            result.query = Some(Ok(start.elapsed()));
+            server_address
+        },
+        Err(e) => {
+            result.query = Some(Err(format!("{}: query of {} failed: {:?}", name, manager, e)));
+            return result;
+        },
+    };
+
+    let start = Instant::now();
+
+    result.connect = Some(match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&server_address)).await {
+        Ok(Ok(_)) => Ok(start.elapsed()),
+        Ok(Err(e)) => Err(format!("{}: connection to {} failed: {}", name, server_address, e)),
+        Err(_) => Err(format!("{}: connection to {} timed out", name, server_address)),
+    });
+
+    result
+}
+
+fn report<'a>(stage: &str, results: impl Iterator<Item = &'a Result<Duration, String>>) {
+    let mut latencies = Vec::new();
+    let mut failures = 0;
+
+    for result in results {
+        match result {
+            Ok(latency) => latencies.push(*latency),
+            Err(e) => {
+                println!("  {}", e);
+                failures += 1;
+            }
+        }
+    }
+
+    if latencies.is_empty() {
+        println!("{}: 0 succeeded, {} failed", stage, failures);
+    } else {
+        let total: Duration = latencies.iter().sum();
+
+        println!("{}: {} succeeded, {} failed, latency min {:?} avg {:?} max {:?}", stage, latencies.len(), failures,
+            latencies.iter().min().unwrap(), total / latencies.len() as u32, latencies.iter().max().unwrap());
+    }
+}
@@ -0,0 +1,63 @@
+use crate::font;
+use crate::screen::{DevicePixel, Screen};
+
+// Panel bring-up diagnostic (--test-pattern): color bars, a gray ramp, a grid and a differently colored marker in
+// each corner, drawn without any server. Wrong colors point at the pixel format, a slanted or torn image at the
+// stride, misplaced corner markers at the orientation
+
+const GRID_SPACING: usize = 40;
+const MARKER_SIZE: usize = 24;
+const GRAY_STEPS: usize = 16;
+
+const BARS: [(u8, u8, u8); 8] = [
+    (255, 255, 255), (255, 255, 0), (0, 255, 255), (0, 255, 0),
+    (255, 0, 255), (255, 0, 0), (0, 0, 255), (0, 0, 0),
+];
+
+pub fn draw(screen: &mut Screen) {
+    let (width, height) = (screen.xres(), screen.yres());
+    let bars_height = height * 2 / 3;
+    let white = DevicePixel::from_rgb(255, 255, 255);
+    let black = DevicePixel::from_rgb(0, 0, 0);
+
+    for (index, (r, g, b)) in BARS.iter().enumerate() {
+        let x = index * width / BARS.len();
+        let bar_width = (index + 1) * width / BARS.len() - x;
+
+        screen.fill_rect(x, 0, bar_width, bars_height, DevicePixel::from_rgb(*r, *g, *b));
+    }
+
+    for step in 0..GRAY_STEPS {
+        let x = step * width / GRAY_STEPS;
+        let step_width = (step + 1) * width / GRAY_STEPS - x;
+        let level = (step * 255 / (GRAY_STEPS - 1)) as u8;
+
+        screen.fill_rect(x, bars_height, step_width, height - bars_height, DevicePixel::from_rgb(level, level, level));
+    }
+
+    for x in (0..width).step_by(GRID_SPACING) {
+        screen.fill_rect(x, 0, 1, height, white);
+    }
+
+    for y in (0..height).step_by(GRID_SPACING) {
+        screen.fill_rect(0, y, width, 1, white);
+    }
+
+    // Red top left, green top right, blue bottom left, white bottom right
+    let marker_width = MARKER_SIZE.min(width);
+    let marker_height = MARKER_SIZE.min(height);
+
+    screen.fill_rect(0, 0, marker_width, marker_height, DevicePixel::from_rgb(255, 0, 0));
+    screen.fill_rect(width - marker_width, 0, marker_width, marker_height, DevicePixel::from_rgb(0, 255, 0));
+    screen.fill_rect(0, height - marker_height, marker_width, marker_height, DevicePixel::from_rgb(0, 0, 255));
+    screen.fill_rect(width - marker_width, height - marker_height, marker_width, marker_height, white);
+
+    let description = format!("{}X{} {} BPP STRIDE {}", width, height, screen.fb.var_screen_info.bits_per_pixel, screen.bytes_per_row());
+    let scale = 2;
+    let text_x = width.saturating_sub(font::text_width(&description, scale)) / 2;
+
+    screen.draw_text(text_x, bars_height / 2, &description, scale, white, Some(black));
+    screen.update();
+
+    println!("Test pattern: {}x{}, {} bits per pixel, {} bytes per row", width, height, screen.fb.var_screen_info.bits_per_pixel, screen.bytes_per_row());
+}
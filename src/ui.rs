@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+
+use crate::font;
+use crate::resources;
+use crate::screen::{DevicePixel, Screen};
+
+// What the panel shows while no session is drawing. The state manager only sets the state, a single renderer task
+// owns drawing the non-session screens
+#[derive(Debug, Clone, PartialEq)]
+pub enum UiState {
+    LookingForManager { attempts: u32 },
+    Querying,
+    Connecting { server: String },
+    Error { message: String, retry_in: Duration },
+    BackingOff { retry_in: Duration },  // Attempts keep failing, the next one is paced by the retry budget
+    Sleeping,
+    Session,    // The session draws, the renderer keeps off the screen
+}
+
+const TEXT_SCALE: usize = 2;
+const TEXT_MARGIN: usize = 8;
+
+pub fn start(screen: Arc<Mutex<Screen>>) -> watch::Sender<UiState> {
+    let (sender, receiver) = watch::channel(UiState::Session);
+
+    tokio::spawn(run_renderer(screen, receiver));
+    sender
+}
+
+async fn run_renderer(screen: Arc<Mutex<Screen>>, mut state: watch::Receiver<UiState>) {
+    while state.changed().await.is_ok() {
+        let mut screen = screen.lock().await;
+
+        // Always the latest state, a session that took over while waiting for the screen is not drawn over
+        let current = state.borrow_and_update().clone();
+
+        if current != UiState::Session {
+            render(&mut screen, &current);
+        }
+    }
+}
+
+// Compose the screen for a state: the splash image (if any) with a status line below it
+pub fn render(screen: &mut Screen, state: &UiState) {
+    let image = match state {
+        UiState::LookingForManager { .. } => Some(resources::LOOKING_FOR_MANAGER_IMAGE),
+        UiState::Querying => Some(resources::QUERY_FOR_SERVER_IMAGE),
+        UiState::Connecting { .. } => Some(resources::CONNECTING_TO_SERVER_IMAGE),
+        UiState::Error { .. } | UiState::BackingOff { .. } | UiState::Sleeping | UiState::Session => None,
+    };
+
+    match image {
+        Some(image) => screen.draw_png_resource(image),
+        None => screen.clear(),
+    }
+
+    match state {
+        UiState::LookingForManager { attempts } if *attempts > 0 => status_line(screen, &format!("Still looking (attempt {})", attempts + 1), DevicePixel::from_rgb(255, 255, 255)),
+        UiState::Connecting { server } => status_line(screen, server, DevicePixel::from_rgb(255, 255, 255)),
+        UiState::Error { message, retry_in } => {
+            centered_text(screen, message, 0, DevicePixel::from_rgb(255, 96, 96));
+            centered_text(screen, &format!("Retrying in {} seconds", retry_in.as_secs()), font::text_height(TEXT_SCALE) + TEXT_MARGIN, DevicePixel::from_rgb(128, 128, 128));
+        },
+        UiState::BackingOff { retry_in } => {
+            centered_text(screen, "No connection", 0, DevicePixel::from_rgb(255, 96, 96));
+            centered_text(screen, &format!("Next attempt in {} seconds", retry_in.as_secs()), font::text_height(TEXT_SCALE) + TEXT_MARGIN, DevicePixel::from_rgb(128, 128, 128));
+        },
+        UiState::Sleeping => centered_text(screen, "Touch to wake up", 0, DevicePixel::from_rgb(128, 128, 128)),
+        _ => {},
+    }
+
+    screen.update();
+}
+
+fn status_line(screen: &mut Screen, text: &str, color: DevicePixel) {
+    let x = screen.xres().saturating_sub(font::text_width(text, TEXT_SCALE)) / 2;
+    let y = screen.yres().saturating_sub(font::text_height(TEXT_SCALE) + TEXT_MARGIN);
+
+    screen.draw_text(x, y, text, TEXT_SCALE, color, None);
+}
+
+// Text centered horizontally, `offset` pixels below the middle of the screen
+fn centered_text(screen: &mut Screen, text: &str, offset: usize, color: DevicePixel) {
+    let x = screen.xres().saturating_sub(font::text_width(text, TEXT_SCALE)) / 2;
+    let y = screen.yres().saturating_sub(font::text_height(TEXT_SCALE)) / 2 + offset;
+
+    screen.draw_text(x, y, text, TEXT_SCALE, color, None);
+}
@@ -0,0 +1,319 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Semaphore};
+use crate::screen::Snapshot;
+
+// Minimal read-only RFB server showing what the panel displays, for remote support. The port is not authenticated, so
+// every viewer is served by its own task with a deadline on each read and write (a stalled viewer cannot hold up the
+// others), and nothing a viewer sends is trusted to size an allocation. Viewers are always sent full frames in Raw
+// encoding, their input is ignored
+
+// Delay before answering an incremental update request, so an idle viewer does not spin
+const INCREMENTAL_UPDATE_DELAY: Duration = Duration::from_millis(250);
+
+const MAX_VIEWERS: usize = 4;
+
+// Viewers keep requesting updates, one that sends nothing for this long is gone
+const VIEWER_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A full frame is well under a MB, a viewer that cannot take it in this time has stopped reading
+const VIEWER_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Cut text is never used, it is read and dropped. A viewer sending more than this is disconnected
+const MAX_CUT_TEXT_LENGTH: u64 = 64 * 1024;
+
+// Pixel format requested by the viewer (true color only)
+#[derive(Debug, Clone, Copy)]
+struct ViewerPixelFormat {
+    bytes_per_pixel: usize,
+    big_endian: bool,
+    red_max: u32,
+    green_max: u32,
+    blue_max: u32,
+    red_shift: u32,
+    green_shift: u32,
+    blue_shift: u32,
+}
+
+impl ViewerPixelFormat {
+    // The panel format (RGB565), offered in ServerInit
+    fn device() -> ViewerPixelFormat {
+        ViewerPixelFormat { bytes_per_pixel: 2, big_endian: false, red_max: 31, green_max: 63, blue_max: 31, red_shift: 11, green_shift: 5, blue_shift: 0 }
+    }
+
+    // Only true color formats of 8, 16 or 32 bits per pixel, with every channel inside the pixel
+    fn decode(buffer: &[u8]) -> Result<ViewerPixelFormat, String> {
+        let bits_per_pixel = buffer[0] as u32;
+
+        if !matches!(bits_per_pixel, 8 | 16 | 32) || buffer[3] == 0 {
+            return Err(format!("Unsupported pixel format ({} bits per pixel, true color {})", bits_per_pixel, buffer[3]));
+        }
+
+        let pixel_format = ViewerPixelFormat {
+            bytes_per_pixel: bits_per_pixel as usize / 8,
+            big_endian: buffer[2] != 0,
+            red_max: u16::from_be_bytes([buffer[4], buffer[5]]) as u32,
+            green_max: u16::from_be_bytes([buffer[6], buffer[7]]) as u32,
+            blue_max: u16::from_be_bytes([buffer[8], buffer[9]]) as u32,
+            red_shift: buffer[10] as u32,
+            green_shift: buffer[11] as u32,
+            blue_shift: buffer[12] as u32,
+        };
+
+        for (max, shift) in [(pixel_format.red_max, pixel_format.red_shift), (pixel_format.green_max, pixel_format.green_shift), (pixel_format.blue_max, pixel_format.blue_shift)] {
+            if max == 0 || shift + (u32::BITS - max.leading_zeros()) > bits_per_pixel {
+                return Err(format!("Invalid channel (maximum {} shift {}) for {} bits per pixel", max, shift, bits_per_pixel));
+            }
+        }
+
+        Ok(pixel_format)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let bits_per_pixel = (self.bytes_per_pixel * 8) as u8;
+        let mut result = vec![bits_per_pixel, bits_per_pixel.min(24), self.big_endian as u8, 1];
+
+        result.extend_from_slice(&(self.red_max as u16).to_be_bytes());
+        result.extend_from_slice(&(self.green_max as u16).to_be_bytes());
+        result.extend_from_slice(&(self.blue_max as u16).to_be_bytes());
+        result.extend_from_slice(&[self.red_shift as u8, self.green_shift as u8, self.blue_shift as u8, 0, 0, 0]);
+        result
+    }
+
+    // 8 bit per channel RGB (see Snapshot::to_rgb) scaled to the viewer channels
+    fn append_pixel(&self, rgb: &[u8], output: &mut Vec<u8>) {
+        let scale = |value: u8, target_max: u32| (value as u32 * target_max + 127) / 255;
+        let value = (scale(rgb[0], self.red_max) << self.red_shift) | (scale(rgb[1], self.green_max) << self.green_shift) | (scale(rgb[2], self.blue_max) << self.blue_shift);
+
+        match (self.bytes_per_pixel, self.big_endian) {
+            (1, _) => output.push(value as u8),
+            (2, false) => output.extend_from_slice(&(value as u16).to_le_bytes()),
+            (2, true) => output.extend_from_slice(&(value as u16).to_be_bytes()),
+            (_, false) => output.extend_from_slice(&value.to_le_bytes()),
+            (_, true) => output.extend_from_slice(&value.to_be_bytes()),
+        }
+    }
+}
+
+pub async fn run(port: u16, snapshots: watch::Receiver<Arc<Snapshot>>, name: String) {
+    let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("VNC mirror cannot listen on port {}: {} - running without it", port, e);
+            return;
+        }
+    };
+    let viewer_slots = Arc::new(Semaphore::new(MAX_VIEWERS));
+
+    loop {
+        let (stream, viewer_address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("VNC mirror accept failed: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let viewer_slot = match viewer_slots.clone().try_acquire_owned() {
+            Ok(viewer_slot) => viewer_slot,
+            Err(_) => {
+                println!("VNC mirror viewer {} refused, already serving {} viewers", viewer_address, MAX_VIEWERS);
+                continue;
+            }
+        };
+
+        println!("VNC mirror viewer {} connected", viewer_address);
+
+        let snapshots = snapshots.clone();
+        let name = name.clone();
+
+        tokio::spawn(async move {
+            match serve_viewer(stream, snapshots, &name).await {
+                Ok(_) => println!("VNC mirror viewer {} disconnected", viewer_address),
+                Err(e) => println!("VNC mirror viewer {} disconnected: {}", viewer_address, e),
+            }
+
+            drop(viewer_slot);
+        });
+    }
+}
+
+async fn serve_viewer(mut stream: TcpStream, snapshots: watch::Receiver<Arc<Snapshot>>, name: &str) -> Result<(), std::io::Error> {
+    let mut protocol_version: [u8; 12] = [0; 12];
+    let mut byte: [u8; 1] = [0; 1];
+
+    write_all(&mut stream, b"RFB 003.008\n").await?;
+    read_exact(&mut stream, &mut protocol_version).await?;
+
+    write_all(&mut stream, &[1, 1]).await?;         // One security type: None
+    read_exact(&mut stream, &mut byte).await?;
+    write_all(&mut stream, &0u32.to_be_bytes()).await?;
+    read_exact(&mut stream, &mut byte).await?;     // ClientInit (shared flag)
+
+    let (width, height) = {
+        let snapshot = snapshots.borrow();
+        (snapshot.width as u16, snapshot.height as u16)
+    };
+    let mut pixel_format = ViewerPixelFormat::device();
+    let mut server_init = Vec::new();
+
+    server_init.extend_from_slice(&width.to_be_bytes());
+    server_init.extend_from_slice(&height.to_be_bytes());
+    server_init.extend_from_slice(&pixel_format.encode());
+    server_init.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    server_init.extend_from_slice(name.as_bytes());
+    write_all(&mut stream, &server_init).await?;
+
+    loop {
+        read_exact(&mut stream, &mut byte).await?;
+
+        match byte[0] {
+            // SetPixelFormat: 3 padding bytes and the pixel format
+            0 => {
+                let mut buffer: [u8; 19] = [0; 19];
+
+                read_exact(&mut stream, &mut buffer).await?;
+                pixel_format = ViewerPixelFormat::decode(&buffer[3..]).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            },
+            // SetEncodings: padding, count and the encodings. Raw is always used
+            2 => {
+                let mut header: [u8; 3] = [0; 3];
+
+                read_exact(&mut stream, &mut header).await?;
+                skip(&mut stream, u16::from_be_bytes([header[1], header[2]]) as u64 * 4).await?;
+            },
+            // FrameUpdateRequest: incremental flag and a rectangle. The whole screen is always sent
+            3 => {
+                let mut request: [u8; 9] = [0; 9];
+
+                read_exact(&mut stream, &mut request).await?;
+
+                if request[0] != 0 {
+                    tokio::time::sleep(INCREMENTAL_UPDATE_DELAY).await;
+                }
+
+                let snapshot = snapshots.borrow().clone();
+                write_all(&mut stream, &frame_update(&snapshot, &pixel_format)).await?;
+            },
+            // KeyEvent and PointerEvent are ignored (read-only)
+            4 => {
+                let mut buffer: [u8; 7] = [0; 7];
+                read_exact(&mut stream, &mut buffer).await?;
+            },
+            5 => {
+                let mut buffer: [u8; 5] = [0; 5];
+                read_exact(&mut stream, &mut buffer).await?;
+            },
+            // ClientCutText: 3 padding bytes, length and the text
+            6 => {
+                let mut header: [u8; 7] = [0; 7];
+
+                read_exact(&mut stream, &mut header).await?;
+                let length = u32::from_be_bytes([header[3], header[4], header[5], header[6]]) as u64;
+
+                if length > MAX_CUT_TEXT_LENGTH {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Cut text of {} bytes is too long", length)));
+                }
+
+                skip(&mut stream, length).await?;
+            },
+            message_type => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Unsupported message type {}", message_type))),
+        }
+    }
+}
+
+async fn read_exact(stream: &mut TcpStream, buffer: &mut [u8]) -> Result<(), std::io::Error> {
+    tokio::time::timeout(VIEWER_READ_TIMEOUT, stream.read_exact(buffer)).await.map_err(|_| timed_out())??;
+    Ok(())
+}
+
+async fn write_all(stream: &mut TcpStream, buffer: &[u8]) -> Result<(), std::io::Error> {
+    tokio::time::timeout(VIEWER_WRITE_TIMEOUT, stream.write_all(buffer)).await.map_err(|_| timed_out())?
+}
+
+// Read and drop `length` bytes
+async fn skip(stream: &mut TcpStream, length: u64) -> Result<(), std::io::Error> {
+    let skipped = tokio::time::timeout(VIEWER_READ_TIMEOUT, tokio::io::copy(&mut (&mut *stream).take(length), &mut tokio::io::sink())).await.map_err(|_| timed_out())??;
+
+    if skipped < length {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "viewer closed the connection"));
+    }
+
+    Ok(())
+}
+
+fn timed_out() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "viewer timed out")
+}
+
+fn frame_update(snapshot: &Snapshot, pixel_format: &ViewerPixelFormat) -> Vec<u8> {
+    let mut result = vec![0, 0];
+
+    result.extend_from_slice(&1u16.to_be_bytes());
+    result.extend_from_slice(&[0, 0, 0, 0]);
+    result.extend_from_slice(&(snapshot.width as u16).to_be_bytes());
+    result.extend_from_slice(&(snapshot.height as u16).to_be_bytes());
+    result.extend_from_slice(&0i32.to_be_bytes());
+
+    for rgb in snapshot.to_rgb().chunks(3) {
+        pixel_format.append_pixel(rgb, &mut result);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SetPixelFormat payload: bits per pixel, depth, big endian, true color, the maximums and the shifts
+    fn pixel_format_message(bits_per_pixel: u8, maxes: [u16; 3], shifts: [u8; 3]) -> Vec<u8> {
+        let mut message = vec![bits_per_pixel, 24, 0, 1];
+
+        for max in maxes {
+            message.extend_from_slice(&max.to_be_bytes());
+        }
+
+        message.extend_from_slice(&shifts);
+        message.extend_from_slice(&[0, 0, 0]);
+        message
+    }
+
+    #[test]
+    fn common_formats_are_accepted() {
+        assert!(ViewerPixelFormat::decode(&pixel_format_message(32, [255, 255, 255], [16, 8, 0])).is_ok());
+        assert!(ViewerPixelFormat::decode(&pixel_format_message(16, [31, 63, 31], [11, 5, 0])).is_ok());
+        assert!(ViewerPixelFormat::decode(&pixel_format_message(8, [7, 7, 3], [0, 3, 6])).is_ok());
+    }
+
+    #[test]
+    fn invalid_formats_are_rejected() {
+        assert!(ViewerPixelFormat::decode(&pixel_format_message(32, [255, 255, 255], [40, 8, 0])).is_err());
+        assert!(ViewerPixelFormat::decode(&pixel_format_message(32, [255, 255, 255], [25, 8, 0])).is_err());
+        assert!(ViewerPixelFormat::decode(&pixel_format_message(16, [255, 63, 31], [11, 5, 0])).is_err());
+        assert!(ViewerPixelFormat::decode(&pixel_format_message(24, [255, 255, 255], [16, 8, 0])).is_err());
+        assert!(ViewerPixelFormat::decode(&pixel_format_message(32, [0, 255, 255], [16, 8, 0])).is_err());
+
+        let mut color_map = pixel_format_message(8, [7, 7, 3], [0, 3, 6]);
+        color_map[3] = 0;
+        assert!(ViewerPixelFormat::decode(&color_map).is_err());
+    }
+
+    #[test]
+    fn pixels_in_the_viewer_format() {
+        let rgb32 = ViewerPixelFormat::decode(&pixel_format_message(32, [255, 255, 255], [16, 8, 0])).unwrap();
+        let mut output = Vec::new();
+
+        rgb32.append_pixel(&[0x12, 0x34, 0x56], &mut output);
+        assert_eq!(output, vec![0x56, 0x34, 0x12, 0x00]);
+
+        // RGB565 expanded to 8 bits a channel comes back unchanged
+        let mut output = Vec::new();
+
+        ViewerPixelFormat::device().append_pixel(&[0xff, 0x82, 0x08], &mut output);
+        assert_eq!(u16::from_le_bytes([output[0], output[1]]), (31 << 11) | (32 << 5) | 1);
+    }
+}